@@ -0,0 +1,56 @@
+/*
+Copyright 2025 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+// Embeds a hash of the sources as KATI_BUILD_ID, so that files rkati writes
+// for itself, like the stamp, are not read by a different build.
+
+use std::{fs, path::Path};
+
+use sha2::{Digest, Sha256};
+
+fn hash_dir(dir: &Path, hasher: &mut Sha256) {
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    paths.sort();
+    for path in paths {
+        if path.is_dir() {
+            hash_dir(&path, hasher);
+        } else if path.extension().is_some_and(|ext| ext == "rs") {
+            hasher.update(path.to_string_lossy().as_bytes());
+            hasher.update(fs::read(&path).unwrap());
+        }
+    }
+}
+
+fn main() {
+    let mut hasher = Sha256::new();
+    for file in ["Cargo.toml", "Cargo.lock"] {
+        if let Ok(contents) = fs::read(file) {
+            hasher.update(contents);
+        }
+        println!("cargo:rerun-if-changed={file}");
+    }
+    hash_dir(Path::new("src-rs"), &mut hasher);
+    println!("cargo:rerun-if-changed=src-rs");
+
+    let id: String = hasher.finalize()[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    println!("cargo:rustc-env=KATI_BUILD_ID={id}");
+}
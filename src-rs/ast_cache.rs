@@ -0,0 +1,550 @@
+/*
+Copyright 2025 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! With --ast_cache, the statements parsed from each makefile are saved next
+//! to the kati stamp, keyed by the makefile's path, mtime, size and content
+//! hash. The next run loads them instead of parsing makefiles that haven't
+//! changed.
+
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    io::{BufReader, Write},
+    os::unix::ffi::OsStrExt,
+    sync::{Arc, LazyLock, atomic::AtomicUsize},
+    time::SystemTime,
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    collect_stats, error,
+    expr::Value,
+    file::Makefile,
+    flags::FLAGS,
+    func::get_func_info,
+    io::{
+        dump_int, dump_mtime, dump_string, dump_usize, is_own_identity, load_int, load_mtime,
+        load_string, load_usize, stamp_identity,
+    },
+    loc::Loc,
+    log,
+    parser::parse_file,
+    stmt::{
+        AssignDirective, AssignOp, AssignStmt, CommandStmt, CondOp, ExportStmt, IfStmt,
+        IncludeStmt, RuleSep, RuleStmt, Stmt,
+    },
+    symtab::{Symbol, intern},
+};
+
+// Bump whenever the serialized form of statements or values changes.
+const AST_CACHE_VERSION: i32 = 1;
+
+#[derive(Clone, Copy)]
+pub enum StmtKind {
+    Rule,
+    Assign,
+    Command,
+    If,
+    Include,
+    Export,
+}
+
+impl StmtKind {
+    fn from_int(v: i32) -> Option<Self> {
+        Some(match v {
+            0 => Self::Rule,
+            1 => Self::Assign,
+            2 => Self::Command,
+            3 => Self::If,
+            4 => Self::Include,
+            5 => Self::Export,
+            _ => return None,
+        })
+    }
+}
+
+const VALUE_LITERAL: i32 = 0;
+const VALUE_LIST: i32 = 1;
+const VALUE_SYM_REF: i32 = 2;
+const VALUE_VAR_REF: i32 = 3;
+const VALUE_VAR_SUBST: i32 = 4;
+const VALUE_FUNC: i32 = 5;
+
+/// Serializes the statements of a single makefile. Every location in a
+/// makefile points into that makefile, so only line numbers are written.
+pub struct AstWriter {
+    out: Vec<u8>,
+    filename: Symbol,
+}
+
+impl AstWriter {
+    fn new(filename: Symbol) -> Self {
+        Self {
+            out: Vec::new(),
+            filename,
+        }
+    }
+
+    pub fn int(&mut self, v: i32) -> Result<()> {
+        dump_int(&mut self.out, v)
+    }
+
+    pub fn bool(&mut self, v: bool) -> Result<()> {
+        self.int(v as i32)
+    }
+
+    pub fn bytes(&mut self, v: &[u8]) -> Result<()> {
+        dump_string(&mut self.out, v)
+    }
+
+    fn loc(&mut self, loc: &Loc) -> Result<()> {
+        if loc.filename != self.filename {
+            error!("location {loc} is outside of {}", self.filename);
+        }
+        self.int(loc.line)
+    }
+
+    fn opt_loc(&mut self, loc: Option<&Loc>) -> Result<()> {
+        match loc {
+            Some(loc) => {
+                self.bool(true)?;
+                self.loc(loc)
+            }
+            None => self.bool(false),
+        }
+    }
+
+    pub fn kind(&mut self, kind: StmtKind, loc: &Loc) -> Result<()> {
+        self.int(kind as i32)?;
+        self.loc(loc)
+    }
+
+    pub fn value(&mut self, v: &Value) -> Result<()> {
+        match v {
+            Value::Literal(loc, lit) => {
+                self.int(VALUE_LITERAL)?;
+                self.opt_loc(loc.as_ref())?;
+                self.bytes(lit)
+            }
+            Value::List(loc, vec) => {
+                self.int(VALUE_LIST)?;
+                self.opt_loc(loc.as_ref())?;
+                self.values(vec)
+            }
+            Value::SymRef(loc, sym) => {
+                self.int(VALUE_SYM_REF)?;
+                self.loc(loc)?;
+                self.bytes(&sym.as_bytes())
+            }
+            Value::VarRef(loc, name) => {
+                self.int(VALUE_VAR_REF)?;
+                self.loc(loc)?;
+                self.value(name)
+            }
+            Value::VarSubst {
+                loc,
+                name,
+                pat,
+                subst,
+            } => {
+                self.int(VALUE_VAR_SUBST)?;
+                self.loc(loc)?;
+                self.value(name)?;
+                self.value(pat)?;
+                self.value(subst)
+            }
+            Value::Func { loc, fi, args } => {
+                self.int(VALUE_FUNC)?;
+                self.loc(loc)?;
+                self.bytes(fi.name)?;
+                self.values(args)
+            }
+        }
+    }
+
+    pub fn opt_value(&mut self, v: Option<&Value>) -> Result<()> {
+        match v {
+            Some(v) => {
+                self.bool(true)?;
+                self.value(v)
+            }
+            None => self.bool(false),
+        }
+    }
+
+    fn values(&mut self, vec: &[Arc<Value>]) -> Result<()> {
+        dump_usize(&mut self.out, vec.len())?;
+        for v in vec {
+            self.value(v)?;
+        }
+        Ok(())
+    }
+
+    pub fn stmts(&mut self, stmts: &[Stmt]) -> Result<()> {
+        dump_usize(&mut self.out, stmts.len())?;
+        for stmt in stmts {
+            stmt.dump(self)?;
+        }
+        Ok(())
+    }
+}
+
+/// The reverse of `AstWriter`. Returns `None` on malformed input.
+struct AstReader<'a> {
+    buf: &'a [u8],
+    filename: Symbol,
+}
+
+impl AstReader<'_> {
+    fn int(&mut self) -> Option<i32> {
+        load_int(&mut self.buf)
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        Some(self.int()? != 0)
+    }
+
+    fn bytes(&mut self) -> Option<Bytes> {
+        Some(Bytes::from(
+            load_string(&mut self.buf)?.into_encoded_bytes(),
+        ))
+    }
+
+    fn loc(&mut self) -> Option<Loc> {
+        Some(Loc {
+            filename: self.filename,
+            line: self.int()?,
+        })
+    }
+
+    fn opt_loc(&mut self) -> Option<Option<Loc>> {
+        Some(if self.bool()? {
+            Some(self.loc()?)
+        } else {
+            None
+        })
+    }
+
+    fn value(&mut self) -> Option<Arc<Value>> {
+        let v = match self.int()? {
+            VALUE_LITERAL => Value::Literal(self.opt_loc()?, self.bytes()?),
+            VALUE_LIST => Value::List(self.opt_loc()?, self.values()?),
+            VALUE_SYM_REF => Value::SymRef(self.loc()?, intern(self.bytes()?)),
+            VALUE_VAR_REF => Value::VarRef(self.loc()?, self.value()?),
+            VALUE_VAR_SUBST => Value::VarSubst {
+                loc: self.loc()?,
+                name: self.value()?,
+                pat: self.value()?,
+                subst: self.value()?,
+            },
+            VALUE_FUNC => Value::Func {
+                loc: self.loc()?,
+                fi: get_func_info(&self.bytes()?)?,
+                args: self.values()?,
+            },
+            _ => return None,
+        };
+        Some(Arc::new(v))
+    }
+
+    fn opt_value(&mut self) -> Option<Option<Arc<Value>>> {
+        Some(if self.bool()? {
+            Some(self.value()?)
+        } else {
+            None
+        })
+    }
+
+    fn values(&mut self) -> Option<Vec<Arc<Value>>> {
+        let len = load_usize(&mut self.buf)?;
+        let mut ret = Vec::with_capacity(len);
+        for _ in 0..len {
+            ret.push(self.value()?);
+        }
+        Some(ret)
+    }
+
+    fn stmts(&mut self) -> Option<Vec<Stmt>> {
+        let len = load_usize(&mut self.buf)?;
+        let mut ret = Vec::with_capacity(len);
+        for _ in 0..len {
+            ret.push(self.stmt()?);
+        }
+        Some(ret)
+    }
+
+    fn stmt(&mut self) -> Option<Stmt> {
+        let kind = StmtKind::from_int(self.int()?)?;
+        let loc = self.loc()?;
+        Some(match kind {
+            StmtKind::Rule => {
+                let lhs = self.value()?;
+                let sep = match self.int()? {
+                    0 => RuleSep::Null,
+                    1 => RuleSep::Semicolon,
+                    2 => RuleSep::Eq,
+                    3 => RuleSep::FinalEq,
+                    _ => return None,
+                };
+                RuleStmt::new(loc, lhs, sep, self.opt_value()?)
+            }
+            StmtKind::Assign => {
+                let lhs = self.value()?;
+                let rhs = self.value()?;
+                let orig_rhs = self.bytes()?;
+                let op = match self.int()? {
+                    0 => AssignOp::Eq,
+                    1 => AssignOp::ColonEq,
+                    2 => AssignOp::PlusEq,
+                    3 => AssignOp::QuestionEq,
+                    _ => return None,
+                };
+                let directive = match self.int()? {
+                    -1 => None,
+                    d => Some(AssignDirective {
+                        is_override: d & 1 != 0,
+                        export: d & 2 != 0,
+                    }),
+                };
+                let is_final = self.bool()?;
+                AssignStmt::new(loc, lhs, rhs, orig_rhs, op, directive, is_final)
+            }
+            StmtKind::Command => {
+                let orig = self.bytes()?;
+                CommandStmt::new(loc, orig, self.value()?)
+            }
+            StmtKind::If => {
+                let op = match self.int()? {
+                    0 => CondOp::Ifeq,
+                    1 => CondOp::Ifneq,
+                    2 => CondOp::Ifdef,
+                    3 => CondOp::Ifndef,
+                    _ => return None,
+                };
+                let lhs = self.value()?;
+                let stmt = IfStmt::new(loc, op, lhs, self.opt_value()?);
+                *stmt.true_stmts.lock() = self.stmts()?;
+                *stmt.false_stmts.lock() = self.stmts()?;
+                stmt
+            }
+            StmtKind::Include => {
+                let expr = self.value()?;
+                IncludeStmt::new(loc, expr, self.bool()?)
+            }
+            StmtKind::Export => {
+                let expr = self.value()?;
+                ExportStmt::new(loc, expr, self.bool()?)
+            }
+        })
+    }
+}
+
+struct CachedAst {
+    mtime: SystemTime,
+    size: u64,
+    hash: Vec<u8>,
+    stmts: Vec<u8>,
+}
+
+/// Entries read from the cache file, and the entries for the makefiles this
+/// process used, which are the ones written back.
+#[derive(Default)]
+struct AstCache {
+    loaded: HashMap<OsString, CachedAst>,
+    used: HashMap<OsString, CachedAst>,
+}
+
+impl AstCache {
+    fn load() -> Self {
+        let mut cache = Self::default();
+        if FLAGS.ast_cache {
+            let filename = crate::ninja::get_ast_cache_filename();
+            if let Ok(f) = std::fs::File::open(&filename) {
+                match Self::load_entries(&mut BufReader::new(f)) {
+                    Some(loaded) => cache.loaded = loaded,
+                    None => log!("Ignoring unusable AST cache {filename:?}"),
+                }
+            }
+        }
+        cache
+    }
+
+    fn load_entries(f: &mut impl std::io::Read) -> Option<HashMap<OsString, CachedAst>> {
+        if !is_own_identity(&load_string(f)?, false) || load_int(f)? != AST_CACHE_VERSION {
+            return None;
+        }
+        let num_files = load_usize(f)?;
+        let mut ret = HashMap::with_capacity(num_files);
+        for _ in 0..num_files {
+            let name = load_string(f)?;
+            let entry = CachedAst {
+                mtime: load_mtime(f)?,
+                size: load_string(f)?
+                    .as_bytes()
+                    .try_into()
+                    .ok()
+                    .map(u64::from_le_bytes)?,
+                hash: load_string(f)?.into_encoded_bytes(),
+                stmts: load_string(f)?.into_encoded_bytes(),
+            };
+            ret.insert(name, entry);
+        }
+        Some(ret)
+    }
+}
+
+static AST_CACHE: LazyLock<Mutex<AstCache>> = LazyLock::new(|| Mutex::new(AstCache::load()));
+static AST_CACHE_HITS: AtomicUsize = AtomicUsize::new(0);
+
+/// Like `Makefile::from_file`, but reuses the statements saved by an earlier
+/// run if the makefile is unchanged.
+pub fn load_makefile(filename: &OsStr) -> Result<Option<Arc<Makefile>>> {
+    if !std::fs::exists(filename)? {
+        return Ok(None);
+    }
+    let md = std::fs::metadata(filename)?;
+    let buf = Bytes::from(std::fs::read(filename)?);
+    let mtime = md.modified()?;
+    let size = md.len();
+    let digest: [u8; 32] = Sha256::digest(&buf).into();
+    let hash = digest.to_vec();
+    let sym = intern(filename.as_bytes().to_vec());
+
+    let entry = {
+        let mut cache = AST_CACHE.lock();
+        cache
+            .used
+            .remove(filename)
+            .or_else(|| cache.loaded.remove(filename))
+    };
+    if let Some(entry) = entry
+        && entry.mtime == mtime
+        && entry.size == size
+        && entry.hash == hash
+    {
+        collect_stats!("AST cache load time");
+        let mut r = AstReader {
+            buf: &entry.stmts,
+            filename: sym,
+        };
+        match r.stmts() {
+            Some(stmts) if r.buf.is_empty() => {
+                AST_CACHE_HITS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                AST_CACHE.lock().used.insert(filename.to_os_string(), entry);
+                return Ok(Some(Arc::new(Makefile {
+                    filename: sym,
+                    stmts: Arc::new(Mutex::new(stmts)),
+                    hash: Some(digest),
+                })));
+            }
+            _ => log!("Ignoring unusable AST cache entry for {filename:?}"),
+        }
+    }
+
+    // Parse warnings would not be repeated when loading from the cache, so
+    // makefiles that produce them are not cached.
+    let warnings = crate::warning_count();
+    let stmts = parse_file(&buf, sym)?;
+    if crate::warning_count() == warnings {
+        let mut w = AstWriter::new(sym);
+        match w.stmts(&stmts.lock()) {
+            Ok(()) => {
+                let entry = CachedAst {
+                    mtime,
+                    size,
+                    hash,
+                    stmts: w.out,
+                };
+                AST_CACHE.lock().used.insert(filename.to_os_string(), entry);
+            }
+            Err(err) => log!("Not caching the AST of {filename:?}: {err}"),
+        }
+    }
+    Ok(Some(Arc::new(Makefile {
+        filename: sym,
+        stmts,
+        hash: Some(digest),
+    })))
+}
+
+pub fn get_ast_cache_hit_count() -> usize {
+    AST_CACHE_HITS.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Writes the ASTs of the makefiles used by this process to the AST cache.
+pub fn save_ast_cache(filename: &OsStr) -> Result<()> {
+    let mut tmp = filename.to_os_string();
+    tmp.push(".tmp");
+    {
+        let cache = AST_CACHE.lock();
+        let mut out = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+        dump_string(&mut out, stamp_identity().as_bytes())?;
+        dump_int(&mut out, AST_CACHE_VERSION)?;
+        dump_usize(&mut out, cache.used.len())?;
+        for (name, entry) in &cache.used {
+            dump_string(&mut out, name.as_bytes())?;
+            dump_mtime(&mut out, &entry.mtime)?;
+            dump_string(&mut out, &entry.size.to_le_bytes())?;
+            dump_string(&mut out, &entry.hash)?;
+            dump_string(&mut out, &entry.stmts)?;
+        }
+        out.flush()?;
+    }
+    std::fs::rename(&tmp, filename)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_buf_no_stats;
+
+    #[test]
+    fn test_round_trip() {
+        let filename = intern("test_round_trip.mk");
+        let buf = Bytes::from_static(
+            b"A := $(wildcard *.c) $(B:.c=.o)\n\
+              override export C ?= ${A}\n\
+              ifeq ($(A),)\n\
+              x: y ; echo $@\n\
+              \techo $(subst a,b,$^)\n\
+              else ifdef B\n\
+              -include $(C)\n\
+              endif\n\
+              unexport D\n",
+        );
+        let stmts = parse_buf_no_stats(&buf, Loc { filename, line: 0 }).unwrap();
+        let stmts = stmts.lock();
+        let mut w = AstWriter::new(filename);
+        w.stmts(&stmts).unwrap();
+
+        let mut r = AstReader {
+            buf: &w.out,
+            filename,
+        };
+        let loaded = r.stmts().unwrap();
+        assert!(r.buf.is_empty());
+        assert_eq!(format!("{stmts:?}"), format!("{loaded:?}"));
+
+        let mut w2 = AstWriter::new(filename);
+        w2.stmts(&loaded).unwrap();
+        assert_eq!(w.out, w2.out);
+    }
+}
@@ -0,0 +1,485 @@
+/*
+Copyright 2025 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+// With --daemon, rkati stays up after generating the ninja file. It keeps
+// the parsed makefiles and the find emulator's directory listings in memory,
+// watches what the stamp depends on with inotify, and regenerates as soon as
+// any of it changes. `rkati --daemon_wait` connects to the daemon and
+// returns once the ninja file is up to date.
+//
+// Only files, globs and find directories are watched. Other $(shell)
+// commands and environment variables are checked whenever a client asks.
+// Each client sends its command line and environment over the socket. The
+// command line has to match the daemon's, and the environment replaces the
+// daemon's for that run and the ones after it, so the regen check, $(shell)
+// and the exported variables all see what the client would have seen.
+//
+// The watcher needs inotify, so the daemon itself only runs on Linux.
+// `--daemon_wait` works wherever Unix sockets do.
+
+// Without the watcher, most of the server side is unused.
+#![cfg_attr(not(target_os = "linux"), allow(dead_code, unused_imports))]
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{CString, OsStr, OsString},
+    io::{BufRead, BufReader, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::{
+            ffi::OsStrExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    eval::Evaluator,
+    func::{COMMAND_RESULTS, CommandOp},
+    io::{dump_string, dump_usize, dump_vec_string, load_string, load_usize, load_vec_string},
+    ninja::{get_daemon_socket_filename, get_generated_filenames, get_ninja_stamp_filename},
+    regen_dump::Stamp,
+    var::USED_ENV_VARS,
+};
+
+// How long the tree has to stay quiet before regenerating, so that a
+// `git checkout` triggers one regeneration rather than one per file.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+#[cfg(target_os = "linux")]
+const WATCH_MASK: u32 = libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MODIFY
+    | libc::IN_CLOSE_WRITE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_ATTRIB
+    | libc::IN_DELETE_SELF
+    | libc::IN_MOVE_SELF;
+
+/// Resets the state one evaluation leaves behind, keeping the caches that
+/// make the next evaluation cheaper.
+pub fn reset_for_next_run() {
+    crate::symtab::clear_global_vars();
+    Evaluator::clear_used_undefined_vars();
+    USED_ENV_VARS.lock().clear();
+    COMMAND_RESULTS.lock().clear();
+    crate::fileutil::clear_glob_cache();
+    crate::file_cache::start_new_run();
+    crate::find::reset_find_emulator();
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// The closest directory at or above `path` that exists, so that the
+// creation of missing directories is noticed.
+fn existing_dir(path: &Path) -> PathBuf {
+    let mut dir = absolute(path);
+    while !dir.is_dir() {
+        if !dir.pop() {
+            break;
+        }
+    }
+    dir
+}
+
+// The directory part of a glob pattern before its first wildcard.
+fn glob_dir(pat: &[u8]) -> &OsStr {
+    let wildcard = pat
+        .iter()
+        .position(|c| matches!(c, b'*' | b'?' | b'['))
+        .unwrap_or(pat.len());
+    let dir = match pat[..wildcard].iter().rposition(|c| *c == b'/') {
+        Some(0) => &pat[..1],
+        Some(slash) => &pat[..slash],
+        None => b".",
+    };
+    OsStr::from_bytes(dir)
+}
+
+/// The directories to watch, each with the names in it that matter, or
+/// `None` if every change in the directory does.
+#[derive(Default)]
+struct WatchList(HashMap<PathBuf, Option<HashSet<OsString>>>);
+
+impl WatchList {
+    fn from_stamp(stamp: &Stamp) -> Self {
+        let mut list = Self::default();
+        for file in &stamp.files {
+            list.add_file(Path::new(file));
+        }
+        for (pat, files) in &stamp.globs {
+            list.add_dir(Path::new(glob_dir(pat.as_bytes())));
+            for file in files {
+                list.add_dir(Path::new(file).parent().unwrap_or(Path::new(".")));
+            }
+        }
+        for cmd in &stamp.commands {
+            match cmd.op {
+                CommandOp::Read | CommandOp::ReadMissing => list.add_file(Path::new(&cmd.cmd)),
+                CommandOp::Find => {
+                    let find = cmd.find.as_ref().unwrap();
+                    for dir in find.read_dirs.iter().chain(&find.missing_dirs) {
+                        list.add_dir(Path::new(dir));
+                    }
+                }
+                CommandOp::Shell | CommandOp::Write | CommandOp::Append => {}
+            }
+        }
+        list
+    }
+
+    fn add_dir(&mut self, dir: &Path) {
+        self.0.insert(existing_dir(dir), None);
+    }
+
+    fn add_file(&mut self, file: &Path) {
+        let parent = match file.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let dir = absolute(parent);
+        let Some(name) = file.file_name() else {
+            return;
+        };
+        if !dir.is_dir() {
+            self.add_dir(&dir);
+            return;
+        }
+        if let Some(names) = self.0.entry(dir).or_insert_with(|| Some(HashSet::new())) {
+            names.insert(name.to_os_string());
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+struct Watcher {
+    fd: OwnedFd,
+    watches: HashMap<i32, (PathBuf, Option<HashSet<OsString>>)>,
+    // Our own outputs, which change on every regeneration.
+    ignored: HashSet<PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    fn new() -> Result<Self> {
+        // SAFETY: inotify_init1 has no memory safety requirements.
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error()).context("inotify_init1");
+        }
+        Ok(Self {
+            // SAFETY: fd is a freshly created descriptor that nothing else owns.
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            watches: HashMap::new(),
+            ignored: get_generated_filenames()
+                .iter()
+                .map(|f| absolute(Path::new(f)))
+                .collect(),
+        })
+    }
+
+    /// Replaces the watches with what the current stamp depends on.
+    fn watch_stamp(&mut self) {
+        for wd in std::mem::take(&mut self.watches).into_keys() {
+            // SAFETY: inotify_rm_watch has no memory safety requirements.
+            unsafe { libc::inotify_rm_watch(self.fd.as_raw_fd(), wd) };
+        }
+        let list = match Stamp::load(get_ninja_stamp_filename()) {
+            Ok(stamp) => WatchList::from_stamp(&stamp),
+            Err(err) => {
+                eprintln!("*kati*: daemon: nothing to watch: {err}");
+                return;
+            }
+        };
+        for (dir, names) in list.0 {
+            let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+                continue;
+            };
+            // SAFETY: path is a valid NUL-terminated string.
+            let wd =
+                unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), WATCH_MASK) };
+            if wd < 0 {
+                crate::log!(
+                    "inotify_add_watch({dir:?}): {}",
+                    std::io::Error::last_os_error()
+                );
+                continue;
+            }
+            self.watches.insert(wd, (dir, names));
+        }
+    }
+
+    /// Reads the pending events and returns whether any of them touched
+    /// something the stamp depends on.
+    fn read_events(&self) -> Result<bool> {
+        const HEADER_LEN: usize = std::mem::size_of::<libc::inotify_event>();
+        let mut changed = false;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            // SAFETY: buf is valid for writes of buf.len() bytes.
+            let n = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if n < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::WouldBlock {
+                    return Ok(changed);
+                }
+                return Err(err).context("reading inotify events");
+            }
+            let n = n as usize;
+            let mut off = 0;
+            while off + HEADER_LEN <= n {
+                // SAFETY: the kernel wrote a whole event header at off.
+                let event = unsafe {
+                    std::ptr::read_unaligned(buf[off..].as_ptr() as *const libc::inotify_event)
+                };
+                let name = &buf[off + HEADER_LEN..off + HEADER_LEN + event.len as usize];
+                let name = &name[..name.iter().position(|c| *c == 0).unwrap_or(name.len())];
+                off += HEADER_LEN + event.len as usize;
+                changed |= self.is_relevant(&event, OsStr::from_bytes(name));
+            }
+        }
+    }
+
+    fn is_relevant(&self, event: &libc::inotify_event, name: &OsStr) -> bool {
+        if event.mask & libc::IN_Q_OVERFLOW != 0 {
+            return true;
+        }
+        let Some((dir, names)) = self.watches.get(&event.wd) else {
+            return false;
+        };
+        if name.is_empty() {
+            // The directory itself was deleted or moved.
+            return event.mask & libc::IN_IGNORED == 0;
+        }
+        if self.ignored.contains(&dir.join(name)) {
+            return false;
+        }
+        names.as_ref().is_none_or(|names| names.contains(name))
+    }
+}
+
+fn poll_readable(fds: &[i32], timeout: Option<Duration>) -> Result<Vec<bool>> {
+    let mut pollfds: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|fd| libc::pollfd {
+            fd: *fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let timeout = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+    // SAFETY: pollfds is valid for pollfds.len() entries.
+    let r = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout) };
+    if r < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() == std::io::ErrorKind::Interrupted {
+            return Ok(vec![false; fds.len()]);
+        }
+        return Err(err).context("poll");
+    }
+    Ok(pollfds.iter().map(|p| p.revents != 0).collect())
+}
+
+/// The command line and environment of a kati process.
+pub struct Invocation {
+    args: Vec<OsString>,
+    env: Vec<(OsString, OsString)>,
+}
+
+impl Invocation {
+    pub fn current() -> Self {
+        Self {
+            args: std::env::args_os().collect(),
+            env: std::env::vars_os().collect(),
+        }
+    }
+
+    fn send(&self, out: &mut impl Write) -> Result<()> {
+        dump_vec_string(
+            out,
+            &self.args.iter().map(|a| a.as_bytes()).collect::<Vec<_>>(),
+        )?;
+        dump_usize(out, self.env.len())?;
+        for (k, v) in &self.env {
+            dump_string(out, k.as_bytes())?;
+            dump_string(out, v.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn receive(f: &mut impl std::io::Read) -> Option<Self> {
+        let args = load_vec_string(f)?;
+        let num_envs = load_usize(f)?;
+        let mut env = Vec::with_capacity(num_envs);
+        for _ in 0..num_envs {
+            env.push((load_string(f)?, load_string(f)?));
+        }
+        Some(Self { args, env })
+    }
+
+    /// The arguments that affect the generated files: everything but the
+    /// binary and the daemon flags.
+    fn build_args(&self) -> Vec<&OsString> {
+        self.args
+            .iter()
+            .skip(1)
+            .filter(|a| *a != "--daemon" && *a != "--daemon_wait")
+            .collect()
+    }
+}
+
+/// Makes `env` the process's environment, which the regen check, evaluation
+/// and $(shell) read. This also drops what the previous run exported.
+fn apply_env(env: &[(OsString, OsString)]) {
+    let keys: HashSet<&OsString> = env.iter().map(|(k, _)| k).collect();
+    for (k, _) in std::env::vars_os() {
+        if !keys.contains(&k) {
+            // SAFETY: the daemon is single threaded between runs.
+            unsafe { std::env::remove_var(&k) };
+        }
+    }
+    for (k, v) in env {
+        // SAFETY: the daemon is single threaded between runs.
+        unsafe { std::env::set_var(k, v) };
+    }
+}
+
+/// Serves `--daemon_wait` clients and regenerates whenever a watched file
+/// changes. `daemon` is how the daemon itself was started, before the first
+/// run exported anything. `regenerate` runs kati again, which first checks the
+/// stamp, and returns whether it succeeded.
+#[cfg(target_os = "linux")]
+pub fn serve(daemon: Invocation, mut regenerate: impl FnMut() -> bool) -> Result<()> {
+    let socket = get_daemon_socket_filename();
+    let _ = std::fs::remove_file(&socket);
+    let listener = UnixListener::bind(&socket)
+        .with_context(|| format!("binding {}", socket.to_string_lossy()))?;
+    let mut watcher = Watcher::new()?;
+    watcher.watch_stamp();
+    eprintln!(
+        "*kati*: daemon: watching {} directories, listening on {}",
+        watcher.watches.len(),
+        socket.to_string_lossy()
+    );
+
+    let mut env = daemon.env.clone();
+    let mut deadline: Option<Instant> = None;
+    loop {
+        let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+        let ready = poll_readable(&[watcher.fd.as_raw_fd(), listener.as_raw_fd()], timeout)?;
+
+        if ready[0] && watcher.read_events()? {
+            deadline = Some(Instant::now() + DEBOUNCE);
+        }
+
+        if deadline.is_some_and(|d| d <= Instant::now()) {
+            deadline = None;
+            apply_env(&env);
+            regenerate();
+            watcher.watch_stamp();
+        }
+
+        if ready[1] {
+            let (stream, _) = listener.accept().context("accept")?;
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(10)));
+            let response: &[u8] = match Invocation::receive(&mut BufReader::new(&stream)) {
+                None => b"failed\n",
+                Some(client) if client.build_args() != daemon.build_args() => {
+                    b"different arguments\n"
+                }
+                Some(client) => {
+                    // Anything that changed so far is picked up by this run.
+                    watcher.read_events()?;
+                    deadline = None;
+                    env = client.env;
+                    apply_env(&env);
+                    let ok = regenerate();
+                    watcher.watch_stamp();
+                    if ok { b"ok\n" } else { b"failed\n" }
+                }
+            };
+            let _ = (&stream).write_all(response);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn serve(_daemon: Invocation, _regenerate: impl FnMut() -> bool) -> Result<()> {
+    anyhow::bail!("--daemon is only supported on Linux")
+}
+
+/// Blocks until the daemon has brought the ninja file up to date. Returns
+/// the exit status for `--daemon_wait`.
+pub fn wait_for_daemon() -> i32 {
+    let socket = get_daemon_socket_filename();
+    let mut stream = match UnixStream::connect(&socket) {
+        Ok(stream) => stream,
+        Err(err) => {
+            eprintln!(
+                "*** no kati daemon listening on {}: {err}",
+                socket.to_string_lossy()
+            );
+            return 1;
+        }
+    };
+    let mut response = String::new();
+    let mut request = Vec::new();
+    if Invocation::current().send(&mut request).is_err()
+        || stream.write_all(&request).is_err()
+        || BufReader::new(&stream).read_line(&mut response).is_err()
+    {
+        eprintln!("*** lost connection to the kati daemon");
+        return 1;
+    }
+    match response.trim_end() {
+        "ok" => 0,
+        "different arguments" => {
+            eprintln!("*** kati daemon was started with different arguments");
+            1
+        }
+        _ => {
+            eprintln!("*** kati daemon failed to regenerate the ninja file");
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_dir() {
+        assert_eq!(glob_dir(b"*.mk"), ".");
+        assert_eq!(glob_dir(b"a/b/*.mk"), "a/b");
+        assert_eq!(glob_dir(b"a/*/b.mk"), "a");
+        assert_eq!(glob_dir(b"/*.mk"), "/");
+        assert_eq!(glob_dir(b"a/b.mk"), "a");
+    }
+}
@@ -54,12 +54,14 @@ pub struct DepNode {
     pub is_default_target: bool,
     pub is_phony: bool,
     pub is_restat: bool,
+    pub is_console: bool,
     pub implicit_outputs: Vec<Symbol>,
     pub actual_inputs: Vec<Symbol>,
     pub actual_order_only_inputs: Vec<Symbol>,
     pub actual_validations: Vec<Symbol>,
     pub rule_vars: Option<Arc<Vars>>,
     pub depfile_var: Option<Var>,
+    pub dyndep_var: Option<Var>,
     pub ninja_pool_var: Option<Var>,
     pub tags_var: Option<Var>,
     pub output_pattern: Option<Symbol>,
@@ -67,7 +69,7 @@ pub struct DepNode {
 }
 
 impl DepNode {
-    fn new(output: Symbol, is_phony: bool, is_restat: bool) -> Arc<Mutex<Self>> {
+    fn new(output: Symbol, is_phony: bool, is_restat: bool, is_console: bool) -> Arc<Mutex<Self>> {
         Arc::new(Mutex::new(Self {
             output,
             cmds: Vec::new(),
@@ -78,12 +80,14 @@ impl DepNode {
             is_default_target: false,
             is_phony,
             is_restat,
+            is_console,
             implicit_outputs: Vec::new(),
             actual_inputs: Vec::new(),
             actual_order_only_inputs: Vec::new(),
             actual_validations: Vec::new(),
             rule_vars: None,
             depfile_var: None,
+            dyndep_var: None,
             ninja_pool_var: None,
             tags_var: None,
             output_pattern: None,
@@ -401,7 +405,9 @@ struct DepBuilder<'a> {
     done: HashMap<Symbol, Arc<Mutex<DepNode>>>,
     phony: HashSet<Symbol>,
     restat: HashSet<Symbol>,
+    console: HashSet<Symbol>,
     depfile_var_name: Symbol,
+    dyndep_var_name: Symbol,
     implicit_outputs_var_name: Symbol,
     ninja_pool_var_name: Symbol,
     validations_var_name: Symbol,
@@ -431,7 +437,9 @@ impl<'a> DepBuilder<'a> {
             done: HashMap::new(),
             phony: HashSet::new(),
             restat: HashSet::new(),
+            console: HashSet::new(),
             depfile_var_name: intern(".KATI_DEPFILE"),
+            dyndep_var_name: intern(".KATI_DYNDEP"),
             implicit_outputs_var_name: intern(".KATI_IMPLICIT_OUTPUTS"),
             ninja_pool_var_name: intern(".KATI_NINJA_POOL"),
             validations_var_name: intern(".KATI_VALIDATIONS"),
@@ -461,6 +469,11 @@ impl<'a> DepBuilder<'a> {
                 self.restat.insert(t);
             }
         }
+        if let Some((targets, _)) = self.get_rule_inputs(intern(".KATI_CONSOLE")) {
+            for t in targets {
+                self.console.insert(t);
+            }
+        }
         if let Some((targets, loc)) = self.get_rule_inputs(intern(".SUFFIXES")) {
             if targets.is_empty() {
                 self.suffix_rules.clear();
@@ -887,6 +900,7 @@ impl<'a> DepBuilder<'a> {
             output,
             self.phony.contains(&output),
             self.restat.contains(&output),
+            self.console.contains(&output),
         );
         self.done.insert(output, n.clone());
 
@@ -947,6 +961,8 @@ impl<'a> DepBuilder<'a> {
 
                 if *name == self.depfile_var_name {
                     n.lock().depfile_var = Some(new_var);
+                } else if *name == self.dyndep_var_name {
+                    n.lock().dyndep_var = Some(new_var);
                 } else if *name == self.implicit_outputs_var_name
                     || *name == self.validations_var_name
                 {
@@ -0,0 +1,204 @@
+/*
+Copyright 2025 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Dumps the dependency graph built by `make_dep` for `--dump_dep_graph`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    io::BufWriter,
+    io::Write,
+    sync::Arc,
+};
+
+use anyhow::Result;
+use parking_lot::Mutex;
+use serde_json::json;
+
+use crate::{
+    command::{Command, CommandEvaluator},
+    dep::{DepNode, NamedDepNode},
+    eval::Evaluator,
+    flags::FLAGS,
+    symtab::Symbol,
+    timeutil::ScopedTimeReporter,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DepGraphFormat {
+    #[default]
+    Json,
+    Dot,
+}
+
+impl DepGraphFormat {
+    pub fn parse(s: &[u8]) -> Option<Self> {
+        match s {
+            b"json" => Some(Self::Json),
+            b"dot" => Some(Self::Dot),
+            _ => None,
+        }
+    }
+}
+
+// Returns every node reachable from `nodes`, parents before children.
+fn collect_nodes(nodes: &[NamedDepNode]) -> Vec<Arc<Mutex<DepNode>>> {
+    let mut done = HashSet::new();
+    let mut result = Vec::new();
+    let mut stack: Vec<Arc<Mutex<DepNode>>> = nodes.iter().rev().map(|(_, n)| n.clone()).collect();
+    while let Some(n) = stack.pop() {
+        let node = n.lock();
+        if !done.insert(node.output) {
+            continue;
+        }
+        for (_, d) in node
+            .validations
+            .iter()
+            .rev()
+            .chain(node.order_onlys.iter().rev())
+            .chain(node.deps.iter().rev())
+        {
+            stack.push(d.clone());
+        }
+        drop(node);
+        result.push(n);
+    }
+    result
+}
+
+fn names(nodes: &[NamedDepNode]) -> Vec<String> {
+    nodes.iter().map(|(s, _)| s.to_string()).collect()
+}
+
+fn dump_json(
+    nodes: &[Arc<Mutex<DepNode>>],
+    ce: &mut Option<CommandEvaluator>,
+    evaluated: &mut HashMap<Symbol, Vec<Command>>,
+    out: &mut impl Write,
+) -> Result<()> {
+    writeln!(out, "[")?;
+    for (i, n) in nodes.iter().enumerate() {
+        let mut record = {
+            let node = n.lock();
+            json!({
+                "output": node.output.to_string(),
+                "deps": names(&node.deps),
+                "order_onlys": names(&node.order_onlys),
+                "validations": names(&node.validations),
+                "implicit_outputs": node.implicit_outputs.iter().map(Symbol::to_string).collect::<Vec<_>>(),
+                "has_rule": node.has_rule,
+                "is_phony": node.is_phony,
+                "is_restat": node.is_restat,
+                "loc": node.loc.as_ref().map(|l| l.to_string()),
+                "pattern": node.output_pattern.map(|p| p.to_string()),
+            })
+        };
+        if let Some(ce) = ce {
+            let commands = ce.eval(n)?;
+            record["commands"] = json!(
+                commands
+                    .iter()
+                    .map(|c| String::from_utf8_lossy(&c.cmd))
+                    .collect::<Vec<_>>()
+            );
+            evaluated.insert(n.lock().output, commands);
+        }
+        serde_json::to_writer(&mut *out, &record)?;
+        writeln!(out, "{}", if i + 1 < nodes.len() { "," } else { "" })?;
+    }
+    writeln!(out, "]")?;
+    Ok(())
+}
+
+fn dot_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn dump_dot(nodes: &[Arc<Mutex<DepNode>>], out: &mut impl Write) -> Result<()> {
+    writeln!(out, "digraph kati {{")?;
+    for n in nodes {
+        let node = n.lock();
+        let output = dot_quote(&node.output.to_string());
+        let shape = if node.is_phony {
+            "ellipse"
+        } else if node.has_rule {
+            "box"
+        } else {
+            "plaintext"
+        };
+        writeln!(out, "  {output} [shape={shape}];")?;
+        for (d, _) in &node.deps {
+            writeln!(out, "  {output} -> {};", dot_quote(&d.to_string()))?;
+        }
+        for (d, _) in &node.order_onlys {
+            writeln!(
+                out,
+                "  {output} -> {} [style=dashed];",
+                dot_quote(&d.to_string())
+            )?;
+        }
+        for (d, _) in &node.validations {
+            writeln!(
+                out,
+                "  {output} -> {} [style=dotted];",
+                dot_quote(&d.to_string())
+            )?;
+        }
+    }
+    writeln!(out, "}}")?;
+    Ok(())
+}
+
+/// Returns the commands evaluated for `--dump_dep_graph_commands`, by
+/// output, for ninja generation to reuse. Without `--ninja`, commands are
+/// expanded again when they run, so side effects of expanding them, like
+/// `$(info)`, happen twice.
+pub fn dump_dep_graph(
+    nodes: &[NamedDepNode],
+    ev: &mut Evaluator,
+    filename: &OsStr,
+) -> Result<HashMap<Symbol, Vec<Command>>> {
+    let _tr = ScopedTimeReporter::new("dump dep graph");
+    let nodes = collect_nodes(nodes);
+    let mut w: Box<dyn std::io::Write> = if filename == OsStr::new("-") {
+        Box::new(std::io::stdout())
+    } else {
+        let f = std::fs::File::create(filename)?;
+        Box::new(BufWriter::new(f))
+    };
+
+    let mut evaluated = HashMap::new();
+    match FLAGS.dump_dep_graph_format {
+        DepGraphFormat::Json => {
+            // Commands are expanded the way ninja generation does, so that
+            // $(shell) in recipes is not run here.
+            let avoid_io = ev.avoid_io;
+            ev.avoid_io = true;
+            let mut ce = if FLAGS.dump_dep_graph_commands {
+                Some(CommandEvaluator::new(ev)?)
+            } else {
+                None
+            };
+            let result = dump_json(&nodes, &mut ce, &mut evaluated, &mut w);
+            drop(ce);
+            ev.avoid_io = avoid_io;
+            result?;
+        }
+        DepGraphFormat::Dot => dump_dot(&nodes, &mut w)?,
+    }
+    w.flush()?;
+    Ok(evaluated)
+}
@@ -0,0 +1,426 @@
+/*
+Copyright 2025 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Finds the depfile a command writes, for `--detect_depfiles`.
+//!
+//! Each entry of `DEPFILE_DETECTORS` recognizes one family of tools and
+//! says which ninja `deps` type its depfiles use. `--depfile_detectors`
+//! picks the detectors to run, in order, and may override their `deps`.
+
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+use memchr::{memchr2, memmem};
+
+use crate::error;
+use crate::flags::FLAGS;
+use crate::strutil::{basename, strip_ext, strip_ext_vec, trim_left_space, word_scanner};
+
+/// How ninja treats a depfile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepsType {
+    /// `deps = gcc`: ninja moves the dependencies into .ninja_deps and
+    /// deletes the depfile. Only valid for depfiles with a single output.
+    Gcc,
+    /// No `deps` binding: ninja reads the depfile on every run.
+    None,
+}
+
+impl DepsType {
+    pub fn parse(s: &[u8]) -> Option<Self> {
+        match s {
+            b"gcc" => Some(Self::Gcc),
+            b"none" => Some(Self::None),
+            _ => None,
+        }
+    }
+
+    /// The value of the `deps` binding, if any.
+    pub fn binding(&self) -> Option<&'static str> {
+        match self {
+            Self::Gcc => Some("gcc"),
+            Self::None => None,
+        }
+    }
+}
+
+pub struct DepfileDetector {
+    pub name: &'static str,
+    pub deps: DepsType,
+    // Returns the depfile `cmd` writes. May rewrite `cmd`, e.g. to keep a
+    // copy of the depfile around.
+    detect: fn(&mut BytesMut) -> Result<Option<Bytes>>,
+}
+
+pub static DEPFILE_DETECTORS: [DepfileDetector; 3] = [
+    DepfileDetector {
+        name: "gcc",
+        deps: DepsType::Gcc,
+        detect: get_gcc_depfile,
+    },
+    // Our javac and kotlinc wrappers write one depfile for every class in
+    // the jar, which `deps = gcc` can't represent.
+    DepfileDetector {
+        name: "javac",
+        deps: DepsType::None,
+        detect: |cmd| Ok(get_wrapper_depfile(cmd, b"javac")),
+    },
+    DepfileDetector {
+        name: "kotlinc",
+        deps: DepsType::None,
+        detect: |cmd| Ok(get_wrapper_depfile(cmd, b"kotlinc")),
+    },
+];
+
+/// A detector enabled by `--depfile_detectors`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DepfileDetection {
+    /// Index into `DEPFILE_DETECTORS`.
+    pub detector: usize,
+    pub deps: DepsType,
+}
+
+impl DepfileDetection {
+    fn new(name: &[u8]) -> Option<Self> {
+        let detector = DEPFILE_DETECTORS
+            .iter()
+            .position(|d| d.name.as_bytes() == name)?;
+        Some(Self {
+            detector,
+            deps: DEPFILE_DETECTORS[detector].deps,
+        })
+    }
+
+    /// What `--detect_depfiles` does without `--depfile_detectors`.
+    pub fn defaults() -> Vec<Self> {
+        vec![Self::new(b"gcc").unwrap()]
+    }
+
+    /// Parses a comma separated list of `name` or `name:deps`.
+    pub fn parse_list(s: &[u8]) -> Option<Vec<Self>> {
+        s.split(|c| *c == b',')
+            .filter(|s| !s.is_empty())
+            .map(|item| {
+                let mut parts = item.splitn(2, |c| *c == b':');
+                let mut detection = Self::new(parts.next()?)?;
+                if let Some(deps) = parts.next() {
+                    detection.deps = DepsType::parse(deps)?;
+                }
+                Some(detection)
+            })
+            .collect()
+    }
+}
+
+pub struct DetectedDepfile {
+    pub path: Bytes,
+    pub deps: DepsType,
+    /// Index into `DEPFILE_DETECTORS`.
+    pub detector: usize,
+}
+
+/// Runs the enabled detectors over `cmd`, which must end with a space.
+pub fn detect_depfile(cmd: &mut BytesMut) -> Result<Option<DetectedDepfile>> {
+    for detection in &FLAGS.depfile_detectors {
+        let detector = &DEPFILE_DETECTORS[detection.detector];
+        if let Some(path) = (detector.detect)(cmd)? {
+            return Ok(Some(DetectedDepfile {
+                path,
+                deps: detection.deps,
+                detector: detection.detector,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+// gcc -MD/-MMD and clang spell the depfile with any of these.
+const GCC_MD_FLAGS: [&[u8]; 4] = [
+    b" -MD",
+    b" -MMD",
+    b" --write-dependencies",
+    b" --write-user-dependencies",
+];
+// The `=` form must come first, as the other would match it too.
+const GCC_MF_FLAGS: [&[u8]; 3] = [b" --dependency-file=", b" --dependency-file", b" -MF"];
+
+pub(crate) fn find_command_line_flag(cmd: &[u8], name: &[u8]) -> Option<usize> {
+    match memmem::find(cmd, name) {
+        Some(0) => None,
+        found => found,
+    }
+}
+
+pub(crate) fn find_command_line_flag_with_arg(cmd: &[u8], name: &[u8]) -> Option<Vec<u8>> {
+    let idx = find_command_line_flag(cmd, name)?;
+
+    let mut val = trim_left_space(&cmd[idx + name.len()..]);
+    while let Some(idx) = memmem::find(val, name) {
+        val = trim_left_space(&val[idx + name.len()..]);
+    }
+
+    let Some(idx) = memchr2(b' ', b'\t', val) else {
+        return Some(val.to_vec());
+    };
+    Some(val[..idx].to_vec())
+}
+
+fn get_gcc_depfile_impl(cmd: &[u8]) -> Result<Option<Vec<u8>>> {
+    if find_command_line_flag(cmd, b" -c").is_none() {
+        return Ok(None);
+    }
+
+    // -Wp,-MD,<file> hands the depfile straight to the preprocessor.
+    for flag in [b" -Wp,-MD,".as_slice(), b" -Wp,-MMD,"] {
+        if let Some(idx) = find_command_line_flag(cmd, flag) {
+            let val = &cmd[idx + flag.len()..];
+            let end = val
+                .iter()
+                .position(|c| b" \t,".contains(c))
+                .unwrap_or(val.len());
+            return Ok(Some(val[..end].to_vec()));
+        }
+    }
+
+    if GCC_MD_FLAGS
+        .iter()
+        .all(|flag| find_command_line_flag(cmd, flag).is_none())
+    {
+        return Ok(None);
+    }
+
+    for flag in GCC_MF_FLAGS {
+        if let Some(mf) = find_command_line_flag_with_arg(cmd, flag) {
+            return Ok(Some(mf));
+        }
+    }
+
+    let Some(o) = find_command_line_flag_with_arg(cmd, b" -o") else {
+        error!(
+            "Cannot find the depfile in {}",
+            String::from_utf8_lossy(cmd)
+        );
+    };
+
+    let mut o = strip_ext_vec(o);
+    o.extend_from_slice(b".d");
+    Ok(Some(o))
+}
+
+fn get_gcc_depfile(cmd: &mut BytesMut) -> Result<Option<Bytes>> {
+    assert!(!cmd.is_empty());
+    let Some(mut out) = get_gcc_depfile_impl(cmd)? else {
+        return Ok(None);
+    };
+
+    // A hack for Android - llvm-rs-cc seems not to emit a dep file.
+    if memmem::find(cmd, b"bin/llvm-rs-cc ").is_some() {
+        return Ok(None);
+    }
+
+    // TODO: A hack for Makefiles generated by automake.
+
+    // A hack for Android to get .P files instead of .d.
+    let mut p = strip_ext_vec(out.clone());
+    p.extend_from_slice(b".P");
+    if memmem::find(cmd, &p).is_some() {
+        let mut rm_f = b"; rm -f ".to_vec();
+        rm_f.extend_from_slice(&out);
+        let Some(found) = memmem::find(cmd, &rm_f) else {
+            error!(
+                "Cannot find remove of .d file: {}",
+                String::from_utf8_lossy(cmd.as_ref())
+            );
+        };
+        let after = cmd[found + rm_f.len()..].to_vec();
+        cmd.truncate(found);
+        cmd.extend_from_slice(&after);
+        return Ok(Some(Bytes::from(out)));
+    }
+
+    // A hack for Android. For .s files, GCC does not use C
+    // preprocessor, so it ignores -MF flag.
+    let mut a_s = b"/".to_vec();
+    a_s.extend_from_slice(strip_ext(basename(&out)));
+    a_s.extend_from_slice(b".s");
+    if memmem::find(cmd, &a_s).is_some() {
+        return Ok(None);
+    }
+
+    cmd.extend_from_slice(b"&& cp ");
+    cmd.extend_from_slice(&out);
+    cmd.put_u8(b' ');
+    cmd.extend_from_slice(&out);
+    cmd.extend_from_slice(b".tmp ");
+    out.extend_from_slice(b".tmp");
+    Ok(Some(Bytes::from(out)))
+}
+
+// Our wrappers take the depfile as `--depfile FILE` or `--depfile=FILE`.
+fn get_wrapper_depfile(cmd: &[u8], tool: &[u8]) -> Option<Bytes> {
+    if !word_scanner(cmd).any(|word| basename(word) == tool) {
+        return None;
+    }
+    find_command_line_flag_with_arg(cmd, b" --depfile=")
+        .or_else(|| find_command_line_flag_with_arg(cmd, b" --depfile"))
+        .map(Bytes::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_depfile_and_cmd(cmd: &str) -> Result<(Option<String>, String)> {
+        let mut cmd = BytesMut::from(cmd.as_bytes());
+        let depfile = get_gcc_depfile(&mut cmd)?;
+        Ok((
+            depfile.map(|b| String::from_utf8_lossy(&b).to_string()),
+            String::from_utf8_lossy(&cmd).to_string(),
+        ))
+    }
+    fn get_depfile(cmd: &str) -> Result<Option<String>> {
+        Ok(get_depfile_and_cmd(cmd)?.0)
+    }
+
+    #[test]
+    fn test_get_depfile() -> Result<()> {
+        assert!(get_depfile("g++ -c fat.cc -MD ").is_err());
+
+        assert_eq!(get_depfile("g++ -c fat.cc -o fat.o")?, None);
+        assert_eq!(
+            get_depfile("g++ -c fat.cc -MD -o fat.o -o fuga.o")?,
+            Some("fuga.d.tmp".into())
+        );
+        assert_eq!(
+            get_depfile("g++ -c fat.cc -MD -o fat.o")?,
+            Some("fat.d.tmp".into())
+        );
+        assert_eq!(
+            get_depfile("g++ -c fat.cc -MD -o fat")?,
+            Some("fat.d.tmp".into())
+        );
+        assert_eq!(
+            get_depfile("g++ -c fat.cc -MD -MF foo.d -o fat.o")?,
+            Some("foo.d.tmp".into())
+        );
+        assert_eq!(
+            get_depfile("g++ -c fat.cc -MD -o fat.o -MF foo.d")?,
+            Some("foo.d.tmp".into())
+        );
+        // A real example from maloader.
+        assert_eq!(
+            get_depfile(
+                "g++ -g -Iinclude -Wall -MMD -fno-omit-frame-pointer -O -m64 -W -Werror   -c -o fat.o fat.cc"
+            )?,
+            Some("fat.d.tmp".into())
+        );
+        // A real example from Android.
+        assert_eq!(
+            get_depfile_and_cmd(
+                "mkdir -p out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/ && echo \"host C++: llvm-rs-cc <= frameworks/compile/slang/llvm-rs-cc.cpp\" && prebuilts/clang/linux-x86/host/3.6/bin/clang++ -I external/llvm -I external/llvm/include -I external/llvm/host/include -I external/clang/include -I external/clang/lib/CodeGen -I frameworks/compile/libbcc/include -I out/host/linux-x86/gen/EXECUTABLES/llvm-rs-cc_intermediates/include -I external/libcxx/include -I frameworks/compile/slang -I out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates -I out/host/linux-x86/gen/EXECUTABLES/llvm-rs-cc_intermediates -I libnativehelper/include/nativehelper $(cat out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/import_includes) -isystem system/core/include -isystem hardware/libhardware/include -isystem hardware/libhardware_legacy/include -isystem hardware/ril/include -isystem libnativehelper/include -isystem frameworks/native/include -isystem frameworks/native/opengl/include -isystem frameworks/av/include -isystem frameworks/base/include -isystem tools/include -isystem out/host/linux-x86/obj/include -c    -fno-exceptions -Wno-multichar -m64 -Wa,--noexecstack -fPIC -no-canonical-prefixes -include build/core/combo/include/arch/linux-x86/AndroidConfig.h -U_FORTIFY_SOURCE -D_FORTIFY_SOURCE=0 -D__STDC_FORMAT_MACROS -D__STDC_CONSTANT_MACROS -DANDROID -fmessage-length=0 -W -Wall -Wno-unused -Winit-self -Wpointer-arith -O2 -g -fno-strict-aliasing -DNDEBUG -UDEBUG  -D__compiler_offsetof=__builtin_offsetof -Werror=int-conversion -Wno-unused-command-line-argument   --gcc-toolchain=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8/    --gcc-toolchain=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8/ --sysroot=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//sysroot -target x86_64-linux-gnu   -DANDROID -fmessage-length=0 -W -Wall -Wno-unused -Winit-self -Wpointer-arith -Wsign-promo -std=gnu++11 -DNDEBUG -UDEBUG  -Wno-inconsistent-missing-override   --gcc-toolchain=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8/ --sysroot=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//sysroot -isystem prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//x86_64-linux/include/c++/4.8 -isystem prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//x86_64-linux/include/c++/4.8/x86_64-linux -isystem prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//x86_64-linux/include/c++/4.8/backward -target x86_64-linux-gnu    -pedantic -Wcast-qual -Wno-long-long -Wno-sign-promo -Wall -Wno-unused-parameter -Wno-return-type -Werror -std=c++11 -O0 -DTARGET_BUILD_VARIANT=eng -DRS_VERSION=23 -D_GNU_SOURCE -D__STDC_LIMIT_MACROS -O2 -fomit-frame-pointer -Wall -W -Wno-unused-parameter -Wwrite-strings -Dsprintf=sprintf -pedantic -Wcast-qual -Wno-long-long -Wno-sign-promo -Wall -Wno-unused-parameter -Wno-return-type -Werror -std=c++11 -O0 -DTARGET_BUILD_VARIANT=eng -DRS_VERSION=23 -fno-exceptions -fpie -D_USING_LIBCXX   -Wno-sign-promo -fno-rtti -Woverloaded-virtual -Wno-sign-promo -std=c++11 -nostdinc++  -MD -MF out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.d -o out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.o frameworks/compile/slang/llvm-rs-cc.cpp && cp out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.d out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.P; sed -e 's/#.*//' -e 's/^[^:]*: *//' -e 's/ *\\$//' -e '/^$/ d' -e 's/$/ :/' < out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.d >> out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.P; rm -f out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.d"
+            )?,
+            (
+                Some("out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.d".into()),
+                "mkdir -p out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/ && echo \"host C++: llvm-rs-cc <= frameworks/compile/slang/llvm-rs-cc.cpp\" && prebuilts/clang/linux-x86/host/3.6/bin/clang++ -I external/llvm -I external/llvm/include -I external/llvm/host/include -I external/clang/include -I external/clang/lib/CodeGen -I frameworks/compile/libbcc/include -I out/host/linux-x86/gen/EXECUTABLES/llvm-rs-cc_intermediates/include -I external/libcxx/include -I frameworks/compile/slang -I out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates -I out/host/linux-x86/gen/EXECUTABLES/llvm-rs-cc_intermediates -I libnativehelper/include/nativehelper $(cat out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/import_includes) -isystem system/core/include -isystem hardware/libhardware/include -isystem hardware/libhardware_legacy/include -isystem hardware/ril/include -isystem libnativehelper/include -isystem frameworks/native/include -isystem frameworks/native/opengl/include -isystem frameworks/av/include -isystem frameworks/base/include -isystem tools/include -isystem out/host/linux-x86/obj/include -c    -fno-exceptions -Wno-multichar -m64 -Wa,--noexecstack -fPIC -no-canonical-prefixes -include build/core/combo/include/arch/linux-x86/AndroidConfig.h -U_FORTIFY_SOURCE -D_FORTIFY_SOURCE=0 -D__STDC_FORMAT_MACROS -D__STDC_CONSTANT_MACROS -DANDROID -fmessage-length=0 -W -Wall -Wno-unused -Winit-self -Wpointer-arith -O2 -g -fno-strict-aliasing -DNDEBUG -UDEBUG  -D__compiler_offsetof=__builtin_offsetof -Werror=int-conversion -Wno-unused-command-line-argument   --gcc-toolchain=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8/    --gcc-toolchain=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8/ --sysroot=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//sysroot -target x86_64-linux-gnu   -DANDROID -fmessage-length=0 -W -Wall -Wno-unused -Winit-self -Wpointer-arith -Wsign-promo -std=gnu++11 -DNDEBUG -UDEBUG  -Wno-inconsistent-missing-override   --gcc-toolchain=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8/ --sysroot=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//sysroot -isystem prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//x86_64-linux/include/c++/4.8 -isystem prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//x86_64-linux/include/c++/4.8/x86_64-linux -isystem prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//x86_64-linux/include/c++/4.8/backward -target x86_64-linux-gnu    -pedantic -Wcast-qual -Wno-long-long -Wno-sign-promo -Wall -Wno-unused-parameter -Wno-return-type -Werror -std=c++11 -O0 -DTARGET_BUILD_VARIANT=eng -DRS_VERSION=23 -D_GNU_SOURCE -D__STDC_LIMIT_MACROS -O2 -fomit-frame-pointer -Wall -W -Wno-unused-parameter -Wwrite-strings -Dsprintf=sprintf -pedantic -Wcast-qual -Wno-long-long -Wno-sign-promo -Wall -Wno-unused-parameter -Wno-return-type -Werror -std=c++11 -O0 -DTARGET_BUILD_VARIANT=eng -DRS_VERSION=23 -fno-exceptions -fpie -D_USING_LIBCXX   -Wno-sign-promo -fno-rtti -Woverloaded-virtual -Wno-sign-promo -std=c++11 -nostdinc++  -MD -MF out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.d -o out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.o frameworks/compile/slang/llvm-rs-cc.cpp && cp out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.d out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.P; sed -e 's/#.*//' -e 's/^[^:]*: *//' -e 's/ *\\$//' -e '/^$/ d' -e 's/$/ :/' < out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.d >> out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.P".into()
+            )
+        );
+        assert_eq!(
+            get_depfile(
+                "echo \"target asm: libsonivox <= external/sonivox/arm-wt-22k/lib_src/ARM-E_filter_gnu.s\" && mkdir -p out/target/product/generic/obj/SHARED_LIBRARIES/libsonivox_intermediates/lib_src/ && prebuilts/gcc/linux-x86/arm/arm-linux-androideabi-4.9/bin/arm-linux-androideabi-gcc -I external/sonivox/arm-wt-22k/host_src -I external/sonivox/arm-wt-22k/lib_src -I external/libcxx/include -I external/sonivox/arm-wt-22k -I out/target/product/generic/obj/SHARED_LIBRARIES/libsonivox_intermediates -I out/target/product/generic/gen/SHARED_LIBRARIES/libsonivox_intermediates -I libnativehelper/include/nativehelper $$(cat out/target/product/generic/obj/SHARED_LIBRARIES/libsonivox_intermediates/import_includes) -isystem system/core/include -isystem hardware/libhardware/include -isystem hardware/libhardware_legacy/include -isystem hardware/ril/include -isystem libnativehelper/include -isystem frameworks/native/include -isystem frameworks/native/opengl/include -isystem frameworks/av/include -isystem frameworks/base/include -isystem out/target/product/generic/obj/include -isystem bionic/libc/arch-arm/include -isystem bionic/libc/include -isystem bionic/libc/kernel/uapi -isystem bionic/libc/kernel/uapi/asm-arm -isystem bionic/libm/include -isystem bionic/libm/include/arm -c  -fno-exceptions -Wno-multichar -msoft-float -ffunction-sections -fdata-sections -funwind-tables -fstack-protector -Wa,--noexecstack -Werror=format-security -D_FORTIFY_SOURCE=2 -fno-short-enums -no-canonical-prefixes -fno-canonical-system-headers -march=armv7-a -mfloat-abi=softfp -mfpu=vfpv3-d16 -include build/core/combo/include/arch/linux-arm/AndroidConfig.h -I build/core/combo/include/arch/linux-arm/ -fno-builtin-sin -fno-strict-volatile-bitfields -Wno-psabi -mthumb-interwork -DANDROID -fmessage-length=0 -W -Wall -Wno-unused -Winit-self -Wpointer-arith -Werror=return-type -Werror=non-virtual-dtor -Werror=address -Werror=sequence-point -DNDEBUG -g -Wstrict-aliasing=2 -fgcse-after-reload -frerun-cse-after-loop -frename-registers -DNDEBUG -UDEBUG     -Wa,\"-I\" -Wa,\"external/sonivox/arm-wt-22k/lib_src\" -Wa,\"--defsym\" -Wa,\"SAMPLE_RATE_22050=1\" -Wa,\"--defsym\" -Wa,\"STEREO_OUTPUT=1\" -Wa,\"--defsym\" -Wa,\"FILTER_ENABLED=1\" -Wa,\"--defsym\" -Wa,\"SAMPLES_8_BIT=1\"   -D__ASSEMBLY__ -MD -MF out/target/product/generic/obj/SHARED_LIBRARIES/libsonivox_intermediates/lib_src/ARM-E_filter_gnu.d -o out/target/product/generic/obj/SHARED_LIBRARIES/libsonivox_intermediates/lib_src/ARM-E_filter_gnu.o external/sonivox/arm-wt-22k/lib_src/ARM-E_filter_gnu.s"
+            )?,
+            None
+        );
+        assert_eq!(
+            get_depfile(
+                "echo \"RenderScript: Galaxy4 <= packages/wallpapers/Galaxy4/src/com/android/galaxy4/galaxy.rs\" && rm -rf out/target/common/obj/APPS/Galaxy4_intermediates/src/renderscript && mkdir -p out/target/common/obj/APPS/Galaxy4_intermediates/src/renderscript/res/raw && mkdir -p out/target/common/obj/APPS/Galaxy4_intermediates/src/renderscript/src && out/host/linux-x86/bin/llvm-rs-cc -o out/target/common/obj/APPS/Galaxy4_intermediates/src/renderscript/res/raw -p out/target/common/obj/APPS/Galaxy4_intermediates/src/renderscript/src -d out/target/common/obj/APPS/Galaxy4_intermediates/src/renderscript -a out/target/common/obj/APPS/Galaxy4_intermediates/src/RenderScript.stamp -MD -target-api 14 -Wall -Werror  -I prebuilts/sdk/renderscript/clang-include -I prebuilts/sdk/renderscript/include packages/wallpapers/Galaxy4/src/com/android/galaxy4/galaxy.rs && mkdir -p out/target/common/obj/APPS/Galaxy4_intermediates/src/ && touch out/target/common/obj/APPS/Galaxy4_intermediates/src/RenderScript.stamp"
+            )?,
+            None
+        );
+        assert_eq!(
+            get_depfile_and_cmd(
+                "(echo \"bc: libclcore.bc <= frameworks/rs/driver/runtime/arch/generic.c\") && (mkdir -p out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/) && (prebuilts/clang/linux-x86/host/3.6/bin/clang -Iframeworks/rs/scriptc -Iexternal/clang/lib/Headers -MD -DRS_VERSION=23 -std=c99 -c -O3 -fno-builtin -emit-llvm -target armv7-none-linux-gnueabi -fsigned-char   -Iframeworks/rs/cpu_ref -DRS_DECLARE_EXPIRED_APIS -Xclang -target-feature -Xclang +long64  frameworks/rs/driver/runtime/arch/generic.c -o out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.bc) && (cp out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.d out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.P; sed -e 's/#.*//' -e 's/^[^:]*: *//' -e 's/ *\\$$//' -e '/^$$/ d' -e 's/$$/ :/' < out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.d >> out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.P; rm -f out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.d)"
+            )?,
+            (
+                Some("out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.d".into()),
+                "(echo \"bc: libclcore.bc <= frameworks/rs/driver/runtime/arch/generic.c\") && (mkdir -p out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/) && (prebuilts/clang/linux-x86/host/3.6/bin/clang -Iframeworks/rs/scriptc -Iexternal/clang/lib/Headers -MD -DRS_VERSION=23 -std=c99 -c -O3 -fno-builtin -emit-llvm -target armv7-none-linux-gnueabi -fsigned-char   -Iframeworks/rs/cpu_ref -DRS_DECLARE_EXPIRED_APIS -Xclang -target-feature -Xclang +long64  frameworks/rs/driver/runtime/arch/generic.c -o out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.bc) && (cp out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.d out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.P; sed -e 's/#.*//' -e 's/^[^:]*: *//' -e 's/ *\\$$//' -e '/^$$/ d' -e 's/$$/ :/' < out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.d >> out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.P)".into()
+            )
+        );
+        assert_eq!(get_depfile("gcc -c foo.P.c")?, None);
+        assert_eq!(get_depfile("gcc -MMD foo.o -o foo")?, None);
+        // TODO: Fix for automake.
+        // assert_eq!(get_depfile("(/bin/sh ./libtool  --tag=CXX   --mode=compile g++ -DHAVE_CONFIG_H -I. -I./src -I./src     -Wall -Wwrite-strings -Woverloaded-virtual -Wno-sign-compare  -DNO_FRAME_POINTER  -DNDEBUG -g -O2 -MT libglog_la-logging.lo -MD -MP -MF .deps/libglog_la-logging.Tpo -c -o libglog_la-logging.lo `test -f 'src/logging.cc' || echo './'`src/logging.cc) && (mv -f .deps/libglog_la-logging.Tpo .deps/libglog_la-logging.Plo)")?, Some(".deps/libglog_la-logging.Plo".into()));
+        // assert_eq!(get_depfile("(g++ -DHAVE_CONFIG_H -I. -I./src  -I./src  -pthread     -Wall -Wwrite-strings -Woverloaded-virtual -Wno-sign-compare  -DNO_FRAME_POINTER  -g -O2 -MT signalhandler_unittest-signalhandler_unittest.o -MD -MP -MF .deps/signalhandler_unittest-signalhandler_unittest.Tpo -c -o signalhandler_unittest-signalhandler_unittest.o `test -f 'src/signalhandler_unittest.cc' || echo './'`src/signalhandler_unittest.cc) && (mv -f .deps/signalhandler_unittest-signalhandler_unittest.Tpo .deps/signalhandler_unittest-signalhandler_unittest.Po)")?, Some(".deps/signalhandler_unittest-signalhandler_unittest.Po".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_gcc_depfile_clang_forms() -> Result<()> {
+        assert_eq!(
+            get_depfile("clang -c a.c -MD -MFa.d -o a.o")?,
+            Some("a.d.tmp".into())
+        );
+        assert_eq!(
+            get_depfile("clang -c a.c --write-dependencies --dependency-file=b.d -o a.o")?,
+            Some("b.d.tmp".into())
+        );
+        assert_eq!(
+            get_depfile("clang -c a.c --write-user-dependencies --dependency-file c.d -o a.o")?,
+            Some("c.d.tmp".into())
+        );
+        assert_eq!(
+            get_depfile("gcc -Wp,-MD,d.d,-MT,a.o -c a.c -o a.o")?,
+            Some("d.d.tmp".into())
+        );
+        assert_eq!(get_depfile("gcc -Wp,-MD,d.d a.c -o a")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_wrapper_depfile() {
+        let depfile = |cmd: &str, tool: &[u8]| {
+            get_wrapper_depfile(cmd.as_bytes(), tool)
+                .map(|b| String::from_utf8_lossy(&b).to_string())
+        };
+        assert_eq!(
+            depfile("bin/javac -d out --depfile out/a.d A.java ", b"javac"),
+            Some("out/a.d".into())
+        );
+        assert_eq!(
+            depfile("kotlinc --depfile=out/b.d B.kt ", b"kotlinc"),
+            Some("out/b.d".into())
+        );
+        assert_eq!(depfile("kotlinc --depfile=out/b.d B.kt ", b"javac"), None);
+        assert_eq!(depfile("javac -d out A.java ", b"javac"), None);
+    }
+
+    #[test]
+    fn test_parse_depfile_detectors() {
+        assert_eq!(
+            DepfileDetection::parse_list(b"javac,gcc:none"),
+            Some(vec![
+                DepfileDetection {
+                    detector: 1,
+                    deps: DepsType::None
+                },
+                DepfileDetection {
+                    detector: 0,
+                    deps: DepsType::None
+                },
+            ])
+        );
+        assert_eq!(DepfileDetection::parse_list(b"cl"), None);
+        assert_eq!(DepfileDetection::parse_list(b"gcc:msvc"), None);
+    }
+}
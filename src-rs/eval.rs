@@ -34,11 +34,15 @@ use crate::parser::{parse_assign_statement, parse_buf_no_stats};
 use crate::rule::{Rule, is_pattern_rule};
 use crate::stmt::{
     AssignOp, AssignStmt, CommandStmt, CondOp, ExportStmt, IfStmt, IncludeStmt, RuleSep, RuleStmt,
-    Statement,
+    Statement, Stmt,
 };
 use crate::strutil::{is_space_byte, trim_leading_curdir, trim_right_space, word_scanner};
-use crate::symtab::{ALLOW_RULES_SYM, KATI_READONLY_SYM, MAKEFILE_LIST, SHELL_SYM, Symbol, intern};
-use crate::var::{Var, VarOrigin, Variable, Vars};
+use crate::symtab::{
+    ALLOW_RULES_SYM, KATI_MEMOIZE_SYM, KATI_READONLY_SYM, MAKEFILE_LIST, SHELL_SYM, Symbol, intern,
+};
+use crate::var::{
+    USED_ENV_VARS, Var, VarOrigin, Variable, Vars, get_shell_status_var, restore_shell_status_var,
+};
 use crate::{collect_stats_with_slow_report, error_loc, file_cache, log, warn_loc};
 
 pub enum RulesAllowed {
@@ -48,6 +52,7 @@ pub enum RulesAllowed {
 }
 
 /// Whether `export` directives are allowed.
+#[derive(Clone)]
 pub enum ExportAllowed {
     /// Export directives are allowed, the default.
     Allowed,
@@ -57,6 +62,43 @@ pub enum ExportAllowed {
     Error(String),
 }
 
+/// What evaluation changed up to a checkpoint since the previous one, for
+/// --incremental_eval: the variables written in between as they were at
+/// this checkpoint, the undefined and environment variables first read in
+/// between, and the rest of the evaluator's state. Restoring every
+/// checkpoint up to one in order, on top of the state evaluation started
+/// from, goes back to that one. Rules are only ever appended during
+/// evaluation, so only their count is kept.
+pub struct EvalCheckpoint {
+    globals: Vec<(Symbol, Option<Var>)>,
+    rule_vars: Vec<(Symbol, Vars)>,
+    pub num_rules: usize,
+    exports: HashMap<Symbol, bool>,
+    profiled_files: Vec<OsString>,
+    is_posix: bool,
+    export_allowed: ExportAllowed,
+    used_undefined_vars: Vec<Symbol>,
+    used_env_vars: Vec<Symbol>,
+    shell_status: Option<i32>,
+}
+
+/// Where an include statement reached directly from a makefile's statement
+/// list stands: the statement's index, its expanded file patterns, and
+/// which of the files it includes is being evaluated. Checkpoints are taken
+/// only where every enclosing include is one of these, so that evaluation
+/// can resume there.
+#[derive(Clone, Debug)]
+pub struct IncludePos {
+    pub stmt_index: usize,
+    pub pats: Bytes,
+    pub file_index: usize,
+}
+
+/// A point to resume evaluation from: the includes it is nested in, from
+/// the top-level makefile down, and the index of the statement in the
+/// innermost makefile.
+pub type ResumeAt<'a> = (&'a [IncludePos], usize);
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum FrameType {
     Root,       // Root node. Exactly one of this exists.
@@ -128,6 +170,14 @@ impl Frame {
     }
 }
 
+/// An include or `$(call)` being evaluated. Unlike frames, these are kept
+/// even when tracing is off, so that errors can say how they were reached.
+pub struct CallSite {
+    pub name: Bytes,
+    pub loc: Loc,
+    pub is_include: bool,
+}
+
 pub struct ScopedFrame {
     stack: Arc<Mutex<Vec<Arc<Frame>>>>,
     frame: Option<Arc<Frame>>,
@@ -254,6 +304,8 @@ pub struct Evaluator {
 
     trace: bool,
     stack: Arc<Mutex<Vec<Arc<Frame>>>>,
+    // The includes and $(call)s being evaluated, outermost first.
+    pub call_stack: Vec<CallSite>,
     assignment_tracefile: Option<Box<dyn std::io::Write>>,
     assignment_sep: String,
 
@@ -275,6 +327,21 @@ pub struct Evaluator {
     pub profiled_files: Vec<OsString>,
 
     pub is_evaluating_command: bool,
+
+    // One entry per `.KATI_MEMOIZE` variable currently being expanded,
+    // collecting the variables it looked up.
+    memo_deps: Vec<Vec<(Symbol, Option<u64>)>>,
+
+    // With --incremental_eval, the includes being evaluated, `None` for
+    // those not reached directly from a statement list, and the index of
+    // the include statement about to be evaluated directly.
+    include_path: Vec<Option<IncludePos>>,
+    direct_include: Option<usize>,
+    // The targets whose variables were written since the last checkpoint,
+    // and the reads earlier checkpoints recorded.
+    written_rule_vars: HashSet<Symbol>,
+    checkpointed_undefined_vars: HashSet<Symbol>,
+    checkpointed_env_vars: HashSet<Symbol>,
 }
 
 impl Default for Evaluator {
@@ -306,6 +373,7 @@ impl Evaluator {
                 None,
                 Bytes::from_static(b"*root*"),
             ))])),
+            call_stack: Vec::new(),
             assignment_tracefile: None,
             assignment_sep: "\n".to_string(),
 
@@ -321,6 +389,14 @@ impl Evaluator {
             profiled_files: Vec::new(),
 
             is_evaluating_command: false,
+
+            memo_deps: Vec::new(),
+
+            include_path: Vec::new(),
+            direct_include: None,
+            written_rule_vars: HashSet::new(),
+            checkpointed_undefined_vars: HashSet::new(),
+            checkpointed_env_vars: HashSet::new(),
         }
     }
 
@@ -423,6 +499,7 @@ impl Evaluator {
                         );
                     }
                     result = prev;
+                    lhs.mark_global_var_written();
                     if result.read().immediate_eval() {
                         let buf = rhs_v.eval_to_buf(self)?;
                         result.write().append_str(&buf, self.current_frame())?;
@@ -467,6 +544,9 @@ impl Evaluator {
             if needs_assign && let Some(deprecated) = &prev.deprecated {
                 result.write().deprecated = Some(deprecated.clone());
             }
+            if needs_assign && prev.memoize {
+                result.write().memoize = true;
+            }
         }
 
         Ok((result, needs_assign))
@@ -485,6 +565,20 @@ impl Evaluator {
                     error_loc!(self.loc.as_ref(), "*** unknown variable: {name}");
                 };
                 var.write().readonly = true;
+                name.mark_global_var_written();
+            }
+            return Ok(());
+        }
+
+        if lhs == *KATI_MEMOIZE_SYM {
+            let rhs = stmt.rhs.eval_to_buf(self)?;
+            for name in word_scanner(&rhs) {
+                let name = intern(rhs.slice_ref(name));
+                let Some(var) = name.get_global_var() else {
+                    error_loc!(self.loc.as_ref(), "*** unknown variable: {name}");
+                };
+                var.write().memoize = true;
+                name.mark_global_var_written();
             }
             return Ok(());
         }
@@ -509,7 +603,8 @@ impl Evaluator {
         }
 
         if stmt.is_final {
-            var.write().readonly = true
+            var.write().readonly = true;
+            lhs.mark_global_var_written();
         }
         self.trace_variable_assign(&lhs, &var)?;
         Ok(())
@@ -582,6 +677,7 @@ impl Evaluator {
         let var_sym = intern(after_targets.slice_ref(assign.lhs));
         let is_final = stmt.sep == RuleSep::FinalEq;
         for target in targets {
+            self.written_rule_vars.insert(*target);
             let scope = self
                 .rule_vars
                 .entry(*target)
@@ -802,7 +898,44 @@ impl Evaluator {
         Ok(())
     }
 
-    pub fn do_include(&mut self, fname: &Bytes) -> Result<()> {
+    /// Evaluates the statements of a makefile, or with `resume`, the rest of
+    /// them from a checkpoint. With --incremental_eval, a checkpoint is taken
+    /// before each include statement that evaluation could resume from.
+    pub fn eval_stmts(&mut self, stmts: &[Stmt], resume: Option<ResumeAt>) -> Result<()> {
+        let mut start = 0;
+        if let Some((path, stmt_index)) = resume {
+            match path.split_first() {
+                None => start = stmt_index,
+                Some((pos, rest)) => {
+                    let Some(stmt) = stmts[pos.stmt_index].as_include() else {
+                        panic!("checkpoint in a statement that is not an include");
+                    };
+                    self.loc = Some(stmt.loc());
+                    self.in_rule = false;
+                    self.include_files(
+                        stmt,
+                        pos.pats.clone(),
+                        Some(pos.stmt_index),
+                        Some((pos.file_index, (rest, stmt_index))),
+                    )?;
+                    start = pos.stmt_index + 1;
+                }
+            }
+        }
+        for (i, stmt) in stmts.iter().enumerate().skip(start) {
+            log!("{stmt:?}");
+            if FLAGS.incremental_eval && stmt.as_include().is_some() {
+                if let Some(path) = self.include_path.iter().cloned().collect() {
+                    crate::incremental::checkpoint(self, path, i, stmt.loc());
+                }
+                self.direct_include = Some(i);
+            }
+            stmt.eval(self)?;
+        }
+        Ok(())
+    }
+
+    pub fn do_include(&mut self, fname: &Bytes, resume: Option<ResumeAt>) -> Result<()> {
         let filename = OsString::from_vec(fname.to_vec());
         collect_stats_with_slow_report!("included makefiles", &filename);
 
@@ -814,25 +947,26 @@ impl Evaluator {
             );
         };
 
-        let v = fname.slice_ref(trim_leading_curdir(fname));
-        if let Some(var_list) = self.lookup_var(*MAKEFILE_LIST)? {
-            var_list.write().append_str(&v, self.current_frame())?;
-        } else {
-            MAKEFILE_LIST.set_global_var(
-                Variable::with_simple_string(
-                    v,
-                    VarOrigin::File,
-                    Some(self.current_frame()),
-                    self.loc.clone(),
-                ),
-                false,
-                None,
-            )?;
-        }
-        for stmt in mk.stmts.lock().iter() {
-            log!("{stmt:?}");
-            stmt.eval(self)?;
+        // When resuming, MAKEFILE_LIST at the checkpoint already has the file.
+        if resume.is_none() {
+            let v = fname.slice_ref(trim_leading_curdir(fname));
+            if let Some(var_list) = self.lookup_var(*MAKEFILE_LIST)? {
+                var_list.write().append_str(&v, self.current_frame())?;
+                MAKEFILE_LIST.mark_global_var_written();
+            } else {
+                MAKEFILE_LIST.set_global_var(
+                    Variable::with_simple_string(
+                        v,
+                        VarOrigin::File,
+                        Some(self.current_frame()),
+                        self.loc.clone(),
+                    ),
+                    false,
+                    None,
+                )?;
+            }
         }
+        self.eval_stmts(&mk.stmts.lock(), resume)?;
 
         if !self.profiled_files.is_empty() {
             for mk in std::mem::take(&mut self.profiled_files) {
@@ -845,8 +979,23 @@ impl Evaluator {
     pub fn eval_include(&mut self, stmt: &IncludeStmt) -> Result<()> {
         self.loc = Some(stmt.loc());
         self.in_rule = false;
+        let direct = self.direct_include.take();
 
         let pats = stmt.expr.eval_to_buf(self)?;
+        self.include_files(stmt, pats, direct, None)
+    }
+
+    // Includes the files `pats` expands to. `direct` is the index of the
+    // include statement if it was reached directly from a statement list.
+    // `resume` skips to the file with the given index and resumes in it.
+    fn include_files(
+        &mut self,
+        stmt: &IncludeStmt,
+        pats: Bytes,
+        direct: Option<usize>,
+        resume: Option<(usize, ResumeAt)>,
+    ) -> Result<()> {
+        let mut file_index = 0;
         for pat in word_scanner(&pats) {
             let pat = pats.slice_ref(pat);
             let files = crate::fileutil::glob(pat.clone());
@@ -886,12 +1035,31 @@ impl Evaluator {
                 {
                     continue;
                 }
+                let index = file_index;
+                file_index += 1;
+                let inner = match resume {
+                    Some((resume_index, _)) if index < resume_index => continue,
+                    Some((resume_index, inner)) if index == resume_index => Some(inner),
+                    _ => None,
+                };
 
+                self.include_path.push(direct.map(|stmt_index| IncludePos {
+                    stmt_index,
+                    pats: pats.clone(),
+                    file_index: index,
+                }));
+                self.call_stack.push(CallSite {
+                    name: fname.clone(),
+                    loc: stmt.loc(),
+                    is_include: true,
+                });
                 {
                     let _frame = self.enter(FrameType::Parse, fname.clone(), stmt.loc());
-                    self.do_include(fname)
+                    self.do_include(fname, inner)
                         .with_context(|| format!("In file included from {}:", stmt.loc()))?;
                 }
+                self.call_stack.pop();
+                self.include_path.pop();
             }
         }
 
@@ -1043,9 +1211,39 @@ impl Evaluator {
         }
 
         self.trace_variable_lookup("lookup", &name, &result)?;
+        if let Some(deps) = self.memo_deps.last_mut() {
+            deps.push((name, result.as_ref().map(|v| v.read().generation())));
+        }
         Ok(result)
     }
 
+    pub fn begin_memo(&mut self) {
+        self.memo_deps.push(Vec::new());
+    }
+
+    /// Finishes recording a memoized expansion and returns the variables
+    /// it depended on. These are also dependencies of any enclosing
+    /// memoized expansion.
+    pub fn end_memo(&mut self) -> Vec<(Symbol, Option<u64>)> {
+        let mut deps = self.memo_deps.pop().unwrap();
+        let mut seen = HashSet::new();
+        deps.retain(|dep| seen.insert(*dep));
+        self.add_memo_deps(&deps);
+        deps
+    }
+
+    pub fn add_memo_deps(&mut self, deps: &[(Symbol, Option<u64>)]) {
+        if let Some(parent) = self.memo_deps.last_mut() {
+            parent.extend_from_slice(deps);
+        }
+    }
+
+    pub fn memo_is_valid(&self, deps: &[(Symbol, Option<u64>)]) -> bool {
+        deps.iter().all(|(sym, generation)| {
+            self.peek_var(*sym).map(|v| v.read().generation()) == *generation
+        })
+    }
+
     pub fn peek_var(&self, name: Symbol) -> Option<Var> {
         let mut result = None;
 
@@ -1130,4 +1328,92 @@ impl Evaluator {
     pub fn used_undefined_vars() -> HashSet<Symbol> {
         USED_UNDEFINED_VARS.lock().clone()
     }
+
+    pub fn clear_used_undefined_vars() {
+        USED_UNDEFINED_VARS.lock().clear();
+    }
+
+    /// Starts or stops recording what evaluation writes and reads between
+    /// checkpoints.
+    pub fn track_changes(&mut self, enabled: bool) {
+        crate::symtab::track_global_var_writes(enabled);
+        self.written_rule_vars.clear();
+        self.checkpointed_undefined_vars.clear();
+        self.checkpointed_env_vars.clear();
+    }
+
+    /// Records what changed since the previous checkpoint.
+    pub fn checkpoint(&mut self) -> EvalCheckpoint {
+        let rule_vars = std::mem::take(&mut self.written_rule_vars)
+            .into_iter()
+            .filter_map(|sym| Some((sym, self.rule_vars.get(&sym)?.snapshot())))
+            .collect();
+        let used_undefined_vars = USED_UNDEFINED_VARS
+            .lock()
+            .iter()
+            .filter(|sym| self.checkpointed_undefined_vars.insert(**sym))
+            .copied()
+            .collect();
+        let used_env_vars = USED_ENV_VARS
+            .lock()
+            .iter()
+            .filter(|sym| self.checkpointed_env_vars.insert(**sym))
+            .copied()
+            .collect();
+        EvalCheckpoint {
+            globals: crate::symtab::take_written_global_vars(),
+            rule_vars,
+            num_rules: self.rules.len(),
+            exports: self.exports.clone(),
+            profiled_files: self.profiled_files.clone(),
+            is_posix: self.is_posix,
+            export_allowed: self.export_allowed.clone(),
+            used_undefined_vars,
+            used_env_vars,
+            shell_status: get_shell_status_var(),
+        }
+    }
+
+    /// Goes back to the state at the last of `cps`, given every checkpoint
+    /// up to it in order and the rules evaluation had added by then.
+    /// Evaluation resumes right before the last checkpoint is taken again,
+    /// so what it recorded counts as not checkpointed yet.
+    pub fn restore(&mut self, cps: &[&EvalCheckpoint], rules: Vec<Rule>) {
+        let Some(last) = cps.last() else {
+            return;
+        };
+        assert_eq!(rules.len(), last.num_rules);
+        for cp in cps {
+            crate::symtab::restore_global_vars(&cp.globals);
+            for (sym, vars) in &cp.rule_vars {
+                self.rule_vars.insert(*sym, Arc::new(vars.snapshot()));
+            }
+            USED_UNDEFINED_VARS
+                .lock()
+                .extend(cp.used_undefined_vars.iter().copied());
+            USED_ENV_VARS
+                .lock()
+                .extend(cp.used_env_vars.iter().copied());
+            self.checkpointed_undefined_vars
+                .extend(cp.used_undefined_vars.iter().copied());
+            self.checkpointed_env_vars
+                .extend(cp.used_env_vars.iter().copied());
+        }
+        for (sym, _) in &last.globals {
+            sym.mark_global_var_written();
+        }
+        self.written_rule_vars = last.rule_vars.iter().map(|(sym, _)| *sym).collect();
+        for sym in &last.used_undefined_vars {
+            self.checkpointed_undefined_vars.remove(sym);
+        }
+        for sym in &last.used_env_vars {
+            self.checkpointed_env_vars.remove(sym);
+        }
+        self.rules = rules;
+        self.exports = last.exports.clone();
+        self.profiled_files = last.profiled_files.clone();
+        self.is_posix = last.is_posix;
+        self.export_allowed = last.export_allowed.clone();
+        restore_shell_status_var(last.shell_status);
+    }
 }
@@ -19,8 +19,10 @@ use std::{ffi::OsStr, os::unix::ffi::OsStrExt, sync::Arc};
 use anyhow::Result;
 use bytes::Bytes;
 use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
 
 use crate::{
+    flags::FLAGS,
     parser::parse_file,
     stmt::Stmt,
     symtab::{Symbol, intern},
@@ -29,6 +31,9 @@ use crate::{
 pub struct Makefile {
     pub filename: Symbol,
     pub stmts: Arc<Mutex<Vec<Stmt>>>,
+    // The SHA-256 of the contents that were parsed, recorded in the stamp
+    // for --regen_content_hash.
+    pub hash: Option<[u8; 32]>,
 }
 
 impl Makefile {
@@ -38,10 +43,17 @@ impl Makefile {
         }
 
         let buf = Bytes::from(std::fs::read(filename)?);
+        let hash = FLAGS
+            .regen_content_hash
+            .then(|| Sha256::digest(&buf).into());
 
         let filename = intern(filename.as_bytes().to_vec());
         let stmts = parse_file(&buf, filename)?;
 
-        Ok(Some(Arc::new(Makefile { filename, stmts })))
+        Ok(Some(Arc::new(Makefile {
+            filename,
+            stmts,
+            hash,
+        })))
     }
 }
@@ -15,26 +15,53 @@ limitations under the License.
 */
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashMap,
     ffi::{OsStr, OsString},
     sync::{Arc, LazyLock},
+    time::SystemTime,
 };
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use bytes::Bytes;
 use parking_lot::Mutex;
 
-use crate::file::Makefile;
+use crate::{file::Makefile, fileutil::hash_file, flags::FLAGS};
 
 static CACHE: LazyLock<Mutex<MakefileCacheManager>> = LazyLock::new(|| {
     Mutex::new(MakefileCacheManager {
         cache: HashMap::new(),
-        extra_file_deps: HashSet::new(),
+        json_cache: HashMap::new(),
+        extra_file_deps: HashMap::new(),
+        mtimes: HashMap::new(),
+        warm: HashMap::new(),
+        run_start: crate::timeutil::start_time(),
+        added: None,
     })
 });
 
+/// A JSON document read by the KATI_json_* functions.
+pub struct JsonFile {
+    pub contents: Bytes,
+    pub value: serde_json::Value,
+}
+
 struct MakefileCacheManager {
     cache: HashMap<OsString, Option<Arc<Makefile>>>,
-    extra_file_deps: HashSet<OsString>,
+    json_cache: HashMap<OsString, Arc<JsonFile>>,
+    // The extra file dependencies, with the hash of their contents when they
+    // were added if --regen_content_hash is set.
+    extra_file_deps: HashMap<OsString, Option<[u8; 32]>>,
+    // With --daemon, the mtime of each makefile when it was parsed, and the
+    // makefiles parsed by earlier runs. Those are reused by later runs as
+    // long as their mtime is unchanged.
+    mtimes: HashMap<OsString, Option<SystemTime>>,
+    warm: HashMap<OsString, (Option<SystemTime>, Option<Arc<Makefile>>)>,
+    // A makefile modified at or after the start of the run that read it
+    // could change again without its mtime changing, so it is not kept warm.
+    run_start: SystemTime,
+    // With --incremental_eval, the files read since the last
+    // `take_used_files`.
+    added: Option<UsedFiles>,
 }
 
 impl MakefileCacheManager {
@@ -43,28 +70,172 @@ impl MakefileCacheManager {
             return Ok(mk.clone());
         }
         let filename = filename.to_os_string();
-        let mk = Makefile::from_file(&filename)?;
-        self.cache.insert(filename, mk.clone());
+        if let Some(added) = &mut self.added {
+            added.makefiles.push(filename.clone());
+        }
+        if !FLAGS.daemon {
+            let mk = parse_makefile(&filename)?;
+            self.cache.insert(filename, mk.clone());
+            return Ok(mk);
+        }
+
+        let mtime = std::fs::metadata(&filename)
+            .and_then(|md| md.modified())
+            .ok();
+        let mk = match self.warm.remove(&filename) {
+            Some((warm_mtime, mk)) if warm_mtime == mtime => mk,
+            _ => parse_makefile(&filename)?,
+        };
+        self.cache.insert(filename.clone(), mk.clone());
+        self.mtimes.insert(filename, mtime);
         Ok(mk)
     }
+
+    fn add_extra_file_dep(&mut self, filename: OsString, hash: Option<[u8; 32]>) {
+        if self.extra_file_deps.contains_key(&filename) {
+            return;
+        }
+        if let Some(added) = &mut self.added {
+            added.extra_file_deps.push((filename.clone(), hash));
+        }
+        self.extra_file_deps.insert(filename, hash);
+    }
+
+    fn get_json(&mut self, filename: &OsStr) -> Result<Option<(Arc<JsonFile>, bool)>> {
+        if let Some(json) = self.json_cache.get(filename) {
+            return Ok(Some((json.clone(), false)));
+        }
+        if !std::fs::exists(filename)? {
+            return Ok(None);
+        }
+        let contents = Bytes::from(std::fs::read(filename)?);
+        let value = serde_json::from_slice(&contents)
+            .with_context(|| format!("failed to parse {}", filename.to_string_lossy()))?;
+        let json = Arc::new(JsonFile { contents, value });
+        self.json_cache
+            .insert(filename.to_os_string(), json.clone());
+        Ok(Some((json, true)))
+    }
+}
+
+fn parse_makefile(filename: &OsStr) -> Result<Option<Arc<Makefile>>> {
+    if FLAGS.ast_cache {
+        crate::ast_cache::load_makefile(filename)
+    } else {
+        Makefile::from_file(filename)
+    }
 }
 
 pub fn get_makefile(filename: &OsStr) -> Result<Option<Arc<Makefile>>> {
     CACHE.lock().get_makefile(filename)
 }
 
-pub fn add_extra_file_dep(filename: OsString) {
-    CACHE.lock().extra_file_deps.insert(filename);
+/// Returns the parsed JSON file, and whether this call read it from disk.
+/// Returns `None` if the file does not exist.
+pub fn get_json(filename: &OsStr) -> Result<Option<(Arc<JsonFile>, bool)>> {
+    CACHE.lock().get_json(filename)
+}
+
+/// Forgets which files the previous evaluation used, keeping the parsed
+/// makefiles around for the next one.
+pub fn start_new_run() {
+    let mut manager = CACHE.lock();
+    let manager = &mut *manager;
+    for (filename, mk) in manager.cache.drain() {
+        let mtime = manager.mtimes.remove(&filename).flatten();
+        if mtime.is_some_and(|mtime| mtime < manager.run_start) {
+            manager.warm.insert(filename, (mtime, mk));
+        }
+    }
+    manager.run_start = crate::timeutil::start_time();
+    manager.json_cache.clear();
+    manager.extra_file_deps.clear();
+}
+
+/// Makefiles and other files evaluation has read.
+#[derive(Default)]
+pub struct UsedFiles {
+    pub makefiles: Vec<OsString>,
+    pub extra_file_deps: Vec<(OsString, Option<[u8; 32]>)>,
+}
+
+/// Starts or stops recording which files evaluation reads, for
+/// --incremental_eval. Files read before count as read since the last
+/// `take_used_files`.
+pub fn track_used_files(enabled: bool) {
+    let mut manager = CACHE.lock();
+    manager.added = enabled.then(|| UsedFiles {
+        makefiles: manager.cache.keys().cloned().collect(),
+        extra_file_deps: manager
+            .extra_file_deps
+            .iter()
+            .map(|(f, h)| (f.clone(), *h))
+            .collect(),
+    });
+}
+
+/// The files read since the last call.
+pub fn take_used_files() -> UsedFiles {
+    CACHE
+        .lock()
+        .added
+        .as_mut()
+        .map(std::mem::take)
+        .unwrap_or_default()
+}
+
+/// Marks the files of an earlier evaluation as read by this one, so that
+/// they end up in the stamp even though this evaluation skipped reading them.
+pub fn restore_used_files(used: &UsedFiles) -> Result<()> {
+    let mut manager = CACHE.lock();
+    for filename in &used.makefiles {
+        manager.get_makefile(filename)?;
+    }
+    for (filename, hash) in &used.extra_file_deps {
+        manager.add_extra_file_dep(filename.clone(), *hash);
+    }
+    Ok(())
+}
+
+/// Records restored files as read since the last `take_used_files`.
+pub fn mark_used_files(used: &UsedFiles) {
+    if let Some(added) = &mut CACHE.lock().added {
+        added.makefiles.extend(used.makefiles.iter().cloned());
+        added
+            .extra_file_deps
+            .extend(used.extra_file_deps.iter().cloned());
+    }
+}
+
+/// Adds a file whose changes should trigger regeneration. `hash` is the
+/// digest of the contents the caller read, if any; otherwise the file is
+/// hashed now when --regen_content_hash is set. The first hash recorded for
+/// a file is kept.
+pub fn add_extra_file_dep(filename: OsString, hash: Option<[u8; 32]>) {
+    let mut manager = CACHE.lock();
+    if manager.extra_file_deps.contains_key(&filename) {
+        return;
+    }
+    let hash = hash.or_else(|| {
+        if FLAGS.regen_content_hash {
+            hash_file(&filename).ok()
+        } else {
+            None
+        }
+    });
+    manager.add_extra_file_dep(filename, hash);
 }
 
-pub fn get_all_filenames() -> HashSet<OsString> {
+/// Returns every file read so far, with the hash of the contents that were
+/// read if it was recorded.
+pub fn get_all_files() -> HashMap<OsString, Option<[u8; 32]>> {
     let manager = CACHE.lock();
-    let mut ret = HashSet::new();
-    for p in manager.cache.keys() {
-        ret.insert(p.clone());
+    let mut ret = HashMap::new();
+    for (p, mk) in &manager.cache {
+        ret.insert(p.clone(), mk.as_ref().and_then(|mk| mk.hash));
     }
-    for f in &manager.extra_file_deps {
-        ret.insert(f.clone());
+    for (f, hash) in &manager.extra_file_deps {
+        ret.entry(f.clone()).or_insert(*hash);
     }
     ret
 }
@@ -14,11 +14,14 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
-use std::io::Read;
+use std::io::{Read, Write};
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
 use std::{
     collections::HashMap,
-    ffi::{CStr, CString, OsStr},
+    ffi::{CStr, CString, OsStr, OsString},
+    fs::{File, OpenOptions},
+    io::BufWriter,
     process::{Command, ExitStatus},
     slice,
     sync::{Arc, LazyLock},
@@ -29,6 +32,7 @@ use anyhow::Result;
 use bytes::{BufMut, Bytes, BytesMut};
 use memchr::memchr2;
 use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
 
 use crate::log;
 
@@ -48,12 +52,171 @@ pub fn get_timestamp(filename: &[u8]) -> Result<Option<SystemTime>> {
     Ok(Some(metadata.modified()?))
 }
 
+/// Returns the SHA-256 of the file's contents, read in fixed size chunks so
+/// large files are not loaded into memory at once.
+pub fn hash_file(filename: &OsStr) -> std::io::Result<[u8; 32]> {
+    let mut f = std::fs::File::open(filename)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Writes a file through `<filename>.tmp`, and only replaces the original
+/// when the contents differ, so its mtime stays put when nothing changed.
+/// The written data is hashed as it goes, so the output is never held in
+/// memory.
+pub struct ChangedFileWriter {
+    filename: OsString,
+    tmp_filename: OsString,
+    out: BufWriter<File>,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl ChangedFileWriter {
+    pub fn create(filename: &OsStr) -> std::io::Result<Self> {
+        Self::create_with_mode(filename, 0o666)
+    }
+
+    pub fn create_with_mode(filename: &OsStr, mode: u32) -> std::io::Result<Self> {
+        let mut tmp_filename = filename.to_os_string();
+        tmp_filename.push(".tmp");
+        let out = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(mode)
+            .open(&tmp_filename)?;
+        Ok(Self {
+            filename: filename.to_os_string(),
+            tmp_filename,
+            out: BufWriter::new(out),
+            hasher: Sha256::new(),
+            len: 0,
+        })
+    }
+
+    /// Finishes writing, and returns whether the file was replaced.
+    pub fn commit(mut self) -> std::io::Result<bool> {
+        self.out.flush()?;
+        let unchanged = std::fs::metadata(&self.filename).is_ok_and(|m| m.len() == self.len)
+            && hash_file(&self.filename).is_ok_and(|h| h[..] == self.hasher.finalize_reset()[..]);
+        if unchanged {
+            std::fs::remove_file(&self.tmp_filename)?;
+        } else {
+            std::fs::rename(&self.tmp_filename, &self.filename)?;
+        }
+        Ok(!unchanged)
+    }
+}
+
+impl Write for ChangedFileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.out.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+pub fn hex_string(digest: &[u8]) -> String {
+    use std::fmt::Write;
+    let mut s = String::with_capacity(digest.len() * 2);
+    for b in digest {
+        let _ = write!(s, "{b:02x}");
+    }
+    s
+}
+
+/// Returns the number of bytes the environment takes in a child's
+/// argument space, including the envp pointers.
+pub fn environ_size() -> usize {
+    std::env::vars_os()
+        .map(|(k, v)| k.len() + v.len() + 2 + std::mem::size_of::<usize>())
+        .sum()
+}
+
+/// Returns the longest single argument a child with an environment of
+/// `env_size` bytes can be passed.
+pub fn max_arg_len(env_size: usize) -> usize {
+    // SAFETY: sysconf has no preconditions.
+    let arg_max = unsafe { libc::sysconf(libc::_SC_ARG_MAX) };
+    // POSIX guarantees at least 4096.
+    let arg_max = if arg_max > 0 { arg_max as usize } else { 4096 };
+    // Leave room for the shell, its flags and the argv pointers.
+    let len = arg_max.saturating_sub(env_size).saturating_sub(4096);
+    if cfg!(target_os = "linux") {
+        // Linux also limits each argument to 32 pages (MAX_ARG_STRLEN),
+        // including the terminating NUL.
+        // SAFETY: sysconf has no preconditions.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(4096) as usize;
+        len.min(32 * page_size - 1)
+    } else {
+        len
+    }
+}
+
+// Writes `cmd` to a temporary script, for commands too long to be passed
+// as an argument.
+fn write_command_script(cmd: &[u8]) -> std::io::Result<OsString> {
+    static COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let n = COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let mut filename = std::env::temp_dir().into_os_string();
+    filename.push(format!("/kati_cmd.{}.{n}.sh", std::process::id()));
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&filename)?;
+    f.write_all(cmd)?;
+    Ok(filename)
+}
+
 pub fn run_command(
     shell: &[u8],
     shellflag: &[u8],
     cmd: &Bytes,
     redirect_stderr: RedirectStderr,
 ) -> Result<(ExitStatus, Vec<u8>)> {
+    match spawn_command(shell, shellflag, cmd, redirect_stderr) {
+        Err(err) if err.raw_os_error() == Some(libc::E2BIG) => {
+            log!(
+                "run_command: command too long ({} bytes), using a script",
+                cmd.len()
+            );
+            // Like ninja's rspfiles, have the shell read the command from a
+            // file instead.
+            let script = write_command_script(cmd)?;
+            let result = spawn_command(
+                shell,
+                b"",
+                &Bytes::from(script.as_bytes().to_vec()),
+                redirect_stderr,
+            );
+            let _ = std::fs::remove_file(&script);
+            Ok(result?)
+        }
+        result => Ok(result?),
+    }
+}
+
+fn spawn_command(
+    shell: &[u8],
+    shellflag: &[u8],
+    cmd: &Bytes,
+    redirect_stderr: RedirectStderr,
+) -> std::io::Result<(ExitStatus, Vec<u8>)> {
     let mut cmd_with_shell;
     let args = if !shell.starts_with(b"/") || memchr2(b' ', b'$', shell).is_some() {
         let cmd_escaped = crate::strutil::escape_shell(cmd);
@@ -64,14 +227,19 @@ pub fn run_command(
         cmd_with_shell.put_slice(b" \"");
         cmd_with_shell.put_slice(&cmd_escaped);
         cmd_with_shell.put_u8(b'\"');
-        &[
+        vec![
             <OsStr as OsStrExt>::from_bytes(b"/bin/sh"),
             <OsStr as OsStrExt>::from_bytes(b"-c"),
             <OsStr as OsStrExt>::from_bytes(&cmd_with_shell),
         ]
+    } else if shellflag.is_empty() {
+        vec![
+            <OsStr as OsStrExt>::from_bytes(shell),
+            <OsStr as OsStrExt>::from_bytes(cmd),
+        ]
     } else {
         // If the shell isn't complicated, we don't need to wrap in /bin/sh
-        &[
+        vec![
             <OsStr as OsStrExt>::from_bytes(shell),
             <OsStr as OsStrExt>::from_bytes(shellflag),
             <OsStr as OsStrExt>::from_bytes(cmd),
@@ -114,6 +282,10 @@ pub type GlobResults = Arc<Result<Vec<Bytes>, std::io::Error>>;
 pub static GLOB_CACHE: LazyLock<Mutex<HashMap<Bytes, GlobResults>>> =
     LazyLock::new(|| Mutex::new(HashMap::new()));
 
+// With --incremental_eval, the globs added to GLOB_CACHE since the last
+// `take_globs`.
+static ADDED_GLOBS: Mutex<Option<Vec<(Bytes, GlobResults)>>> = Mutex::new(None);
+
 pub fn glob(pat: Bytes) -> GlobResults {
     let mut cache = GLOB_CACHE.lock();
     if let Some(entry) = cache.get(&pat) {
@@ -129,10 +301,51 @@ pub fn glob(pat: Bytes) -> GlobResults {
             Ok(vec![pat.clone()])
         },
     );
+    if let Some(added) = &mut *ADDED_GLOBS.lock() {
+        added.push((pat.clone(), glob.clone()));
+    }
     cache.insert(pat, glob.clone());
     glob
 }
 
+/// Starts or stops recording which globs are expanded, for
+/// --incremental_eval. Globs expanded before count as expanded since the
+/// last `take_globs`.
+pub fn track_globs(enabled: bool) {
+    let cache = GLOB_CACHE.lock();
+    *ADDED_GLOBS.lock() = enabled.then(|| {
+        cache
+            .iter()
+            .map(|(pat, res)| (pat.clone(), res.clone()))
+            .collect()
+    });
+}
+
+/// The globs expanded since the last call, with their results.
+pub fn take_globs() -> Vec<(Bytes, GlobResults)> {
+    ADDED_GLOBS
+        .lock()
+        .as_mut()
+        .map(std::mem::take)
+        .unwrap_or_default()
+}
+
+/// Adds globs an earlier evaluation expanded to the cache, so that they end
+/// up in the stamp even though this evaluation skipped expanding them.
+pub fn restore_globs(globs: &[(Bytes, GlobResults)]) {
+    let mut cache = GLOB_CACHE.lock();
+    for (pat, res) in globs {
+        cache.entry(pat.clone()).or_insert_with(|| res.clone());
+    }
+}
+
+/// Records restored globs as expanded since the last `take_globs`.
+pub fn mark_globs(globs: &[(Bytes, GlobResults)]) {
+    if let Some(added) = &mut *ADDED_GLOBS.lock() {
+        added.extend(globs.iter().cloned());
+    }
+}
+
 // Use libc glob over the `glob` crate, to maintain compatibility.
 // The glob crate ends up normalizing the paths too much:
 //   ./src/*_test.cc -> src/find_test.cc
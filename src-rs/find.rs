@@ -17,9 +17,11 @@ limitations under the License.
 use std::{
     collections::{HashMap, HashSet},
     ffi::{CString, OsStr, OsString},
+    io::{BufReader, Write},
     os::unix::{ffi::OsStrExt, fs::FileTypeExt},
     path::PathBuf,
     sync::{Arc, LazyLock, OnceLock, Weak, atomic::AtomicUsize},
+    time::SystemTime,
 };
 
 use anyhow::Result;
@@ -32,6 +34,10 @@ use crate::{
     collect_stats, error,
     fileutil::fnmatch,
     flags::FLAGS,
+    io::{
+        dump_int, dump_mtime, dump_string, dump_usize, is_own_identity, load_int, load_mtime,
+        load_string, load_usize, stamp_identity,
+    },
     loc::Loc,
     log,
     strutil::{WordWriter, basename, concat_dir, has_word, normalize_path, trim_left_space},
@@ -39,6 +45,10 @@ use crate::{
 };
 
 static NODE_COUNT: AtomicUsize = AtomicUsize::new(0);
+static FIND_CACHE_HITS: AtomicUsize = AtomicUsize::new(0);
+
+// Bump whenever the find cache's format changes.
+const FIND_CACHE_VERSION: i32 = 1;
 
 macro_rules! find_warn_loc {
     ($loc:expr, $fmt:expr $(, $($arg:tt)*)?) => {
@@ -68,6 +78,33 @@ enum FileType {
     Socket,
 }
 
+impl FileType {
+    fn as_int(&self) -> i32 {
+        match self {
+            FileType::BlockDevice => 0,
+            FileType::CharDevice => 1,
+            FileType::Dir => 2,
+            FileType::Fifo => 3,
+            FileType::Symlink => 4,
+            FileType::Regular => 5,
+            FileType::Socket => 6,
+        }
+    }
+
+    fn from_int(i: i32) -> Option<FileType> {
+        match i {
+            0 => Some(FileType::BlockDevice),
+            1 => Some(FileType::CharDevice),
+            2 => Some(FileType::Dir),
+            3 => Some(FileType::Fifo),
+            4 => Some(FileType::Symlink),
+            5 => Some(FileType::Regular),
+            6 => Some(FileType::Socket),
+            _ => None,
+        }
+    }
+}
+
 impl TryFrom<std::fs::FileType> for FileType {
     type Error = anyhow::Error;
 
@@ -558,43 +595,45 @@ impl DirentNode {
     ) -> NodeType {
         collect_stats!("init find emulator Dirent NodeType::Dir initialize");
 
-        let entries = match std::fs::read_dir(&name) {
-            Ok(entries) => entries,
-            Err(err) => {
-                warn!("opendir({:?}) failed: {:?}", name, err);
-                return NodeType::Dir {
-                    parent,
-                    children: Vec::new(),
-                };
-            }
+        let mtime = if FLAGS.find_cache || FLAGS.daemon {
+            std::fs::metadata(&name).and_then(|md| md.modified()).ok()
+        } else {
+            None
         };
-
-        let mut children = Vec::new();
-        for entry in entries {
-            let entry = match entry {
-                Ok(entry) => entry,
-                Err(err) => {
-                    warn!("readdir failed: {:?}", err);
-                    continue;
+        let cached = mtime.and_then(|mtime| FIND_CACHE.lock().lookup(&name, mtime));
+        let entries = match cached {
+            Some(entries) => {
+                FIND_CACHE_HITS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                entries
+            }
+            None => match Self::read_dir_entries(&name) {
+                Some(entries) => entries,
+                None => {
+                    return NodeType::Dir {
+                        parent,
+                        children: Vec::new(),
+                    };
                 }
-            };
-            if entry.file_name() == "."
-                || entry.file_name() == ".."
-                || entry.file_name() == ".repo"
-                || entry.file_name() == ".git"
-            {
-                continue;
+            },
+        };
+        if let Some(mtime) = mtime {
+            let mut cache = FIND_CACHE.lock();
+            // A directory modified in the same clock tick as this run could
+            // change again without its mtime changing, so its listing is not
+            // trusted by later runs.
+            if mtime < cache.racy_cutoff {
+                cache
+                    .explored
+                    .insert(name.clone(), (mtime, entries.clone()));
             }
+        }
 
-            let Some(typ) = entry.file_type().ok() else {
-                warn!("stat failed: {:?}", entry.path());
-                continue;
-            };
-            let path = entry.path();
-            let base = OsStr::from_bytes(basename(path.as_os_str().as_bytes())).to_os_string();
+        let mut children = Vec::new();
+        for (base, typ) in entries {
+            let path = name.join(&base);
             children.push((
                 base.clone(),
-                Arc::new(if typ.is_dir() {
+                Arc::new(if typ == FileType::Dir {
                     DirentNode {
                         base,
                         inner: OnceLock::new(),
@@ -603,7 +642,7 @@ impl DirentNode {
                             parent: Some(Arc::downgrade(self)),
                         })),
                     }
-                } else if typ.is_symlink() {
+                } else if typ == FileType::Symlink {
                     DirentNode {
                         base,
                         inner: OnceLock::new(),
@@ -614,9 +653,7 @@ impl DirentNode {
                     }
                 } else {
                     let inner = OnceLock::new();
-                    let _ = inner.set(NodeType::File {
-                        typ: typ.try_into().unwrap(),
-                    });
+                    let _ = inner.set(NodeType::File { typ });
                     DirentNode {
                         base,
                         inner,
@@ -629,6 +666,45 @@ impl DirentNode {
 
         NodeType::Dir { parent, children }
     }
+
+    /// Lists the entries of the directory `name`, or None if it can't be
+    /// read.
+    fn read_dir_entries(name: &PathBuf) -> Option<Vec<(OsString, FileType)>> {
+        let entries = match std::fs::read_dir(name) {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("opendir({:?}) failed: {:?}", name, err);
+                return None;
+            }
+        };
+
+        let mut ret = Vec::new();
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    warn!("readdir failed: {:?}", err);
+                    continue;
+                }
+            };
+            if entry.file_name() == "."
+                || entry.file_name() == ".."
+                || entry.file_name() == ".repo"
+                || entry.file_name() == ".git"
+            {
+                continue;
+            }
+
+            let Some(typ) = entry.file_type().ok() else {
+                warn!("stat failed: {:?}", entry.path());
+                continue;
+            };
+            let path = entry.path();
+            let base = OsStr::from_bytes(basename(path.as_os_str().as_bytes())).to_os_string();
+            ret.push((base, typ.try_into().unwrap()));
+        }
+        Some(ret)
+    }
 }
 
 struct DirentNodeKey(Arc<DirentNode>);
@@ -1126,22 +1202,26 @@ impl FindCommandParser {
 }
 
 pub struct FindEmulator {
-    root: Arc<DirentNode>,
+    root: Mutex<Arc<DirentNode>>,
 }
 
 impl FindEmulator {
     fn new() -> Self {
         Self {
-            root: DirentNode::new(),
+            root: Mutex::new(DirentNode::new()),
         }
     }
 
+    fn root(&self) -> Arc<DirentNode> {
+        self.root.lock().clone()
+    }
+
     fn can_handle(s: &[u8]) -> bool {
         !s.starts_with(b"/") && !s.starts_with(b".repo") && !s.starts_with(b".git")
     }
 
     fn find_dir(&self, d: &[u8], should_fallback: &mut bool) -> Option<Arc<DirentNode>> {
-        let r = self.root.find_dir(d);
+        let r = self.root().find_dir(d);
         if r.is_none() {
             *should_fallback = std::fs::exists(OsStr::from_bytes(d)).unwrap_or(false);
         }
@@ -1174,7 +1254,7 @@ impl FindEmulator {
             }
         }
 
-        let mut root = self.root.clone();
+        let mut root = self.root();
 
         let mut fc_chdir = Bytes::new();
         if let Some(chdir) = &fc.chdir {
@@ -1363,6 +1443,116 @@ pub fn get_node_count() -> usize {
     NODE_COUNT.load(std::sync::atomic::Ordering::Relaxed)
 }
 
+pub fn get_find_cache_hit_count() -> usize {
+    FIND_CACHE_HITS.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Drops the find emulator's tree so the next find sees the current state of
+/// the file system. Listings of directories whose mtime hasn't changed since
+/// they were read are reused.
+pub fn reset_find_emulator() {
+    {
+        let mut cache = FIND_CACHE.lock();
+        cache.loaded = std::mem::take(&mut cache.explored);
+        cache.racy_cutoff = crate::timeutil::start_time();
+    }
+    *FIND_EMULATOR.root.lock() = DirentNode::new();
+}
+
+/// With --find_cache, the directory listings the find emulator reads are
+/// saved next to the kati stamp along with each directory's mtime. The next
+/// run reuses a listing as long as the directory's mtime is unchanged, so
+/// only the directories that changed are read again. --daemon keeps the
+/// listings in memory between evaluations the same way.
+///
+/// This is kept apart from the stamp's `read_dirs`. Those only name the
+/// directories a find command read, while the cache needs each directory's
+/// entries and their types, and the stamp is loaded and checksummed by every
+/// no-op regen check, which should stay cheap on large trees. The cache is
+/// also used without --regen.
+struct FindCache {
+    loaded: HashMap<PathBuf, CachedDir>,
+    explored: HashMap<PathBuf, CachedDir>,
+    // Listings of directories with an mtime at or after this are not saved.
+    racy_cutoff: SystemTime,
+}
+
+/// A directory's mtime and entries.
+type CachedDir = (SystemTime, Vec<(OsString, FileType)>);
+
+impl FindCache {
+    fn load() -> Self {
+        let mut cache = Self {
+            loaded: HashMap::new(),
+            explored: HashMap::new(),
+            racy_cutoff: crate::timeutil::start_time(),
+        };
+        if FLAGS.find_cache {
+            let filename = crate::ninja::get_find_cache_filename();
+            if let Ok(f) = std::fs::File::open(&filename) {
+                match Self::load_entries(&mut BufReader::new(f)) {
+                    Some(loaded) => cache.loaded = loaded,
+                    None => log!("Ignoring unusable find cache {filename:?}"),
+                }
+            }
+        }
+        cache
+    }
+
+    fn load_entries(f: &mut impl std::io::Read) -> Option<HashMap<PathBuf, CachedDir>> {
+        if !is_own_identity(&load_string(f)?, false) || load_int(f)? != FIND_CACHE_VERSION {
+            return None;
+        }
+        let num_dirs = load_usize(f)?;
+        let mut ret = HashMap::with_capacity(num_dirs);
+        for _ in 0..num_dirs {
+            let name = PathBuf::from(load_string(f)?);
+            let mtime = load_mtime(f)?;
+            let num_entries = load_usize(f)?;
+            let mut entries = Vec::with_capacity(num_entries);
+            for _ in 0..num_entries {
+                let base = load_string(f)?;
+                let typ = FileType::from_int(load_int(f)?)?;
+                entries.push((base, typ));
+            }
+            ret.insert(name, (mtime, entries));
+        }
+        Some(ret)
+    }
+
+    fn lookup(&self, name: &PathBuf, mtime: SystemTime) -> Option<Vec<(OsString, FileType)>> {
+        let (cached_mtime, entries) = self.loaded.get(name)?;
+        (*cached_mtime == mtime).then(|| entries.clone())
+    }
+}
+
+static FIND_CACHE: LazyLock<Mutex<FindCache>> = LazyLock::new(|| Mutex::new(FindCache::load()));
+
+/// Writes the directories explored by this run to the find cache.
+pub fn save_find_cache(filename: &OsStr) -> Result<()> {
+    let mut tmp = filename.to_os_string();
+    tmp.push(".tmp");
+    {
+        let cache = FIND_CACHE.lock();
+        let mut out = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+        dump_string(&mut out, stamp_identity().as_bytes())?;
+        dump_int(&mut out, FIND_CACHE_VERSION)?;
+        dump_usize(&mut out, cache.explored.len())?;
+        for (name, (mtime, entries)) in &cache.explored {
+            dump_string(&mut out, name.as_os_str().as_bytes())?;
+            dump_mtime(&mut out, mtime)?;
+            dump_usize(&mut out, entries.len())?;
+            for (base, typ) in entries {
+                dump_string(&mut out, base.as_bytes())?;
+                dump_int(&mut out, typ.as_int())?;
+            }
+        }
+        out.flush()?;
+    }
+    std::fs::rename(&tmp, filename)?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -41,8 +41,13 @@ pub static FLAGS: LazyLock<Flags> = LazyLock::new(|| {
 pub struct Flags {
     pub detect_android_echo: bool,
     pub detect_depfiles: bool,
+    pub depfile_detectors: Vec<crate::depfile::DepfileDetection>,
     pub dump_kati_stamp: bool,
     pub dump_include_graph: Option<OsString>,
+    pub dump_dep_graph: Option<OsString>,
+    pub dump_dep_graph_format: crate::dep_graph::DepGraphFormat,
+    pub dump_dep_graph_commands: bool,
+    pub gen_compdb: Option<OsString>,
     pub dump_variable_assignment_trace: Option<OsString>,
     pub enable_debug: bool,
     pub enable_kati_warnings: bool,
@@ -50,16 +55,25 @@ pub struct Flags {
     pub gen_all_targets: bool,
     pub generate_ninja: bool,
     pub generate_empty_ninja: bool,
+    pub ninja_regen_rule: bool,
     pub is_dry_run: bool,
     pub is_silent_mode: bool,
     pub is_syntax_check_only: bool,
     pub regen: bool,
+    pub daemon: bool,
+    pub daemon_wait: bool,
+    pub incremental_eval: bool,
     pub regen_debug: bool,
+    pub regen_explain: Option<OsString>,
+    pub regen_content_hash: bool,
     pub regen_ignoring_kati_binary: bool,
     pub use_find_emulator: bool,
+    pub find_cache: bool,
+    pub ast_cache: bool,
     pub color_warnings: bool,
     pub no_builtin_rules: bool,
     pub no_ninja_prelude: bool,
+    pub ninja_dedup_rules: bool,
     pub use_ninja_phony_output: bool,
     pub use_ninja_validations: bool,
     pub emit_sandbox_disabled: bool,
@@ -82,10 +96,14 @@ pub struct Flags {
     pub default_pool: OsString,
     pub ignore_dirty_pattern: Option<crate::strutil::Pattern>,
     pub no_ignore_dirty_pattern: Option<crate::strutil::Pattern>,
+    pub regen_ignore_env_pattern: Option<crate::strutil::Pattern>,
+    pub regen_track_env_pattern: Option<crate::strutil::Pattern>,
     pub ignore_optional_include_pattern: Option<crate::strutil::Pattern>,
     pub makefile: Mutex<Option<OsString>>,
     pub ninja_dir: Option<OsString>,
     pub ninja_suffix: OsString,
+    pub ninja_shards: usize,
+    pub rspfile_threshold: Option<usize>,
     pub working_dir: Option<OsString>, // -C <dir>
     pub num_cpus: usize,
     pub num_jobs: usize,
@@ -151,22 +169,29 @@ impl Flags {
                 b"--warn" => flags.enable_kati_warnings = true,
                 b"--ninja" => flags.generate_ninja = true,
                 b"--empty_ninja_file" => flags.generate_empty_ninja = true,
+                b"--ninja_regen_rule" => flags.ninja_regen_rule = true,
                 b"--gen_all_targets" => flags.gen_all_targets = true,
                 b"--regen" => {
                     // TODO: Make this default.
                     flags.regen = true
                 }
                 b"--regen_debug" => flags.regen_debug = true,
+                b"--daemon" => flags.daemon = true,
+                b"--daemon_wait" => flags.daemon_wait = true,
+                b"--incremental_eval" => flags.incremental_eval = true,
                 b"--regen_ignoring_kati_binary" => flags.regen_ignoring_kati_binary = true,
+                b"--regen_content_hash" => flags.regen_content_hash = true,
                 b"--dump_kati_stamp" => {
                     flags.dump_kati_stamp = true;
                     flags.regen_debug = true;
                 }
+                b"--dump_dep_graph_commands" => flags.dump_dep_graph_commands = true,
                 b"--detect_android_echo" => flags.detect_android_echo = true,
                 b"--detect_depfiles" => flags.detect_depfiles = true,
                 b"--color_warnings" => flags.color_warnings = true,
                 b"--no_builtin_rules" => flags.no_builtin_rules = true,
                 b"--no_ninja_prelude" => flags.no_ninja_prelude = true,
+                b"--ninja_dedup_rules" => flags.ninja_dedup_rules = true,
                 b"--use_ninja_phony_output" => flags.use_ninja_phony_output = true,
                 b"--use_ninja_validations" => flags.use_ninja_validations = true,
                 b"--emit_sandbox_disabled" => flags.emit_sandbox_disabled = true,
@@ -199,6 +224,8 @@ impl Flags {
                     flags.werror_real_no_cmds = true;
                 }
                 b"--use_find_emulator" => flags.use_find_emulator = true,
+                b"--find_cache" => flags.find_cache = true,
+                b"--ast_cache" => flags.ast_cache = true,
                 _ => {
                     if let Some(arg) = parse_command_line_option_with_arg("-C", &arg, &mut iter) {
                         flags.working_dir = Some(arg);
@@ -206,6 +233,23 @@ impl Flags {
                         parse_command_line_option_with_arg("--dump_include_graph", &arg, &mut iter)
                     {
                         flags.dump_include_graph = Some(arg);
+                    } else if let Some(arg) =
+                        parse_command_line_option_with_arg("--dump_dep_graph", &arg, &mut iter)
+                    {
+                        flags.dump_dep_graph = Some(arg);
+                    } else if let Some(arg) = parse_command_line_option_with_arg(
+                        "--dump_dep_graph_format",
+                        &arg,
+                        &mut iter,
+                    ) {
+                        let Some(format) = crate::dep_graph::DepGraphFormat::parse(arg.as_bytes())
+                        else {
+                            panic!(
+                                "Invalid --dump_dep_graph_format flag: {}",
+                                arg.to_string_lossy()
+                            );
+                        };
+                        flags.dump_dep_graph_format = format;
                     } else if let Some(arg) = parse_command_line_option_with_arg(
                         "--dump_variable_assignment_trace",
                         &arg,
@@ -240,6 +284,44 @@ impl Flags {
                         parse_command_line_option_with_arg("--ninja_suffix", &arg, &mut iter)
                     {
                         flags.ninja_suffix = arg;
+                    } else if let Some(arg) =
+                        parse_command_line_option_with_arg("--regen_explain", &arg, &mut iter)
+                    {
+                        flags.regen_explain = Some(arg);
+                    } else if let Some(arg) =
+                        parse_command_line_option_with_arg("--gen_compdb", &arg, &mut iter)
+                    {
+                        flags.gen_compdb = Some(arg);
+                    } else if let Some(arg) =
+                        parse_command_line_option_with_arg("--depfile_detectors", &arg, &mut iter)
+                    {
+                        let Some(detectors) =
+                            crate::depfile::DepfileDetection::parse_list(arg.as_bytes())
+                        else {
+                            panic!(
+                                "Invalid --depfile_detectors flag: {}",
+                                arg.to_string_lossy()
+                            );
+                        };
+                        flags.detect_depfiles = true;
+                        flags.depfile_detectors = detectors;
+                    } else if let Some(arg) =
+                        parse_command_line_option_with_arg("--ninja_shards", &arg, &mut iter)
+                    {
+                        let Some(shards) = arg.to_string_lossy().parse::<usize>().ok() else {
+                            panic!("Invalid --ninja_shards flag: {}", arg.to_string_lossy());
+                        };
+                        flags.ninja_shards = shards;
+                    } else if let Some(arg) =
+                        parse_command_line_option_with_arg("--rspfile_threshold", &arg, &mut iter)
+                    {
+                        let Some(threshold) = arg.to_string_lossy().parse::<usize>().ok() else {
+                            panic!(
+                                "Invalid --rspfile_threshold flag: {}",
+                                arg.to_string_lossy()
+                            );
+                        };
+                        flags.rspfile_threshold = Some(threshold);
                     } else if let Some(arg) =
                         parse_command_line_option_with_arg("--ninja_dir", &arg, &mut iter)
                     {
@@ -261,6 +343,16 @@ impl Flags {
                     {
                         flags.no_ignore_dirty_pattern =
                             Some(Pattern::new(Bytes::from(arg.as_bytes().to_vec())));
+                    } else if let Some(arg) =
+                        parse_command_line_option_with_arg("--regen_ignore_env", &arg, &mut iter)
+                    {
+                        flags.regen_ignore_env_pattern =
+                            Some(Pattern::new(Bytes::from(arg.as_bytes().to_vec())));
+                    } else if let Some(arg) =
+                        parse_command_line_option_with_arg("--regen_track_env", &arg, &mut iter)
+                    {
+                        flags.regen_track_env_pattern =
+                            Some(Pattern::new(Bytes::from(arg.as_bytes().to_vec())));
                     } else if let Some(arg) =
                         parse_command_line_option_with_arg("--writable", &arg, &mut iter)
                     {
@@ -301,6 +393,37 @@ impl Flags {
             );
         }
 
+        if flags.detect_depfiles && flags.depfile_detectors.is_empty() {
+            flags.depfile_detectors = crate::depfile::DepfileDetection::defaults();
+        }
+
+        if flags.gen_compdb.is_some() && !flags.generate_ninja {
+            panic!("--gen_compdb is valid only together with --ninja");
+        }
+
+        if flags.daemon {
+            if !cfg!(target_os = "linux") {
+                panic!("--daemon is only supported on Linux");
+            }
+            if !flags.generate_ninja {
+                panic!("--daemon is valid only together with --ninja");
+            }
+            // The daemon regenerates only when the stamp says so.
+            flags.regen = true;
+        }
+        if flags.incremental_eval {
+            if !flags.daemon && !flags.daemon_wait {
+                panic!("--incremental_eval is valid only together with --daemon");
+            }
+            if flags.dump_include_graph.is_some() || flags.dump_variable_assignment_trace.is_some()
+            {
+                panic!(
+                    "--incremental_eval can't be used with --dump_include_graph or \
+                     --dump_variable_assignment_trace"
+                );
+            }
+        }
+
         flags
     }
 }
@@ -27,13 +27,14 @@ use std::{
 use anyhow::Result;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use parking_lot::Mutex;
+use regex::bytes::Regex;
 
 use crate::{
     collect_stats, collect_stats_with_slow_report, error_loc,
-    eval::{Evaluator, ExportAllowed, FrameType},
+    eval::{CallSite, Evaluator, ExportAllowed, FrameType},
     expr::{Evaluable, Value},
-    file_cache::add_extra_file_dep,
-    fileutil::{RedirectStderr, run_command},
+    file_cache::{self, JsonFile, add_extra_file_dep},
+    fileutil::{RedirectStderr, hash_file, hex_string, run_command},
     find::FindCommand,
     flags::FLAGS,
     kati_warn_loc,
@@ -762,16 +763,19 @@ fn call_func(args: &[Arc<Value>], ev: &mut Evaluator, out: &mut dyn BufMut) -> R
 
     ev.eval_depth -= 1;
 
+    let loc = ev.loc.clone().unwrap_or_default();
+    ev.call_stack.push(CallSite {
+        name: func_name_buf.clone(),
+        loc: loc.clone(),
+        is_include: false,
+    });
     {
-        let _frame = ev.enter(
-            FrameType::Call,
-            func_name_buf,
-            ev.loc.clone().unwrap_or_default(),
-        );
+        let _frame = ev.enter(FrameType::Call, func_name_buf, loc);
         if let Some(func) = func {
             func.read().eval(ev, out)?;
         }
     }
+    ev.call_stack.pop();
 
     ev.eval_depth += 1;
 
@@ -1032,6 +1036,7 @@ fn deprecated_var_func(
             }
         };
 
+        sym.mark_global_var_written();
         let mut v = v.write();
         if v.deprecated.is_some() {
             error_loc!(
@@ -1078,6 +1083,7 @@ fn obsolete_var_func(args: &[Arc<Value>], ev: &mut Evaluator, _out: &mut dyn Buf
             }
         };
 
+        sym.mark_global_var_written();
         let mut v = v.write();
         if v.deprecated.is_some() {
             error_loc!(
@@ -1193,12 +1199,259 @@ fn extra_file_deps_func(
                     fname.to_string_lossy()
                 );
             }
-            add_extra_file_dep(fname.to_os_string());
+            add_extra_file_dep(fname.to_os_string(), None);
+        }
+    }
+    Ok(())
+}
+
+fn file_hash_func(args: &[Arc<Value>], ev: &mut Evaluator, out: &mut dyn BufMut) -> Result<()> {
+    let files = args[0].eval_to_buf(ev)?;
+    let mut ww = WordWriter::new(out);
+    for file in word_scanner(&files) {
+        let fname = <OsStr as OsStrExt>::from_bytes(file);
+        let digest = match hash_file(fname) {
+            Ok(digest) => digest,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                error_loc!(
+                    ev.loc.as_ref(),
+                    "*** file does not exist: {}",
+                    fname.to_string_lossy()
+                );
+            }
+            Err(err) => {
+                error_loc!(
+                    ev.loc.as_ref(),
+                    "*** failed to read {}: {err}",
+                    fname.to_string_lossy()
+                );
+            }
+        };
+        // The result is only valid as long as the file is unchanged, so
+        // treat it like $(KATI_extra_file_deps).
+        add_extra_file_dep(fname.to_os_string(), Some(digest));
+        ww.write(hex_string(&digest).as_bytes());
+    }
+    Ok(())
+}
+
+fn load_json(
+    args: &[Arc<Value>],
+    ev: &mut Evaluator,
+    func_name: &str,
+) -> Result<(Arc<JsonFile>, Bytes)> {
+    if ev.avoid_io {
+        error_loc!(
+            ev.loc.as_ref(),
+            "*** $({func_name} ...) is not supported in rules."
+        );
+    }
+
+    let arg = args[0].eval_to_buf(ev)?;
+    let filename = trim_space(&arg);
+    if filename.is_empty() {
+        error_loc!(ev.loc.as_ref(), "*** Missing filename");
+    }
+    let path = match args.get(1) {
+        Some(path) => path.eval_to_buf(ev)?,
+        None => Bytes::new(),
+    };
+
+    let filename = <OsStr as OsStrExt>::from_bytes(filename);
+    let json = match file_cache::get_json(filename) {
+        Ok(Some((json, loaded))) => {
+            // Track the file the same way $(file <) does, but only once even
+            // if the file is queried many times.
+            if loaded && should_store_command_result(filename.as_bytes()) {
+                COMMAND_RESULTS.lock().push(CommandResult {
+                    op: CommandOp::Read,
+                    shell: Bytes::new(),
+                    shellflag: Bytes::new(),
+                    cmd: Bytes::from(filename.as_bytes().to_vec()),
+                    find: None,
+                    result: json.contents.clone(),
+                    loc: ev.loc.clone().unwrap_or_default(),
+                })
+            }
+            json
         }
+        Ok(None) => {
+            error_loc!(
+                ev.loc.as_ref(),
+                "*** file does not exist: {}",
+                filename.to_string_lossy()
+            );
+        }
+        Err(err) => error_loc!(ev.loc.as_ref(), "*** {err:#}"),
+    };
+    Ok((json, path))
+}
+
+// Paths are a list of object keys and array indices separated by '.', e.g.
+// "modules.0.srcs". An empty path refers to the whole document.
+fn lookup_json_path<'a>(
+    value: &'a serde_json::Value,
+    path: &[u8],
+) -> Option<&'a serde_json::Value> {
+    let path = trim_space(path);
+    if path.is_empty() {
+        return Some(value);
+    }
+    let mut value = value;
+    for key in path.split(|c| *c == b'.') {
+        let key = std::str::from_utf8(key).ok()?;
+        value = match value {
+            serde_json::Value::Object(map) => map.get(key)?,
+            serde_json::Value::Array(list) => list.get(key.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+fn write_json_scalar(value: &serde_json::Value, ww: &mut WordWriter) {
+    match value {
+        serde_json::Value::Null => {}
+        serde_json::Value::String(s) => ww.write(s.as_bytes()),
+        v => ww.write(v.to_string().as_bytes()),
+    }
+}
+
+fn json_get_func(args: &[Arc<Value>], ev: &mut Evaluator, out: &mut dyn BufMut) -> Result<()> {
+    let (json, path) = load_json(args, ev, "KATI_json_get")?;
+    let Some(value) = lookup_json_path(&json.value, &path) else {
+        return Ok(());
+    };
+    let mut ww = WordWriter::new(out);
+    // Arrays become word lists. Anything else is written as is, with strings
+    // unquoted.
+    match value {
+        serde_json::Value::Array(list) => {
+            for v in list {
+                write_json_scalar(v, &mut ww);
+            }
+        }
+        v => write_json_scalar(v, &mut ww),
     }
     Ok(())
 }
 
+fn json_keys_func(args: &[Arc<Value>], ev: &mut Evaluator, out: &mut dyn BufMut) -> Result<()> {
+    let (json, path) = load_json(args, ev, "KATI_json_keys")?;
+    let Some(serde_json::Value::Object(map)) = lookup_json_path(&json.value, &path) else {
+        return Ok(());
+    };
+    let mut ww = WordWriter::new(out);
+    for key in map.keys() {
+        ww.write(key.as_bytes());
+    }
+    Ok(())
+}
+
+fn json_encode_func(args: &[Arc<Value>], ev: &mut Evaluator, out: &mut dyn BufMut) -> Result<()> {
+    let words = args[0].eval_to_buf(ev)?;
+    let list = word_scanner(&words)
+        .map(|w| serde_json::Value::String(String::from_utf8_lossy(w).into_owned()))
+        .collect();
+    out.put_slice(serde_json::Value::Array(list).to_string().as_bytes());
+    Ok(())
+}
+
+fn optional_message(args: &[Arc<Value>], idx: usize, ev: &mut Evaluator) -> Result<String> {
+    let Some(v) = args.get(idx) else {
+        return Ok(String::new());
+    };
+    let msg = v.eval_to_buf(ev)?;
+    let msg = trim_space(&msg);
+    if msg.is_empty() {
+        return Ok(String::new());
+    }
+    Ok(format!(". {}", String::from_utf8_lossy(msg)))
+}
+
+fn validation_error(ev: &Evaluator, msg: String) -> Result<()> {
+    let mut msg = msg;
+    // Innermost first. A $(call) outside of any makefile has no location.
+    for c in ev.call_stack.iter().rev() {
+        if c.loc == Loc::default() {
+            continue;
+        }
+        let name = String::from_utf8_lossy(&c.name);
+        if c.is_include {
+            msg.push_str(&format!("\n{}: from include {name}", c.loc));
+        } else {
+            msg.push_str(&format!("\n{}: from $(call {name})", c.loc));
+        }
+    }
+    error_loc!(ev.loc.as_ref(), "*** {msg}");
+}
+
+fn assert_func(args: &[Arc<Value>], ev: &mut Evaluator, _out: &mut dyn BufMut) -> Result<()> {
+    let cond = args[0].eval_to_buf(ev)?;
+    if !trim_space(&cond).is_empty() {
+        return Ok(());
+    }
+    let msg = optional_message(args, 1, ev)?;
+    validation_error(ev, format!("Assertion failed{msg}."))
+}
+
+fn check_defined_func(
+    args: &[Arc<Value>],
+    ev: &mut Evaluator,
+    _out: &mut dyn BufMut,
+) -> Result<()> {
+    let vars = args[0].eval_to_buf(ev)?;
+    let mut missing = Vec::new();
+    for var in word_scanner(&vars) {
+        let sym = intern(vars.slice_ref(var));
+        // Like ifdef, a variable with an empty value is not defined.
+        let defined = match ev.lookup_var(sym)? {
+            Some(v) => {
+                let v = v.read();
+                v.used(ev, &sym)?;
+                !v.string()?.is_empty()
+            }
+            None => false,
+        };
+        if !defined {
+            missing.push(sym.to_string());
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let msg = optional_message(args, 1, ev)?;
+    validation_error(
+        ev,
+        format!(
+            "Required variables not defined: {}{msg}.",
+            missing.join(" ")
+        ),
+    )
+}
+
+fn check_one_of_func(args: &[Arc<Value>], ev: &mut Evaluator, _out: &mut dyn BufMut) -> Result<()> {
+    let name = args[0].eval_to_buf(ev)?;
+    let sym = intern(name.slice_ref(trim_space(&name)));
+    let allowed = args[1].eval_to_buf(ev)?;
+    let value = ev.eval_var(sym)?;
+    let value = trim_space(&value);
+    if word_scanner(&allowed).any(|a| a == value) {
+        return Ok(());
+    }
+    let allowed = word_scanner(&allowed)
+        .map(|a| String::from_utf8_lossy(a))
+        .collect::<Vec<_>>()
+        .join(" ");
+    validation_error(
+        ev,
+        format!(
+            "Invalid value for {sym}: '{}'. Expected one of: {allowed}.",
+            String::from_utf8_lossy(value)
+        ),
+    )
+}
+
 fn foreach_sep_func(args: &[Arc<Value>], ev: &mut Evaluator, out: &mut dyn BufMut) -> Result<()> {
     let varname = intern(args[0].eval_to_buf(ev)?);
     let separator = args[1].eval_to_buf(ev)?;
@@ -1278,6 +1531,7 @@ fn visibility_prefix_func(
     };
     if !prefixes.is_empty() {
         v.write().set_visibility_prefix(prefixes, &sym)?;
+        sym.mark_global_var_written();
     }
 
     Ok(())
@@ -1300,6 +1554,84 @@ fn debug_func(args: &[Arc<Value>], ev: &mut Evaluator, _out: &mut dyn BufMut) ->
     Ok(())
 }
 
+// Compiled regular expressions are cached, as the same pattern is typically
+// used from a macro that is called many times.
+static REGEX_CACHE: LazyLock<Mutex<HashMap<Bytes, Arc<Regex>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn compile_regex(ev: &Evaluator, func_name: &str, pat: Bytes) -> Result<Arc<Regex>> {
+    if let Some(re) = REGEX_CACHE.lock().get(&pat) {
+        return Ok(re.clone());
+    }
+    let re = match std::str::from_utf8(&pat).map(Regex::new) {
+        Ok(Ok(re)) => Arc::new(re),
+        Ok(Err(err)) => {
+            // Syntax errors span multiple lines with a caret diagram; the last
+            // line carries the actual reason.
+            let err = err.to_string();
+            error_loc!(
+                ev.loc.as_ref(),
+                "*** invalid regular expression in `{func_name}': '{}': {}.",
+                String::from_utf8_lossy(&pat),
+                err.lines()
+                    .last()
+                    .unwrap_or_default()
+                    .trim_start_matches("error: ")
+            );
+        }
+        Err(_) => {
+            error_loc!(
+                ev.loc.as_ref(),
+                "*** invalid regular expression in `{func_name}': '{}': not valid UTF-8.",
+                String::from_utf8_lossy(&pat)
+            );
+        }
+    };
+    REGEX_CACHE.lock().insert(pat, re.clone());
+    Ok(re)
+}
+
+fn regex_match_func(args: &[Arc<Value>], ev: &mut Evaluator, out: &mut dyn BufMut) -> Result<()> {
+    let pat = args[0].eval_to_buf(ev)?;
+    let text = args[1].eval_to_buf(ev)?;
+    let re = compile_regex(ev, "KATI_regex_match", pat)?;
+    let mut ww = WordWriter::new(out);
+    for tok in word_scanner(&text) {
+        if let Some(caps) = re.captures(tok) {
+            // Prefer the first capture group, so callers can extract a part of
+            // each word without a separate $(patsubst).
+            let m = caps.get(1).or_else(|| caps.get(0)).unwrap();
+            ww.write(m.as_bytes());
+        }
+    }
+    Ok(())
+}
+
+fn regex_replace_func(args: &[Arc<Value>], ev: &mut Evaluator, out: &mut dyn BufMut) -> Result<()> {
+    let pat = args[0].eval_to_buf(ev)?;
+    let repl = args[1].eval_to_buf(ev)?;
+    let text = args[2].eval_to_buf(ev)?;
+    let re = compile_regex(ev, "KATI_regex_replace", pat)?;
+    let mut ww = WordWriter::new(out);
+    for tok in word_scanner(&text) {
+        ww.write(&re.replace_all(tok, repl.as_ref()));
+    }
+    Ok(())
+}
+
+fn regex_filter_func(args: &[Arc<Value>], ev: &mut Evaluator, out: &mut dyn BufMut) -> Result<()> {
+    let pat = args[0].eval_to_buf(ev)?;
+    let text = args[1].eval_to_buf(ev)?;
+    let re = compile_regex(ev, "KATI_regex_filter", pat)?;
+    let mut ww = WordWriter::new(out);
+    for tok in word_scanner(&text) {
+        if re.is_match(tok) {
+            ww.write(tok);
+        }
+    }
+    Ok(())
+}
+
 const fn func(name: &'static [u8], f: MakeFuncImpl, arity: i16) -> FuncInfo {
     FuncInfo {
         name,
@@ -1416,6 +1748,44 @@ const FUNC_INFO: &[FuncInfo] = &[
         trim_right_space_1st: false,
     },
     func(b"KATI_debug_var", debug_func, 1),
+    func(b"KATI_regex_match", regex_match_func, 2),
+    func(b"KATI_regex_replace", regex_replace_func, 3),
+    func(b"KATI_regex_filter", regex_filter_func, 2),
+    func(b"KATI_file_hash", file_hash_func, 1),
+    FuncInfo {
+        name: b"KATI_json_get",
+        func: json_get_func,
+        arity: 2,
+        min_arity: 1,
+        trim_space: false,
+        trim_right_space_1st: false,
+    },
+    FuncInfo {
+        name: b"KATI_json_keys",
+        func: json_keys_func,
+        arity: 2,
+        min_arity: 1,
+        trim_space: false,
+        trim_right_space_1st: false,
+    },
+    func(b"KATI_json_encode", json_encode_func, 1),
+    FuncInfo {
+        name: b"KATI_assert",
+        func: assert_func,
+        arity: 2,
+        min_arity: 1,
+        trim_space: false,
+        trim_right_space_1st: false,
+    },
+    FuncInfo {
+        name: b"KATI_check_defined",
+        func: check_defined_func,
+        arity: 2,
+        min_arity: 1,
+        trim_space: false,
+        trim_right_space_1st: false,
+    },
+    func(b"KATI_check_one_of", check_one_of_func, 2),
 ];
 
 static FUNC_INFO_MAP: LazyLock<HashMap<&'static [u8], &'static FuncInfo>> =
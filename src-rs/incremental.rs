@@ -0,0 +1,258 @@
+/*
+Copyright 2025 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+// With --incremental_eval, the daemon takes a checkpoint of the evaluator
+// before each include statement, along with the makefiles, globs and
+// commands evaluation had consumed by then. Includes nested in other
+// makefiles get checkpoints too, as long as every include leading to them is
+// in a statement list rather than in a conditional or $(eval). When a later
+// run has to regenerate, the regen check reports every input that changed,
+// and evaluation resumes from the last checkpoint taken before any of them
+// was consumed instead of starting over, descending into the includes the
+// checkpoint is nested in.
+//
+// A checkpoint only keeps what the part of the evaluation since the previous
+// checkpoint wrote and read: copies of the variables it assigned or changed
+// in place, and the undefined and environment variables, files and globs it
+// first read. A checkpoint can be resumed from as long as none of the files
+// and globs of any checkpoint up to it changed. Restoring a checkpoint
+// applies those of every checkpoint up to it, in order, on top of the
+// variables the run starts evaluation with, which match those of the earlier
+// run as the arguments and environment are unchanged.
+// Output of $(info) and $(warning) in the part that is reused is not
+// repeated.
+
+use std::{collections::HashSet, sync::Arc};
+
+use anyhow::Result;
+use parking_lot::Mutex;
+use serde_json::Value;
+
+use crate::{
+    eval::{EvalCheckpoint, Evaluator, IncludePos},
+    file_cache::{
+        UsedFiles, mark_used_files, restore_used_files, take_used_files, track_used_files,
+    },
+    fileutil::{GlobResults, mark_globs, restore_globs, take_globs, track_globs},
+    func::{COMMAND_RESULTS, CommandResult},
+    loc::Loc,
+    rule::Rule,
+};
+
+struct Checkpoint {
+    // The includes the checkpoint is nested in, and the index of the include
+    // statement in the innermost makefile.
+    path: Vec<IncludePos>,
+    stmt_index: usize,
+    loc: Loc,
+    eval: EvalCheckpoint,
+    // The files and globs first read since the previous checkpoint.
+    files: UsedFiles,
+    globs: Vec<(bytes::Bytes, GlobResults)>,
+    num_commands: usize,
+}
+
+impl Checkpoint {
+    // Whether nothing this checkpoint recorded changed. Earlier checkpoints
+    // have to be checked too.
+    fn is_valid(&self, changes: &Changes, commands: &[CommandResult]) -> bool {
+        let files = self
+            .files
+            .makefiles
+            .iter()
+            .chain(self.files.extra_file_deps.iter().map(|(f, _)| f));
+        for f in files {
+            if changes.files.contains(&*f.to_string_lossy()) {
+                return false;
+            }
+        }
+        for (pat, _) in &self.globs {
+            if changes.globs.contains(&*String::from_utf8_lossy(pat)) {
+                return false;
+            }
+        }
+        for cr in &commands[..self.num_commands] {
+            if changes
+                .commands
+                .contains(&*String::from_utf8_lossy(&cr.cmd))
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// What the regen check found changed since the last successful run.
+#[derive(Default)]
+struct Changes {
+    // Something that every checkpoint depends on changed, like the
+    // arguments or the environment.
+    all: bool,
+    files: HashSet<String>,
+    globs: HashSet<String>,
+    commands: HashSet<String>,
+}
+
+impl Changes {
+    fn from_reasons(reasons: &[Value]) -> Self {
+        let mut changes = Self::default();
+        for reason in reasons {
+            let field = |name| reason[name].as_str().unwrap_or_default().to_string();
+            match reason["type"].as_str() {
+                Some("file") => {
+                    changes.files.insert(field("file"));
+                }
+                Some("glob") => {
+                    changes.globs.insert(field("pattern"));
+                }
+                Some("shell" | "find") => {
+                    changes.commands.insert(field("command"));
+                }
+                Some("read") => {
+                    changes.commands.insert(field("file"));
+                }
+                // The outputs are written again either way.
+                Some("missing_output") => {}
+                _ => changes.all = true,
+            }
+        }
+        changes
+    }
+}
+
+/// The checkpoints of one run, and what they need to be restored.
+#[derive(Default)]
+struct Run {
+    checkpoints: Vec<Arc<Checkpoint>>,
+    rules: Vec<Rule>,
+    commands: Vec<CommandResult>,
+}
+
+#[derive(Default)]
+struct State {
+    // The last run that generated the ninja file, and so the stamp the regen
+    // check compares against.
+    committed: Option<Run>,
+    current: Run,
+    changes: Option<Changes>,
+}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+fn with_state<T>(f: impl FnOnce(&mut State) -> T) -> T {
+    f(STATE.lock().get_or_insert_with(State::default))
+}
+
+/// Records why this run regenerates.
+pub fn set_changes(reasons: &[Value]) {
+    with_state(|s| s.changes = Some(Changes::from_reasons(reasons)));
+}
+
+/// Restores the latest checkpoint that is still valid, and returns where to
+/// resume evaluation from, or `None` to evaluate from the start.
+pub fn resume(ev: &mut Evaluator) -> Result<Option<(Vec<IncludePos>, usize)>> {
+    ev.track_changes(true);
+    track_used_files(true);
+    track_globs(true);
+    let (checkpoints, rules, commands) = {
+        let mut state = STATE.lock();
+        let state = state.get_or_insert_with(State::default);
+        state.current = Run::default();
+        let (Some(mut last), Some(changes)) = (state.committed.take(), state.changes.take()) else {
+            return Ok(None);
+        };
+        if changes.all {
+            return Ok(None);
+        }
+        let num_valid = last
+            .checkpoints
+            .iter()
+            .position(|cp| !cp.is_valid(&changes, &last.commands))
+            .unwrap_or(last.checkpoints.len());
+        let Some(pos) = num_valid.checked_sub(1) else {
+            return Ok(None);
+        };
+        last.checkpoints.truncate(pos + 1);
+        let num_rules = last.checkpoints[pos].eval.num_rules;
+        let num_commands = last.checkpoints[pos].num_commands;
+        let mut rules = std::mem::take(&mut last.rules);
+        rules.truncate(num_rules);
+        let mut commands = std::mem::take(&mut last.commands);
+        commands.truncate(num_commands);
+        (last.checkpoints, rules, commands)
+    };
+    let (checkpoint, kept) = checkpoints.split_last().unwrap();
+
+    eprintln!(
+        "*kati*: incremental: reusing evaluation up to {}",
+        checkpoint.loc
+    );
+    let evals: Vec<&EvalCheckpoint> = checkpoints.iter().map(|cp| &cp.eval).collect();
+    ev.restore(&evals, rules);
+    for cp in &checkpoints {
+        restore_used_files(&cp.files)?;
+        restore_globs(&cp.globs);
+    }
+    // What this run read before resuming, like the top-level makefile, the
+    // first checkpoint already recorded, and restoring is not reading.
+    // Evaluation resumes right before the last checkpoint is taken again, so
+    // what that one recorded counts as read since the previous one.
+    take_used_files();
+    take_globs();
+    mark_used_files(&checkpoint.files);
+    mark_globs(&checkpoint.globs);
+    *COMMAND_RESULTS.lock() = commands;
+    let kept = kept.to_vec();
+    with_state(|s| s.current.checkpoints = kept);
+    Ok(Some((checkpoint.path.clone(), checkpoint.stmt_index)))
+}
+
+/// Takes a checkpoint before the include statement at `stmt_index` in the
+/// makefile `path` leads to.
+pub fn checkpoint(ev: &mut Evaluator, path: Vec<IncludePos>, stmt_index: usize, loc: Loc) {
+    let checkpoint = Checkpoint {
+        path,
+        stmt_index,
+        loc,
+        eval: ev.checkpoint(),
+        files: take_used_files(),
+        globs: take_globs(),
+        num_commands: COMMAND_RESULTS.lock().len(),
+    };
+    with_state(|s| s.current.checkpoints.push(Arc::new(checkpoint)));
+}
+
+/// Keeps the rules of the top-level makefile, which dependency analysis
+/// consumes.
+pub fn finish_eval(ev: &mut Evaluator) {
+    ev.track_changes(false);
+    track_used_files(false);
+    track_globs(false);
+    let rules = ev.rules.clone();
+    with_state(|s| s.current.rules = rules);
+}
+
+/// Makes this run's checkpoints available to the next run, once the ninja
+/// file and the stamp have been written.
+pub fn commit() {
+    let commands = std::mem::take(&mut *COMMAND_RESULTS.lock());
+    with_state(|s| {
+        let mut run = std::mem::take(&mut s.current);
+        run.commands = commands;
+        s.committed = Some(run);
+    });
+}
@@ -15,13 +15,18 @@ limitations under the License.
 */
 
 use std::{
-    ffi::OsString,
-    io::Write,
-    os::unix::ffi::OsStringExt,
-    time::{Duration, UNIX_EPOCH},
+    ffi::{OsStr, OsString},
+    io::{Read, Write},
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::MetadataExt,
+    },
+    sync::LazyLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Result;
+use sha2::{Digest, Sha256};
 
 pub fn dump_int(out: &mut impl Write, val: i32) -> Result<()> {
     out.write_all(&val.to_le_bytes())?;
@@ -85,6 +90,150 @@ pub fn load_systemtime(f: &mut impl std::io::Read) -> Option<std::time::SystemTi
     Some(UNIX_EPOCH + Duration::from_secs_f64(f64::from_le_bytes(buf)))
 }
 
+/// Unlike `dump_systemtime`, keeps the full precision so that mtimes can be
+/// compared for equality after a round trip.
+pub fn dump_mtime(out: &mut impl Write, t: &SystemTime) -> Result<()> {
+    let nanos = t.duration_since(UNIX_EPOCH)?.as_nanos();
+    dump_string(out, &nanos.to_le_bytes())
+}
+
+pub fn load_mtime(f: &mut impl std::io::Read) -> Option<SystemTime> {
+    let nanos = load_string(f)?;
+    let nanos = u128::from_le_bytes(nanos.as_bytes().try_into().ok()?);
+    UNIX_EPOCH.checked_add(Duration::from_nanos(nanos.try_into().ok()?))
+}
+
+// The kati stamp starts with a header:
+//
+//   magic, format version, generation time, writer identity
+//
+// followed by the records and a SHA-256 of the records. The generation time
+// is refreshed in place on every clean regen check, so it sits at a fixed
+// offset and is not covered by the checksum.
+const STAMP_MAGIC: &[u8; 8] = b"KATISTMP";
+// Bump whenever the records change.
+const STAMP_VERSION: i32 = 2;
+pub const STAMP_GEN_TIME_OFFSET: u64 = STAMP_MAGIC.len() as u64 + 4;
+
+const CHECKSUM_LEN: usize = 32;
+
+/// Which kati wrote a stamp or cache: its name, version and build. Cargo
+/// builds embed a hash of their sources, and other builds fall back to the
+/// size and modification time of the binary. The build is only shown, see
+/// `is_own_identity`.
+pub fn stamp_identity() -> &'static str {
+    static IDENTITY: LazyLock<String> = LazyLock::new(|| {
+        let build = option_env!("KATI_BUILD_ID").map_or_else(
+            || {
+                std::env::current_exe()
+                    .and_then(std::fs::metadata)
+                    .map_or_else(
+                        |_| "unknown".to_string(),
+                        |m| format!("{}-{}.{}", m.len(), m.mtime(), m.mtime_nsec()),
+                    )
+            },
+            str::to_string,
+        );
+        format!("rkati {} {build}", env!("CARGO_PKG_VERSION"))
+    });
+    &IDENTITY
+}
+
+/// Whether `identity`, written by `stamp_identity`, is of this kati. The
+/// build is not compared: the kati binary is one of the files a stamp
+/// depends on, and the formats have versions of their own. With
+/// `any_version`, the version is not compared either.
+pub fn is_own_identity(identity: &OsStr, any_version: bool) -> bool {
+    let mut words = identity.as_bytes().split(|c| *c == b' ');
+    words.next() == Some(b"rkati")
+        && (any_version || words.next() == Some(env!("CARGO_PKG_VERSION").as_bytes()))
+}
+
+pub fn dump_stamp_header(out: &mut impl Write, gen_time: &SystemTime) -> Result<()> {
+    out.write_all(STAMP_MAGIC)?;
+    dump_int(out, STAMP_VERSION)?;
+    dump_systemtime(out, gen_time)?;
+    dump_string(out, stamp_identity().as_bytes())?;
+    Ok(())
+}
+
+/// Returns the generation time and the identity of the writer, or why the
+/// stamp can't be used. See `is_own_identity` for `any_version`.
+pub fn load_stamp_header(
+    f: &mut impl Read,
+    any_version: bool,
+) -> Result<(SystemTime, OsString), String> {
+    let mut magic = [0u8; STAMP_MAGIC.len()];
+    if f.read_exact(&mut magic).is_err() || &magic != STAMP_MAGIC {
+        return Err("not an rkati stamp (bad magic)".to_string());
+    }
+    let version = load_int(f).ok_or("truncated header")?;
+    if version != STAMP_VERSION {
+        return Err(format!(
+            "stamp format version {version}, expected {STAMP_VERSION}"
+        ));
+    }
+    let gen_time = load_systemtime(f).ok_or("truncated header")?;
+    let identity = load_string(f).ok_or("truncated header")?;
+    if !is_own_identity(&identity, any_version) {
+        return Err(format!(
+            "written by {}, expected {}",
+            identity.to_string_lossy(),
+            stamp_identity()
+        ));
+    }
+    Ok((gen_time, identity))
+}
+
+/// Hashes the stamp records as they are written.
+pub struct StampWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> StampWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Appends the checksum of everything written so far.
+    pub fn finish(mut self) -> Result<W> {
+        let checksum = self.hasher.finalize();
+        self.inner.write_all(&checksum)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for StampWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads the stamp records that follow the header, and checks them against
+/// the trailing checksum before any of them is parsed.
+pub fn load_stamp_records(f: &mut impl Read) -> Result<Vec<u8>, String> {
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf).map_err(|e| e.to_string())?;
+    let Some(len) = buf.len().checked_sub(CHECKSUM_LEN) else {
+        return Err("missing checksum".to_string());
+    };
+    if Sha256::digest(&buf[..len]).as_slice() != &buf[len..] {
+        return Err("checksum mismatch".to_string());
+    }
+    buf.truncate(len);
+    Ok(buf)
+}
+
 #[cfg(test)]
 mod tests {
     use std::os::unix::ffi::OsStrExt;
@@ -116,4 +265,69 @@ mod tests {
         let mut buf = &buf[..];
         assert_eq!(load_string(&mut buf), Some(s));
     }
+
+    #[test]
+    fn test_stamp() {
+        let gen_time = UNIX_EPOCH + Duration::from_secs(1234);
+        let mut buf = Vec::new();
+        dump_stamp_header(&mut buf, &gen_time).unwrap();
+        let mut out = StampWriter::new(&mut buf);
+        dump_string(&mut out, b"Makefile").unwrap();
+        out.finish().unwrap();
+
+        let mut f = &buf[..];
+        assert_eq!(
+            load_stamp_header(&mut f, false),
+            Ok((gen_time, OsString::from(stamp_identity())))
+        );
+        let records = load_stamp_records(&mut f).unwrap();
+        assert_eq!(
+            load_string(&mut &records[..]),
+            Some(OsString::from("Makefile"))
+        );
+
+        // A corrupt length is caught before it is used.
+        let mut corrupt = buf.clone();
+        let len = corrupt.len();
+        corrupt[len - CHECKSUM_LEN - b"Makefile".len() - 1] ^= 0x80;
+        let mut f = &corrupt[..];
+        load_stamp_header(&mut f, false).unwrap();
+        assert_eq!(
+            load_stamp_records(&mut f),
+            Err("checksum mismatch".to_string())
+        );
+
+        let mut truncated = &buf[buf.len() - CHECKSUM_LEN + 1..];
+        assert_eq!(
+            load_stamp_records(&mut truncated),
+            Err("missing checksum".to_string())
+        );
+
+        let mut old = Vec::new();
+        dump_systemtime(&mut old, &gen_time).unwrap();
+        assert_eq!(
+            load_stamp_header(&mut &old[..], false),
+            Err("not an rkati stamp (bad magic)".to_string())
+        );
+
+        let mut other = buf.clone();
+        other[STAMP_MAGIC.len()] = 0;
+        assert_eq!(
+            load_stamp_header(&mut &other[..], false),
+            Err(format!("stamp format version 0, expected {STAMP_VERSION}"))
+        );
+    }
+
+    #[test]
+    fn test_is_own_identity() {
+        let version = env!("CARGO_PKG_VERSION");
+        assert!(is_own_identity(OsStr::new(stamp_identity()), false));
+        // Another build of the same version.
+        let rebuilt = format!("rkati {version} 1234-5678.9");
+        assert!(is_own_identity(OsStr::new(&rebuilt), false));
+        assert!(!is_own_identity(OsStr::new("rkati 0.0.0-old abc"), false));
+        assert!(is_own_identity(OsStr::new("rkati 0.0.0-old abc"), true));
+        assert!(!is_own_identity(OsStr::new("ckati 1.0"), true));
+        assert!(!is_own_identity(OsStr::new(""), true));
+    }
 }
@@ -24,8 +24,12 @@ limitations under the License.
 
 use strutil::trim_prefix_str;
 
+pub mod ast_cache;
 pub mod command;
+pub mod daemon;
 pub mod dep;
+pub mod dep_graph;
+pub mod depfile;
 pub mod eval;
 pub mod exec;
 pub mod expr;
@@ -35,6 +39,7 @@ pub mod fileutil;
 pub mod find;
 pub mod flags;
 pub mod func;
+pub mod incremental;
 pub mod io;
 pub mod loc;
 pub mod ninja;
@@ -130,7 +135,15 @@ fn color_error_log(loc: Option<&crate::loc::Loc>, msg: String) -> anyhow::Error
     }
 }
 
+static WARNING_COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// How many located warnings have been printed so far.
+pub fn warning_count() -> usize {
+    WARNING_COUNT.load(std::sync::atomic::Ordering::Relaxed)
+}
+
 fn color_warn_log(loc: Option<&crate::loc::Loc>, msg: String) {
+    WARNING_COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
     let Some(loc) = loc else {
         eprintln!("{msg}");
         return;
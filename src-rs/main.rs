@@ -22,6 +22,7 @@ limitations under the License.
 #![deny(unsafe_op_in_unsafe_fn)]
 #![deny(clippy::undocumented_unsafe_blocks)]
 
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::io::{Write, stdout};
 use std::os::unix::ffi::OsStrExt;
@@ -38,7 +39,7 @@ use kati::dep::{NamedDepNode, make_dep};
 use kati::fileutil::clear_glob_cache;
 use kati::log;
 use kati::ninja::generate_ninja;
-use kati::regen::needs_regen;
+use kati::regen::{needs_regen, needs_regen_with_reasons};
 use kati::regen_dump::stamp_dump_main;
 
 use kati::eval::FrameType;
@@ -112,11 +113,22 @@ fn read_bootstrap_makefile(targets: &[Symbol]) -> Result<Arc<Mutex<Vec<Stmt>>>>
 }
 
 fn run(targets: &[Symbol], cl_vars: &Vec<Bytes>, orig_args: OsString) -> Result<i32> {
-    let start_time = std::time::SystemTime::now();
+    let start_time = if FLAGS.daemon {
+        kati::timeutil::start_time()
+    } else {
+        std::time::SystemTime::now()
+    };
 
     if FLAGS.generate_ninja && (FLAGS.regen || FLAGS.dump_kati_stamp) {
         let _tr = ScopedTimeReporter::new("regen_check_time");
-        if !needs_regen(start_time, &orig_args) {
+        let needs_regen = if FLAGS.incremental_eval {
+            let (needs_regen, reasons) = needs_regen_with_reasons(start_time, &orig_args);
+            kati::incremental::set_changes(&reasons);
+            needs_regen
+        } else {
+            needs_regen(start_time, &orig_args)
+        };
+        if !needs_regen {
             eprintln!("No need to regenerate ninja file");
             return Ok(0);
         }
@@ -212,10 +224,17 @@ fn run(targets: &[Symbol], cl_vars: &Vec<Bytes>, orig_args: OsString) -> Result<
         let Some(mk) = kati::file_cache::get_makefile(&makefile)? else {
             bail!("makefile not found")
         };
-        let stmts = mk.stmts.lock();
-        for stmt in stmts.iter() {
-            log!("{stmt:?}");
-            stmt.eval(&mut ev)?;
+        let resume = if FLAGS.incremental_eval {
+            kati::incremental::resume(&mut ev)?
+        } else {
+            None
+        };
+        ev.eval_stmts(
+            &mk.stmts.lock(),
+            resume.as_ref().map(|(path, i)| (&path[..], *i)),
+        )?;
+        if FLAGS.incremental_eval {
+            kati::incremental::finish_eval(&mut ev);
         }
     }
 
@@ -234,6 +253,11 @@ fn run(targets: &[Symbol], cl_vars: &Vec<Bytes>, orig_args: OsString) -> Result<
         nodes = make_dep(&mut ev, targets.to_owned())?;
     }
 
+    let mut evaluated_commands = HashMap::new();
+    if let Some(filename) = &FLAGS.dump_dep_graph {
+        evaluated_commands = kati::dep_graph::dump_dep_graph(&nodes, &mut ev, filename)?;
+    }
+
     if FLAGS.is_syntax_check_only {
         return Ok(0);
     }
@@ -245,8 +269,17 @@ fn run(targets: &[Symbol], cl_vars: &Vec<Bytes>, orig_args: OsString) -> Result<
             Loc::default(),
         );
         let _tr = ScopedTimeReporter::new("generate ninja time");
-        generate_ninja(&nodes, &mut ev, orig_args.as_bytes(), start_time)?;
+        generate_ninja(
+            &nodes,
+            &mut ev,
+            orig_args.as_bytes(),
+            start_time,
+            evaluated_commands,
+        )?;
         ev.finish()?;
+        if FLAGS.incremental_eval {
+            kati::incremental::commit();
+        }
         return Ok(0);
     }
 
@@ -372,12 +405,15 @@ fn main() {
     let orig_args = std::env::args_os()
         .collect::<Vec<OsString>>()
         .join(OsStr::new(" "));
+    if FLAGS.daemon_wait {
+        std::process::exit(kati::daemon::wait_for_daemon());
+    }
     find_first_makefile();
     if FLAGS.makefile.lock().is_none() {
         eprintln!("*** No targets specified and no makefile found.");
         std::process::exit(1);
     }
-    let ret = match run(&FLAGS.targets, &FLAGS.cl_vars, orig_args) {
+    let run_and_report = || match run(&FLAGS.targets, &FLAGS.cl_vars, orig_args.clone()) {
         Ok(ret) => ret,
         Err(err) => {
             for cause in err.chain() {
@@ -386,6 +422,18 @@ fn main() {
             1
         }
     };
+    let daemon = FLAGS.daemon.then(kati::daemon::Invocation::current);
+    let mut ret = run_and_report();
+    if let Some(daemon) = daemon {
+        let regenerate = || {
+            kati::daemon::reset_for_next_run();
+            run_and_report() == 0
+        };
+        if let Err(err) = kati::daemon::serve(daemon, regenerate) {
+            eprintln!("*** {err:#}");
+            ret = 1;
+        }
+    }
     #[cfg(feature = "gperf")]
     {
         if FLAGS.cpu_profile_path.is_some() {
@@ -15,10 +15,8 @@ limitations under the License.
 */
 
 use std::ffi::{OsStr, OsString};
-use std::fs::OpenOptions;
 use std::io::Write;
-use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::time::SystemTime;
 use std::{
     collections::{HashMap, HashSet},
@@ -27,123 +25,49 @@ use std::{
 
 use anyhow::Result;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use memchr::{memchr2, memmem};
 use parking_lot::Mutex;
 
+use crate::file_cache;
 use crate::func::CommandOp;
-use crate::io::{dump_int, dump_string, dump_systemtime, dump_usize, dump_vec_string};
-use crate::strutil::{basename, concat_dir, dirname, strip_ext, strip_ext_vec};
+use crate::io::{
+    StampWriter, dump_int, dump_stamp_header, dump_string, dump_usize, dump_vec_string,
+};
+use crate::strutil::{concat_dir, dirname};
 use crate::{
     command::{Command, CommandEvaluator},
     dep::{DepNode, NamedDepNode, is_special_target},
+    depfile::{
+        DEPFILE_DETECTORS, DepsType, detect_depfile, find_command_line_flag,
+        find_command_line_flag_with_arg,
+    },
     eval::Evaluator,
     expr::Evaluable,
+    fileutil::{ChangedFileWriter, environ_size, max_arg_len},
     flags::FLAGS,
-    strutil::{escape_shell, trim_left_space},
+    kati_warn,
+    regen::should_ignore_env,
+    strutil::{escape_shell, trim_space, word_scanner},
     symtab::{Symbol, intern},
     timeutil::ScopedTimeReporter,
-    var::USED_ENV_VARS,
+    var::{USED_ENV_VARS, Var},
 };
-use crate::{error, file_cache};
-
-fn find_command_line_flag(cmd: &[u8], name: &[u8]) -> Option<usize> {
-    match memmem::find(cmd, name) {
-        Some(0) => None,
-        found => found,
-    }
-}
-
-fn find_command_line_flag_with_arg(cmd: &[u8], name: &[u8]) -> Option<Vec<u8>> {
-    let idx = find_command_line_flag(cmd, name)?;
-
-    let mut val = trim_left_space(&cmd[idx + name.len()..]);
-    while let Some(idx) = memmem::find(val, name) {
-        val = trim_left_space(&val[idx + name.len()..]);
-    }
-
-    let Some(idx) = memchr2(b' ', b'\t', val) else {
-        return Some(val.to_vec());
-    };
-    Some(val[..idx].to_vec())
-}
-
-fn get_depfile_from_command_impl(cmd: &mut BytesMut) -> Result<Option<Vec<u8>>> {
-    if (find_command_line_flag(cmd, b" -MD").is_none()
-        && find_command_line_flag(cmd, b" -MMD").is_none())
-        || find_command_line_flag(cmd, b" -c").is_none()
-    {
-        return Ok(None);
-    }
-
-    if let Some(mf) = find_command_line_flag_with_arg(cmd, b" -MF") {
-        return Ok(Some(mf));
-    }
-
-    let Some(o) = find_command_line_flag_with_arg(cmd, b" -o") else {
-        error!(
-            "Cannot find the depfile in {}",
-            String::from_utf8_lossy(cmd.as_ref())
-        );
-    };
-
-    let mut o = strip_ext_vec(o);
-    o.extend_from_slice(b".d");
-    Ok(Some(o))
-}
-
-fn get_depfile_from_command(cmd: &mut BytesMut) -> Result<Option<Bytes>> {
-    assert!(!cmd.is_empty());
-    let Some(mut out) = get_depfile_from_command_impl(cmd)? else {
-        return Ok(None);
-    };
-
-    // A hack for Android - llvm-rs-cc seems not to emit a dep file.
-    if memmem::find(cmd, b"bin/llvm-rs-cc ").is_some() {
-        return Ok(None);
-    }
-
-    // TODO: A hack for Makefiles generated by automake.
-
-    // A hack for Android to get .P files instead of .d.
-    let mut p = strip_ext_vec(out.clone());
-    p.extend_from_slice(b".P");
-    if memmem::find(cmd, &p).is_some() {
-        let mut rm_f = b"; rm -f ".to_vec();
-        rm_f.extend_from_slice(&out);
-        let Some(found) = memmem::find(cmd, &rm_f) else {
-            error!(
-                "Cannot find remove of .d file: {}",
-                String::from_utf8_lossy(cmd.as_ref())
-            );
-        };
-        let after = cmd[found + rm_f.len()..].to_vec();
-        cmd.truncate(found);
-        cmd.extend_from_slice(&after);
-        return Ok(Some(Bytes::from(out)));
-    }
-
-    // A hack for Android. For .s files, GCC does not use C
-    // preprocessor, so it ignores -MF flag.
-    let mut a_s = b"/".to_vec();
-    a_s.extend_from_slice(strip_ext(basename(&out)));
-    a_s.extend_from_slice(b".s");
-    if memmem::find(cmd, &a_s).is_some() {
-        return Ok(None);
-    }
-
-    cmd.extend_from_slice(b"&& cp ");
-    cmd.extend_from_slice(&out);
-    cmd.put_u8(b' ');
-    cmd.extend_from_slice(&out);
-    cmd.extend_from_slice(b".tmp ");
-    out.extend_from_slice(b".tmp");
-    Ok(Some(Bytes::from(out)))
-}
 
 struct NinjaNode {
     node: Arc<Mutex<DepNode>>,
     commands: Vec<Command>,
     rule_id: Option<usize>,
+    // The node's depfile, dyndep, pool and tags variables, evaluated along
+    // with its commands so that emitting the node needs no evaluator.
+    depfile: Option<Bytes>,
+    dyndep: Option<Symbol>,
+    pool: Option<Bytes>,
+    tags: Option<Bytes>,
+}
+
+struct CompdbEntry {
+    file: Vec<u8>,
+    command: Vec<u8>,
+    output: Vec<u8>,
 }
 
 struct NinjaGenerator<'a> {
@@ -156,11 +80,29 @@ struct NinjaGenerator<'a> {
     kati_binary: OsString,
     start_time: SystemTime,
     nodes: Vec<NinjaNode>,
-    default_target: Mutex<Option<Arc<Mutex<DepNode>>>>,
+    // Rule bodies already emitted with --ninja_dedup_rules, by name.
+    shared_rules: HashMap<Vec<u8>, String>,
+    // With --ninja_shards, shared rules go to the top-level file so that
+    // every shard can use them.
+    shared_rule_defs: Vec<u8>,
+    compdb: Vec<CompdbEntry>,
+    // How many depfiles each of DEPFILE_DETECTORS found.
+    depfile_counts: Vec<usize>,
+    // Exported (Some) and unexported (None) variables for env.sh, sorted so
+    // that the script only changes with them.
+    exports: Vec<(Symbol, Option<Bytes>)>,
+    // Commands longer than this go through an rspfile.
+    max_command_len: usize,
+    // Commands evaluated before ninja generation, by output.
+    evaluated: HashMap<Symbol, Vec<Command>>,
 }
 
 impl<'a> NinjaGenerator<'a> {
-    fn new(ce: CommandEvaluator<'a>, start_time: SystemTime) -> Result<Self> {
+    fn new(
+        ce: CommandEvaluator<'a>,
+        start_time: SystemTime,
+        evaluated: HashMap<Symbol, Vec<Command>>,
+    ) -> Result<Self> {
         let shell = Self::escape_ninja(&ce.ev.get_shell()?);
         let shell_flags = Self::escape_ninja(&Bytes::from_static(ce.ev.get_shell_flag()));
         ce.ev.avoid_io = true;
@@ -174,19 +116,74 @@ impl<'a> NinjaGenerator<'a> {
             kati_binary: OsString::from(std::env::current_exe().unwrap()),
             start_time,
             nodes: Vec::new(),
-            default_target: Mutex::new(None),
+            shared_rules: HashMap::new(),
+            shared_rule_defs: Vec::new(),
+            compdb: Vec::new(),
+            depfile_counts: vec![0; DEPFILE_DETECTORS.len()],
+            exports: Vec::new(),
+            max_command_len: 0,
+            evaluated,
         })
     }
 
     fn generate(&mut self, nodes: &Vec<NamedDepNode>, orig_args: &[u8]) -> Result<()> {
         let _ = std::fs::remove_file(Self::get_stamp_temp_filename());
         self.populate_ninja_nodes(nodes)?;
+        self.collect_exports()?;
+        self.max_command_len = self.get_max_command_len();
         self.generate_ninja()?;
+        if FLAGS.enable_stat_logs && FLAGS.detect_depfiles {
+            for detection in &FLAGS.depfile_detectors {
+                eprintln!(
+                    "*kati*: {} depfiles detected by {}",
+                    self.depfile_counts[detection.detector],
+                    DEPFILE_DETECTORS[detection.detector].name
+                );
+            }
+        }
         self.generate_shell()?;
+        if let Some(filename) = &FLAGS.gen_compdb {
+            self.generate_compdb(filename)?;
+        }
         self.generate_stamp(orig_args)?;
+        if FLAGS.find_cache {
+            crate::find::save_find_cache(&get_find_cache_filename())?;
+        }
+        if FLAGS.ast_cache {
+            crate::ast_cache::save_ast_cache(&get_ast_cache_filename())?;
+        }
         Ok(())
     }
 
+    fn collect_exports(&mut self) -> Result<()> {
+        let mut exports: Vec<_> = self.ce.ev.exports.clone().into_iter().collect();
+        exports.sort_by_cached_key(|(symbol, _)| symbol.as_bytes());
+        for (symbol, is_exported) in exports {
+            let val = if is_exported {
+                Some(self.ce.ev.eval_var(symbol)?)
+            } else {
+                None
+            };
+            self.exports.push((symbol, val));
+        }
+        Ok(())
+    }
+
+    // Ninja runs commands as `/bin/sh -c <command>` with our environment
+    // plus the exports from env.sh, which all count against ARG_MAX.
+    fn get_max_command_len(&self) -> usize {
+        if let Some(threshold) = FLAGS.rspfile_threshold {
+            return threshold;
+        }
+        let env_size = environ_size()
+            + self
+                .exports
+                .iter()
+                .filter_map(|(symbol, val)| Some(symbol.as_bytes().len() + val.as_ref()?.len() + 2))
+                .sum::<usize>();
+        max_arg_len(env_size)
+    }
+
     fn get_stamp_temp_filename() -> OsString {
         ninja_file(".kati_stamp", ".tmp")
     }
@@ -231,7 +228,10 @@ impl<'a> NinjaGenerator<'a> {
             return Ok(());
         }
 
-        let commands = self.ce.eval(node)?;
+        let commands = match self.evaluated.remove(&output) {
+            Some(commands) => commands,
+            None => self.ce.eval(node)?,
+        };
         let rule_id = if commands.is_empty() {
             None
         } else {
@@ -240,11 +240,29 @@ impl<'a> NinjaGenerator<'a> {
             Some(id)
         };
 
-        self.nodes.push(NinjaNode {
+        let (depfile_var, dyndep_var, pool_var, tags_var) = {
+            let node = node.lock();
+            (
+                node.depfile_var.clone().filter(|_| !commands.is_empty()),
+                node.dyndep_var.clone(),
+                node.ninja_pool_var.clone(),
+                node.tags_var.clone(),
+            )
+        };
+        let nn = NinjaNode {
             node: node.clone(),
             commands,
             rule_id,
-        });
+            depfile: self.eval_node_var(&depfile_var)?,
+            dyndep: self
+                .eval_node_var(&dyndep_var)?
+                .map(|dyndep| Bytes::copy_from_slice(trim_space(&dyndep)))
+                .filter(|dyndep| !dyndep.is_empty())
+                .map(intern),
+            pool: self.eval_node_var(&pool_var)?,
+            tags: self.eval_node_var(&tags_var)?,
+        };
+        self.nodes.push(nn);
 
         let deps = node.lock().deps.clone();
         for (_symbol, depnode) in deps {
@@ -264,6 +282,53 @@ impl<'a> NinjaGenerator<'a> {
         Ok(())
     }
 
+    fn eval_node_var(&mut self, var: &Option<Var>) -> Result<Option<Bytes>> {
+        var.as_ref()
+            .map(|var| var.read().eval_to_buf(self.ce.ev))
+            .transpose()
+    }
+
+    fn emitter(&self) -> NodeEmitter {
+        NodeEmitter {
+            shell: self.shell.clone(),
+            shell_flags: self.shell_flags.clone(),
+            max_command_len: self.max_command_len,
+        }
+    }
+
+    // Writes `en` to `out`, naming its shared rule and defining it if it is
+    // new. With --ninja_shards, shared rules go to the top-level file so that
+    // every shard can use them.
+    fn write_emitted_node(&mut self, en: EmittedNode, out: &mut impl std::io::Write) -> Result<()> {
+        if let Some(detector) = en.depfile_detector {
+            self.depfile_counts[detector] += 1;
+        }
+        self.compdb.extend(en.compdb);
+        let Some(rule) = en.shared_rule else {
+            out.write_all(&en.text)?;
+            return Ok(());
+        };
+        let name = match self.shared_rules.get(&rule) {
+            Some(name) => name.clone(),
+            None => {
+                let name = format!("rule{}", self.shared_rules.len());
+                if FLAGS.ninja_shards > 0 {
+                    writeln!(self.shared_rule_defs, "rule {name}")?;
+                    self.shared_rule_defs.extend_from_slice(&rule);
+                } else {
+                    writeln!(out, "rule {name}")?;
+                    out.write_all(&rule)?;
+                }
+                self.shared_rules.insert(rule, name.clone());
+                name
+            }
+        };
+        out.write_all(&en.text[..en.rule_name_pos])?;
+        out.write_all(name.as_bytes())?;
+        out.write_all(&en.text[en.rule_name_pos..])?;
+        Ok(())
+    }
+
     fn translate_command(inp: Bytes) -> Bytes {
         let mut cmd_buf = BytesMut::new();
         let mut prev_backslash = false;
@@ -428,93 +493,6 @@ impl<'a> NinjaGenerator<'a> {
         }
     }
 
-    fn get_depfile(&mut self, node: &DepNode, cmd_buf: &mut BytesMut) -> Result<Option<Bytes>> {
-        if let Some(depfile_var) = node.depfile_var.clone() {
-            let depfile = depfile_var.read().eval_to_buf(self.ce.ev)?;
-            return Ok(Some(depfile));
-        }
-        if !FLAGS.detect_depfiles {
-            return Ok(None);
-        }
-
-        cmd_buf.put_u8(b' ');
-        let result = get_depfile_from_command(cmd_buf);
-        cmd_buf.truncate(cmd_buf.len() - 1);
-        result
-    }
-
-    fn emit_depfile(
-        &mut self,
-        node: &DepNode,
-        cmd_buf: &mut BytesMut,
-        out: &mut impl std::io::Write,
-    ) -> Result<()> {
-        if let Some(depfile) = self.get_depfile(node, cmd_buf)? {
-            write!(out, " depfile = ")?;
-            out.write_all(&depfile)?;
-            writeln!(out, "\n deps = gcc")?;
-        }
-        Ok(())
-    }
-
-    fn emit_node(&mut self, nn: &NinjaNode, out: &mut impl std::io::Write) -> Result<()> {
-        let node = nn.node.lock();
-        let commands = &nn.commands;
-
-        let mut rule_name = "phony".to_string();
-        let use_local_pool = FLAGS.remote_num_jobs > 0;
-        if is_special_target(&node.output) {
-            return Ok(());
-        }
-        if FLAGS.enable_debug {
-            writeln!(out, "# {}", node.loc.clone().unwrap_or_default())?;
-        }
-        if !commands.is_empty() {
-            rule_name = format!("rule{}", nn.rule_id.unwrap());
-            writeln!(out, "rule {rule_name}")?;
-
-            let mut description = Bytes::from_static(b"build $out");
-            let mut cmd_buf = BytesMut::new();
-            Self::gen_shell_script(
-                &node.output.as_bytes(),
-                commands,
-                &mut cmd_buf,
-                &mut description,
-            );
-            out.write_all(b" description = ")?;
-            out.write_all(&description)?;
-            out.write_all(b"\n")?;
-            self.emit_depfile(&node, &mut cmd_buf, out)?;
-
-            // It seems Linux is OK with ~130kB and Mac's limit is ~250kB.
-            // TODO: Find this number automatically.
-            if cmd_buf.len() > 100 * 1000 {
-                writeln!(out, " rspfile = $out.rsp")?;
-                write!(out, " rspfile_content = ")?;
-                out.write_all(&cmd_buf)?;
-                write!(out, "\n command = ")?;
-                out.write_all(&self.shell)?;
-                writeln!(out, " $out.rsp")?;
-            } else {
-                out.write_all(b" command = ")?;
-                out.write_all(&self.shell)?;
-                out.write_all(b" ")?;
-                out.write_all(&self.shell_flags)?;
-                out.write_all(b" \"")?;
-                out.write_all(&escape_shell(&cmd_buf.freeze()))?;
-                writeln!(out, "\"")?;
-            }
-            if node.is_restat {
-                writeln!(out, " restat = 1")?;
-            }
-            if FLAGS.emit_sandbox_disabled {
-                writeln!(out, " sandbox_disabled = true")?;
-            }
-        }
-
-        self.emit_build(nn, &node, rule_name, use_local_pool, out)
-    }
-
     fn escape_ninja(s: &Bytes) -> Bytes {
         let extras = s.iter().filter(|c| b"$: ".contains(c)).count();
         if extras == 0 {
@@ -538,94 +516,13 @@ impl<'a> NinjaGenerator<'a> {
         Self::escape_ninja(&s.as_bytes())
     }
 
-    fn emit_build(
-        &mut self,
-        nn: &NinjaNode,
-        node: &DepNode,
-        rule_name: String,
-        use_local_pool: bool,
-        out: &mut impl std::io::Write,
-    ) -> Result<()> {
-        let target = Self::escape_build_target(node.output);
-        write!(out, "build ")?;
-        out.write_all(&target)?;
-        if !node.implicit_outputs.is_empty() {
-            write!(out, " |")?;
-            for output in &node.implicit_outputs {
-                out.write_all(b" ")?;
-                out.write_all(&Self::escape_build_target(*output))?;
-            }
-        }
-        write!(out, ": {rule_name}")?;
-        if node.is_phony && !FLAGS.use_ninja_phony_output {
-            write!(out, " _kati_always_build_")?;
-        }
-        for (s, _) in &node.deps {
-            out.write_all(b" ")?;
-            out.write_all(&Self::escape_build_target(*s))?;
-        }
-        if !node.order_onlys.is_empty() {
-            write!(out, " ||")?;
-            for (s, _) in &node.order_onlys {
-                out.write_all(b" ")?;
-                out.write_all(&Self::escape_build_target(*s))?;
-            }
-        }
-        if !node.validations.is_empty() {
-            write!(out, " |@")?;
-            for (s, _) in &node.validations {
-                out.write_all(b" ")?;
-                out.write_all(&Self::escape_build_target(*s))?;
-            }
-        }
-
-        writeln!(out)?;
-
-        let pool = if let Some(ninja_pool_var) = &node.ninja_pool_var {
-            Some(ninja_pool_var.read().eval_to_buf(self.ce.ev)?)
-        } else {
-            None
-        };
-
-        if pool.as_ref().is_some_and(|pool| !pool.is_empty()) {
-            let pool = pool.unwrap();
-            if pool.as_ref() != b"none" {
-                write!(out, " pool = ")?;
-                out.write_all(&pool)?;
-                out.write_all(b"\n")?;
-            }
-        } else if !FLAGS.default_pool.is_empty() && rule_name != "phony" {
-            write!(out, " pool = ")?;
-            out.write_all(FLAGS.default_pool.as_bytes())?;
-            out.write_all(b"\n")?;
-        } else if use_local_pool {
-            writeln!(out, " pool = local_pool")?;
-        }
-        if node.is_phony && FLAGS.use_ninja_phony_output {
-            writeln!(out, " phony_output = true")?;
-        }
-        if let Some(tags_var) = &node.tags_var {
-            let tags = tags_var.read().eval_to_buf(self.ce.ev)?;
-            if !tags.is_empty() {
-                write!(out, " tags = ")?;
-                out.write_all(&tags)?;
-                writeln!(out)?;
-            }
-        }
-        if node.is_default_target {
-            *self.default_target.lock() = Some(nn.node.clone());
-        }
-        Ok(())
-    }
-
     fn get_env_script_filename() -> OsString {
         ninja_file("env", ".sh")
     }
 
     fn generate_ninja(&mut self) -> Result<()> {
         let _tr = ScopedTimeReporter::new("ninja gen (emit)");
-        let out = std::fs::File::create(get_ninja_filename())?;
-        let mut out = std::io::BufWriter::new(out);
+        let mut out = ChangedFileWriter::create(&get_ninja_filename())?;
 
         write!(out, "# Generated by kati unknown\n\n")?;
 
@@ -653,16 +550,32 @@ impl<'a> NinjaGenerator<'a> {
             }
         }
 
+        if FLAGS.ninja_regen_rule {
+            self.emit_regen_rule(&mut out)?;
+        }
+
         if !FLAGS.generate_empty_ninja {
-            for node in std::mem::take(&mut self.nodes) {
-                self.emit_node(&node, &mut out)?;
+            let nodes = std::mem::take(&mut self.nodes);
+            if FLAGS.ninja_shards > 0 {
+                self.emit_shards(&nodes, &mut out)?;
+            } else {
+                let emitter = self.emitter();
+                for nn in &nodes {
+                    let en = emitter.emit_node(nn)?;
+                    self.write_emitted_node(en, &mut out)?;
+                }
             }
 
             write!(out, "\ndefault ")?;
             if FLAGS.targets.is_empty() || FLAGS.gen_all_targets {
-                out.write_all(&Self::escape_build_target(
-                    self.default_target.lock().as_ref().unwrap().lock().output,
-                ))?;
+                let default_target = nodes
+                    .iter()
+                    .map(|nn| nn.node.lock())
+                    .filter(|node| node.is_default_target && !is_special_target(&node.output))
+                    .last()
+                    .unwrap()
+                    .output;
+                out.write_all(&Self::escape_build_target(default_target))?;
             } else {
                 let mut empty = true;
                 for s in &FLAGS.targets {
@@ -676,45 +589,206 @@ impl<'a> NinjaGenerator<'a> {
             out.write_all(b"\n")?;
         }
 
+        // Drop the shards of an earlier run that had more of them.
+        let num_shards = if FLAGS.generate_empty_ninja {
+            0
+        } else {
+            FLAGS.ninja_shards
+        };
+        for i in num_shards.. {
+            if std::fs::remove_file(get_ninja_shard_filename(i)).is_err() {
+                break;
+            }
+        }
+
         let mut used_env_vars = USED_ENV_VARS.lock().clone();
+        for e in &used_env_vars {
+            if should_ignore_env(&e.as_bytes()) {
+                kati_warn!(
+                    "warning: environment variable {e} was used, but --regen_ignore_env ignores its changes"
+                );
+            }
+        }
         // PATH changes $(shell).
         used_env_vars.insert(intern("PATH"));
         for e in used_env_vars {
             let k = e.as_bytes();
+            if should_ignore_env(&k) {
+                continue;
+            }
             let k = OsStr::from_bytes(&k);
             let val = std::env::var_os(k).unwrap();
             self.used_envs.insert(e, val);
         }
 
+        out.commit()?;
+        Ok(())
+    }
+
+    // Lets plain `ninja` regenerate its own manifest by rerunning kati with
+    // the same arguments whenever a makefile changes. Kati only rewrites
+    // files whose contents changed, hence restat.
+    fn emit_regen_rule(&self, out: &mut impl std::io::Write) -> Result<()> {
+        let mut command = quote_shell_arg(self.kati_binary.as_bytes());
+        let mut args = std::env::args_os().skip(1);
+        while let Some(arg) = args.next() {
+            // Ninja already runs commands in the directory kati switched to,
+            // and the daemon and --regen_explain are for interactive use.
+            if arg == "-C" || arg == "--regen_explain" {
+                args.next();
+                continue;
+            }
+            if arg.as_bytes().starts_with(b"-C")
+                || arg.as_bytes().starts_with(b"--regen_explain=")
+                || arg == "--daemon"
+                || arg == "--daemon_wait"
+                || arg == "--incremental_eval"
+            {
+                continue;
+            }
+            command.push(b' ');
+            command.extend_from_slice(&quote_shell_arg(arg.as_bytes()));
+        }
+
+        writeln!(out, "rule kati_regen")?;
+        write!(out, " command = ")?;
+        for c in command {
+            if c == b'$' {
+                out.write_all(b"$")?;
+            }
+            out.write_all(&[c])?;
+        }
+        writeln!(out, "\n description = Regenerating $out")?;
+        writeln!(out, " generator = 1")?;
+        writeln!(out, " restat = 1\n")?;
+
+        write!(out, "build ")?;
+        out.write_all(&Self::escape_ninja(&Bytes::from(
+            get_ninja_filename().into_vec(),
+        )))?;
+        if FLAGS.ninja_shards > 0 {
+            write!(out, " |")?;
+            for shard in 0..FLAGS.ninja_shards {
+                out.write_all(b" ")?;
+                out.write_all(&Self::escape_ninja(&Bytes::from(
+                    get_ninja_shard_filename(shard).into_vec(),
+                )))?;
+            }
+        }
+        write!(out, ": kati_regen")?;
+        // Ninja cannot depend on files that don't exist, such as optional
+        // includes that were not found.
+        let mut makefiles: Vec<_> = file_cache::get_all_files()
+            .into_keys()
+            .filter(|f| std::fs::exists(f).unwrap_or(false))
+            .collect();
+        makefiles.sort();
+        for makefile in makefiles {
+            out.write_all(b" ")?;
+            out.write_all(&Self::escape_ninja(&Bytes::from(makefile.into_vec())))?;
+        }
+        if !FLAGS.regen_ignoring_kati_binary {
+            out.write_all(b" | ")?;
+            out.write_all(&Self::escape_ninja(&Bytes::from(
+                self.kati_binary.as_bytes().to_vec(),
+            )))?;
+        }
+        writeln!(out, "\n")?;
+        Ok(())
+    }
+
+    // Shards only need the pools, bindings and shared rules from the
+    // top-level file, which subninja inherits.
+    fn emit_shards(&mut self, nodes: &[NinjaNode], out: &mut impl std::io::Write) -> Result<()> {
+        let mut shard_nodes: Vec<Vec<&NinjaNode>> = vec![Vec::new(); FLAGS.ninja_shards];
+        let node_shards: Vec<usize> = nodes
+            .iter()
+            .map(|nn| {
+                let shard = shard_index(&nn.node.lock().output.as_bytes(), shard_nodes.len());
+                shard_nodes[shard].push(nn);
+                shard
+            })
+            .collect();
+
+        let emitter = self.emitter();
+        let emitted = std::thread::scope(|s| {
+            let handles: Vec<_> = shard_nodes
+                .iter()
+                .map(|nodes| {
+                    let emitter = &emitter;
+                    s.spawn(move || {
+                        nodes
+                            .iter()
+                            .map(|nn| emitter.emit_node(nn))
+                            .collect::<Result<Vec<_>>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        // Shared rules are named in the order of the nodes, as without shards.
+        let mut emitted: Vec<_> = emitted.into_iter().map(Vec::into_iter).collect();
+        let mut shards = vec![b"# Generated by kati unknown\n\n".to_vec(); FLAGS.ninja_shards];
+        for shard in node_shards {
+            let en = emitted[shard].next().unwrap();
+            self.write_emitted_node(en, &mut shards[shard])?;
+        }
+
+        let _tr = ScopedTimeReporter::new("ninja gen (write shards)");
+        std::thread::scope(|s| {
+            let handles: Vec<_> = shards
+                .iter()
+                .enumerate()
+                .map(|(i, shard)| {
+                    s.spawn(move || {
+                        let mut out = ChangedFileWriter::create(&get_ninja_shard_filename(i))?;
+                        out.write_all(shard)?;
+                        out.commit()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .try_for_each(|h| h.join().unwrap().map(|_| ()))
+        })?;
+
+        out.write_all(&self.shared_rule_defs)?;
+        for i in 0..shards.len() {
+            write!(out, "subninja ")?;
+            out.write_all(&Self::escape_ninja(&Bytes::from(
+                get_ninja_shard_filename(i).into_vec(),
+            )))?;
+            writeln!(out)?;
+        }
         Ok(())
     }
 
     fn generate_shell(&mut self) -> Result<()> {
         {
-            let out = std::fs::File::create(Self::get_env_script_filename())?;
-            let mut out = std::io::BufWriter::new(out);
+            let mut out = ChangedFileWriter::create(&Self::get_env_script_filename())?;
 
             writeln!(out, "#!/bin/sh")?;
             writeln!(out, "# Generated by kati unknown\n")?;
 
-            let exports = self.ce.ev.exports.clone();
-            for (symbol, is_exported) in exports {
-                if is_exported {
-                    let val = self.ce.ev.eval_var(symbol)?;
+            for (symbol, val) in &self.exports {
+                if let Some(val) = val {
                     write!(out, "export '{symbol}'='")?;
-                    out.write_all(&val)?;
+                    out.write_all(val)?;
                     writeln!(out, "'")?;
                 } else {
                     writeln!(out, "unset '{symbol}'")?;
                 }
             }
+            out.commit()?;
         }
 
         {
-            let mut opts = OpenOptions::new();
-            opts.create(true).write(true).mode(0o755);
-            let out = opts.open(get_ninja_shell_script_filename())?;
-            let mut out = std::io::BufWriter::new(out);
+            let mut out =
+                ChangedFileWriter::create_with_mode(&get_ninja_shell_script_filename(), 0o755)?;
 
             writeln!(out, "#!/bin/sh")?;
             writeln!(out, "# Generated by kati unknown\n")?;
@@ -729,26 +803,67 @@ impl<'a> NinjaGenerator<'a> {
                 write!(out, "-j{} ", FLAGS.remote_num_jobs)?;
             }
             writeln!(out, "\"$@\"")?;
+            out.commit()?;
         }
         Ok(())
     }
 
+    fn generate_compdb(&self, filename: &OsStr) -> Result<()> {
+        let _tr = ScopedTimeReporter::new("ninja gen (compdb)");
+        let directory = std::env::current_dir()?;
+        let directory = directory.to_string_lossy();
+        let entries: Vec<_> = self
+            .compdb
+            .iter()
+            .map(|e| {
+                serde_json::json!({
+                    "directory": directory,
+                    "file": String::from_utf8_lossy(&e.file),
+                    "command": String::from_utf8_lossy(&e.command),
+                    "output": String::from_utf8_lossy(&e.output),
+                })
+            })
+            .collect();
+        let mut out = ChangedFileWriter::create(filename)?;
+        serde_json::to_writer_pretty(&mut out, &entries)?;
+        writeln!(out)?;
+        out.commit()?;
+        Ok(())
+    }
+
     fn generate_stamp(&self, orig_args: &[u8]) -> Result<()> {
         {
             let out = std::fs::File::create(Self::get_stamp_temp_filename())?;
             let mut out = std::io::BufWriter::new(out);
 
-            dump_systemtime(&mut out, &self.start_time)?;
+            dump_stamp_header(&mut out, &self.start_time)?;
+            let mut out = StampWriter::new(out);
 
-            let makefiles = file_cache::get_all_filenames();
-            dump_usize(&mut out, makefiles.len() + 1)?;
-            dump_string(&mut out, self.kati_binary.as_bytes())?;
-            for makefile in makefiles {
-                dump_string(&mut out, makefile.as_bytes())?;
+            // The kati binary gets no hash, so a newer binary always
+            // triggers regeneration.
+            let mut files = vec![(self.kati_binary.clone(), None)];
+            files.extend(file_cache::get_all_files());
+            dump_usize(&mut out, files.len())?;
+            for (file, _) in &files {
+                dump_string(&mut out, file.as_bytes())?;
+            }
+            // With --regen_content_hash, files with a newer mtime are hashed
+            // again and only count as modified if their contents differ from
+            // what this run read.
+            let mut hashes = Vec::new();
+            if FLAGS.regen_content_hash {
+                for (_, hash) in &files {
+                    hashes.push(hash.map_or_else(Vec::new, |h| h.to_vec()));
+                }
             }
+            dump_vec_string(&mut out, &hashes)?;
 
-            dump_usize(&mut out, Evaluator::used_undefined_vars().len())?;
-            for v in Evaluator::used_undefined_vars() {
+            let undefineds: Vec<Symbol> = Evaluator::used_undefined_vars()
+                .into_iter()
+                .filter(|v| !should_ignore_env(&v.as_bytes()))
+                .collect();
+            dump_usize(&mut out, undefineds.len())?;
+            for v in undefineds {
                 dump_string(&mut out, &v.as_bytes())?;
             }
             dump_usize(&mut out, self.used_envs.len())?;
@@ -810,22 +925,496 @@ impl<'a> NinjaGenerator<'a> {
             }
 
             dump_string(&mut out, orig_args)?;
+            out.finish()?.flush()?;
         }
         std::fs::rename(Self::get_stamp_temp_filename(), get_ninja_stamp_filename())?;
         Ok(())
     }
 }
 
+// One node's part of the ninja file. With --ninja_dedup_rules, shared rules
+// are only named once every node has been emitted, so that the names don't
+// depend on which shard got there first. `text` then leaves out the name of
+// `shared_rule` at `rule_name_pos`.
+#[derive(Default)]
+struct EmittedNode {
+    text: Vec<u8>,
+    shared_rule: Option<Vec<u8>>,
+    rule_name_pos: usize,
+    compdb: Option<CompdbEntry>,
+    // The index in DEPFILE_DETECTORS of the detector that found the depfile.
+    depfile_detector: Option<usize>,
+}
+
+// What emitting a node needs from the generator. Unlike the generator, it
+// can be shared by the threads that emit shards.
+struct NodeEmitter {
+    shell: Bytes,
+    shell_flags: Bytes,
+    max_command_len: usize,
+}
+
+impl NodeEmitter {
+    // Returns the command binding for `cmd_buf`, or None if it is too long
+    // to be passed to the shell and needs an rspfile.
+    fn shell_command(&self, cmd_buf: &[u8]) -> Option<Vec<u8>> {
+        let escaped = escape_shell(&Bytes::copy_from_slice(cmd_buf));
+        let mut command =
+            Vec::with_capacity(self.shell.len() + self.shell_flags.len() + escaped.len() + 4);
+        command.extend_from_slice(&self.shell);
+        command.push(b' ');
+        command.extend_from_slice(&self.shell_flags);
+        command.extend_from_slice(b" \"");
+        command.extend_from_slice(&escaped);
+        command.push(b'"');
+        (command.len() <= self.max_command_len).then_some(command)
+    }
+
+    fn get_depfile(
+        nn: &NinjaNode,
+        cmd_buf: &mut BytesMut,
+    ) -> Result<Option<(Bytes, DepsType, Option<usize>)>> {
+        if let Some(depfile) = &nn.depfile {
+            return Ok(Some((depfile.clone(), DepsType::Gcc, None)));
+        }
+        if !FLAGS.detect_depfiles {
+            return Ok(None);
+        }
+
+        cmd_buf.put_u8(b' ');
+        let result = detect_depfile(cmd_buf);
+        cmd_buf.truncate(cmd_buf.len() - 1);
+        let Some(depfile) = result? else {
+            return Ok(None);
+        };
+        Ok(Some((depfile.path, depfile.deps, Some(depfile.detector))))
+    }
+
+    // Returns the detector that found the depfile, if one did.
+    fn emit_depfile(
+        nn: &NinjaNode,
+        cmd_buf: &mut BytesMut,
+        out: &mut impl std::io::Write,
+    ) -> Result<Option<usize>> {
+        let Some((depfile, deps, detector)) = Self::get_depfile(nn, cmd_buf)? else {
+            return Ok(None);
+        };
+        write!(out, " depfile = ")?;
+        out.write_all(&depfile)?;
+        out.write_all(b"\n")?;
+        if let Some(deps) = deps.binding() {
+            writeln!(out, " deps = {deps}")?;
+        }
+        Ok(detector)
+    }
+
+    fn emit_node(&self, nn: &NinjaNode) -> Result<EmittedNode> {
+        let node = nn.node.lock();
+        let commands = &nn.commands;
+        let mut en = EmittedNode::default();
+
+        let mut rule_name = "phony".to_string();
+        let use_local_pool = FLAGS.remote_num_jobs > 0;
+        if is_special_target(&node.output) {
+            return Ok(en);
+        }
+        if FLAGS.enable_debug {
+            writeln!(en.text, "# {}", node.loc.clone().unwrap_or_default())?;
+        }
+        if !commands.is_empty() && FLAGS.ninja_dedup_rules {
+            let bindings = self.emit_shared_rule(nn, &node, &mut en)?;
+            self.emit_build(nn, &node, None, use_local_pool, &mut en)?;
+            en.text.extend_from_slice(&bindings);
+            return Ok(en);
+        }
+        if !commands.is_empty() {
+            rule_name = format!("rule{}", nn.rule_id.unwrap());
+            let out = &mut en.text;
+            writeln!(out, "rule {rule_name}")?;
+
+            let mut description = Bytes::from_static(b"build $out");
+            let mut cmd_buf = BytesMut::new();
+            NinjaGenerator::gen_shell_script(
+                &node.output.as_bytes(),
+                commands,
+                &mut cmd_buf,
+                &mut description,
+            );
+            en.compdb = compdb_entry(&node, &cmd_buf);
+            out.write_all(b" description = ")?;
+            out.write_all(&description)?;
+            out.write_all(b"\n")?;
+            en.depfile_detector = Self::emit_depfile(nn, &mut cmd_buf, out)?;
+
+            if let Some(command) = self.shell_command(&cmd_buf) {
+                out.write_all(b" command = ")?;
+                out.write_all(&command)?;
+                out.write_all(b"\n")?;
+            } else {
+                writeln!(out, " rspfile = $out.rsp")?;
+                write!(out, " rspfile_content = ")?;
+                out.write_all(&cmd_buf)?;
+                write!(out, "\n command = ")?;
+                out.write_all(&self.shell)?;
+                writeln!(out, " $out.rsp")?;
+            }
+            if node.is_restat {
+                writeln!(out, " restat = 1")?;
+            }
+            if FLAGS.emit_sandbox_disabled {
+                writeln!(out, " sandbox_disabled = true")?;
+            }
+        }
+
+        self.emit_build(nn, &node, Some(&rule_name), use_local_pool, &mut en)?;
+        Ok(en)
+    }
+
+    // Sets the body of the rule for `node` as its shared rule, and returns
+    // the build-level bindings that differ between the nodes sharing it.
+    fn emit_shared_rule(
+        &self,
+        nn: &NinjaNode,
+        node: &DepNode,
+        en: &mut EmittedNode,
+    ) -> Result<Vec<u8>> {
+        let mut description = Bytes::from_static(b"build $out");
+        let mut cmd_buf = BytesMut::new();
+        NinjaGenerator::gen_shell_script(
+            &node.output.as_bytes(),
+            &nn.commands,
+            &mut cmd_buf,
+            &mut description,
+        );
+        en.compdb = compdb_entry(node, &cmd_buf);
+
+        let mut rule = Vec::new();
+        let mut bindings = Vec::new();
+        if description.as_ref() == b"build $out" {
+            rule.extend_from_slice(b" description = build $out\n");
+        } else {
+            bindings.extend_from_slice(b" description = ");
+            bindings.extend_from_slice(&description);
+            bindings.push(b'\n');
+        }
+        en.depfile_detector = Self::emit_depfile(nn, &mut cmd_buf, &mut bindings)?;
+
+        if let Some(command) = self.shell_command(&cmd_buf) {
+            let output = node.output.as_bytes();
+            let output = output
+                .iter()
+                .all(|c| is_shell_safe(*c))
+                .then_some(&output[..]);
+            // _kati_always_build_ is an explicit input of phony targets, so
+            // $in would not match their dependencies.
+            let inputs = if node.deps.is_empty() || (node.is_phony && !FLAGS.use_ninja_phony_output)
+            {
+                None
+            } else {
+                let mut inputs = Vec::new();
+                for (s, _) in &node.deps {
+                    if !inputs.is_empty() {
+                        inputs.push(b' ');
+                    }
+                    inputs.extend_from_slice(&s.as_bytes());
+                }
+                inputs
+                    .iter()
+                    .all(|c| *c == b' ' || is_shell_safe(*c))
+                    .then_some(inputs)
+            };
+            rule.extend_from_slice(b" command = ");
+            rule.extend_from_slice(&templatize_command(&command, output, inputs.as_deref()));
+            rule.push(b'\n');
+        } else {
+            rule.extend_from_slice(b" rspfile = $out.rsp\n command = ");
+            rule.extend_from_slice(&self.shell);
+            rule.extend_from_slice(b" $out.rsp\n");
+            bindings.extend_from_slice(b" rspfile_content = ");
+            bindings.extend_from_slice(&cmd_buf);
+            bindings.push(b'\n');
+        }
+        if node.is_restat {
+            rule.extend_from_slice(b" restat = 1\n");
+        }
+        if FLAGS.emit_sandbox_disabled {
+            rule.extend_from_slice(b" sandbox_disabled = true\n");
+        }
+
+        en.shared_rule = Some(rule);
+        Ok(bindings)
+    }
+
+    // Without `rule_name`, the build uses `en.shared_rule`.
+    fn emit_build(
+        &self,
+        nn: &NinjaNode,
+        node: &DepNode,
+        rule_name: Option<&str>,
+        use_local_pool: bool,
+        en: &mut EmittedNode,
+    ) -> Result<()> {
+        let is_phony_rule = rule_name == Some("phony");
+        let dyndep = nn.dyndep;
+        // Ninja requires the dyndep file to be an input of the edge.
+        let dyndep_input = dyndep.filter(|dyndep| {
+            !node
+                .deps
+                .iter()
+                .chain(&node.order_onlys)
+                .any(|(s, _)| s == dyndep)
+        });
+
+        let out = &mut en.text;
+        let target = NinjaGenerator::escape_build_target(node.output);
+        write!(out, "build ")?;
+        out.write_all(&target)?;
+        if !node.implicit_outputs.is_empty() {
+            write!(out, " |")?;
+            for output in &node.implicit_outputs {
+                out.write_all(b" ")?;
+                out.write_all(&NinjaGenerator::escape_build_target(*output))?;
+            }
+        }
+        write!(out, ": ")?;
+        match rule_name {
+            Some(rule_name) => out.write_all(rule_name.as_bytes())?,
+            None => en.rule_name_pos = out.len(),
+        }
+        if node.is_phony && !FLAGS.use_ninja_phony_output {
+            write!(out, " _kati_always_build_")?;
+        }
+        for (s, _) in &node.deps {
+            out.write_all(b" ")?;
+            out.write_all(&NinjaGenerator::escape_build_target(*s))?;
+        }
+        if !node.order_onlys.is_empty() || dyndep_input.is_some() {
+            write!(out, " ||")?;
+            for (s, _) in &node.order_onlys {
+                out.write_all(b" ")?;
+                out.write_all(&NinjaGenerator::escape_build_target(*s))?;
+            }
+            if let Some(dyndep) = dyndep_input {
+                out.write_all(b" ")?;
+                out.write_all(&NinjaGenerator::escape_build_target(dyndep))?;
+            }
+        }
+        if !node.validations.is_empty() {
+            write!(out, " |@")?;
+            for (s, _) in &node.validations {
+                out.write_all(b" ")?;
+                out.write_all(&NinjaGenerator::escape_build_target(*s))?;
+            }
+        }
+
+        writeln!(out)?;
+
+        if node.is_console && !is_phony_rule {
+            // Ninja's built-in pool for commands that need the terminal.
+            writeln!(out, " pool = console")?;
+        } else if let Some(pool) = nn.pool.as_ref().filter(|pool| !pool.is_empty()) {
+            if pool.as_ref() != b"none" {
+                write!(out, " pool = ")?;
+                out.write_all(pool)?;
+                out.write_all(b"\n")?;
+            }
+        } else if !FLAGS.default_pool.is_empty() && !is_phony_rule {
+            write!(out, " pool = ")?;
+            out.write_all(FLAGS.default_pool.as_bytes())?;
+            out.write_all(b"\n")?;
+        } else if use_local_pool {
+            writeln!(out, " pool = local_pool")?;
+        }
+        if node.is_phony && FLAGS.use_ninja_phony_output {
+            writeln!(out, " phony_output = true")?;
+        }
+        if let Some(dyndep) = dyndep {
+            write!(out, " dyndep = ")?;
+            out.write_all(&NinjaGenerator::escape_build_target(dyndep))?;
+            writeln!(out)?;
+        }
+        if let Some(tags) = nn.tags.as_ref().filter(|tags| !tags.is_empty()) {
+            write!(out, " tags = ")?;
+            out.write_all(tags)?;
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+// Returns the compiler invocation in `cmd`, if any. Like the rest of the
+// generated ninja file, `cmd` still has `$` escaped.
+fn compdb_entry(node: &DepNode, cmd: &[u8]) -> Option<CompdbEntry> {
+    FLAGS.gen_compdb.as_ref()?;
+    let cmd = unescape_ninja_dollars(cmd);
+    for command in split_shell_commands(&cmd) {
+        let mut command = command.to_vec();
+        command.push(b' ');
+        if find_command_line_flag(&command, b" -c ").is_none() {
+            continue;
+        }
+        let output = find_command_line_flag_with_arg(&command, b" -o")
+            .unwrap_or_else(|| node.output.as_bytes().to_vec());
+        let Some(file) = word_scanner(&command).find(|w| is_compdb_source(w) && *w != output)
+        else {
+            continue;
+        };
+        let file = file.to_vec();
+        command.pop();
+        return Some(CompdbEntry {
+            file,
+            command,
+            output,
+        });
+    }
+    None
+}
+
 impl Drop for NinjaGenerator<'_> {
     fn drop(&mut self) {
         self.ce.ev.avoid_io = false;
     }
 }
 
+fn unescape_ninja_dollars(cmd: &[u8]) -> Vec<u8> {
+    let mut r = Vec::with_capacity(cmd.len());
+    let mut i = 0;
+    while i < cmd.len() {
+        r.push(cmd[i]);
+        i += if cmd[i..].starts_with(b"$$") { 2 } else { 1 };
+    }
+    r
+}
+
+// Splits a shell command line into its simple commands at `;`, `&&`, `||`
+// and pipes outside of quotes, dropping the subshell parentheses that
+// gen_shell_script adds.
+fn split_shell_commands(cmd: &[u8]) -> Vec<&[u8]> {
+    let mut r = Vec::new();
+    let mut quote = None;
+    let mut prev_backslash = false;
+    let mut start = 0;
+    for (i, &c) in cmd.iter().enumerate() {
+        if prev_backslash {
+            prev_backslash = false;
+            continue;
+        }
+        match (quote, c) {
+            (_, b'\\') => prev_backslash = true,
+            (Some(q), _) if q == c => quote = None,
+            (Some(_), _) => {}
+            (None, b'\'' | b'"' | b'`') => quote = Some(c),
+            (None, b';' | b'&' | b'|') => {
+                r.push(&cmd[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    r.push(&cmd[start..]);
+    r.into_iter()
+        .map(|c| {
+            let c = c.trim_ascii();
+            let c = c.strip_prefix(b"(").unwrap_or(c);
+            c.strip_suffix(b")").unwrap_or(c).trim_ascii()
+        })
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+fn is_compdb_source(word: &[u8]) -> bool {
+    const EXTS: &[&[u8]] = &[
+        b".c", b".cc", b".cp", b".cpp", b".cxx", b".c++", b".C", b".m", b".mm", b".s", b".S",
+    ];
+    !word.starts_with(b"-")
+        && EXTS
+            .iter()
+            .any(|ext| word.ends_with(ext) && word.len() > ext.len())
+}
+
+// Characters ninja does not quote when expanding $in and $out.
+fn is_shell_safe(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"_+-./".contains(&c)
+}
+
+// Replaces occurrences of the output and the inputs which start a word in
+// an escaped command with $out and $in, so that "$@.d" also becomes
+// "$out.d". Callers only pass paths which need no quoting, so ninja
+// expands the result back to the same command.
+fn templatize_command(cmd: &[u8], output: Option<&[u8]>, inputs: Option<&[u8]>) -> Vec<u8> {
+    let mut r = Vec::with_capacity(cmd.len());
+    let mut dollars = 0;
+    let mut i = 0;
+    while i < cmd.len() {
+        // A word can't start right after a '$' which escapes it.
+        let at_boundary = dollars % 2 == 0 && (i == 0 || !is_shell_safe(cmd[i - 1]));
+        if at_boundary {
+            let rest = &cmd[i..];
+            let found = [(inputs, b"$in".as_slice()), (output, b"$out".as_slice())]
+                .into_iter()
+                .find_map(|(word, var)| {
+                    let word = word?;
+                    let after = rest.strip_prefix(word)?;
+                    // Otherwise ninja would read a longer variable name.
+                    let ends_var = !after
+                        .first()
+                        .is_some_and(|c| c.is_ascii_alphanumeric() || b"_-".contains(c));
+                    ends_var.then_some((word, var))
+                });
+            if let Some((word, var)) = found {
+                r.extend_from_slice(var);
+                i += word.len();
+                dollars = 0;
+                continue;
+            }
+        }
+        if cmd[i] == b'$' {
+            dollars += 1;
+        } else {
+            dollars = 0;
+        }
+        r.push(cmd[i]);
+        i += 1;
+    }
+    r
+}
+
+// Quotes `arg` for /bin/sh, unless it only has characters the shell
+// leaves alone.
+fn quote_shell_arg(arg: &[u8]) -> Vec<u8> {
+    if !arg.is_empty() && arg.iter().all(|c| is_shell_safe(*c) || *c == b'=') {
+        return arg.to_vec();
+    }
+    let mut r = vec![b'\''];
+    for c in arg {
+        if *c == b'\'' {
+            r.extend_from_slice(b"'\\''");
+        } else {
+            r.push(*c);
+        }
+    }
+    r.push(b'\'');
+    r
+}
+
 pub fn get_ninja_filename() -> OsString {
     ninja_file("build", ".ninja")
 }
 
+pub fn get_ninja_shard_filename(shard: usize) -> OsString {
+    ninja_file("build", &format!(".{shard}.ninja"))
+}
+
+// FNV-1a, so that a target lands in the same shard on every run.
+fn shard_index(output: &[u8], shards: usize) -> usize {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for c in output {
+        h ^= *c as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    (h % shards as u64) as usize
+}
+
 pub fn get_ninja_shell_script_filename() -> OsString {
     ninja_file("ninja", ".sh")
 }
@@ -834,6 +1423,44 @@ pub fn get_ninja_stamp_filename() -> OsString {
     ninja_file(".kati_stamp", "")
 }
 
+pub fn get_find_cache_filename() -> OsString {
+    ninja_file(".kati_find_cache", "")
+}
+
+pub fn get_ast_cache_filename() -> OsString {
+    ninja_file(".kati_ast_cache", "")
+}
+
+pub fn get_daemon_socket_filename() -> OsString {
+    ninja_file(".kati_daemon", ".sock")
+}
+
+/// Every file kati writes next to the ninja file, including the temporary
+/// files they are written through.
+pub fn get_generated_filenames() -> Vec<OsString> {
+    let mut files = vec![
+        get_ninja_filename(),
+        get_ninja_shell_script_filename(),
+        NinjaGenerator::get_env_script_filename(),
+        get_ninja_stamp_filename(),
+        get_find_cache_filename(),
+        get_ast_cache_filename(),
+        get_daemon_socket_filename(),
+    ];
+    files.extend((0..FLAGS.ninja_shards).map(get_ninja_shard_filename));
+    files.extend(FLAGS.gen_compdb.clone());
+    let tmps: Vec<OsString> = files
+        .iter()
+        .map(|f| {
+            let mut tmp = f.clone();
+            tmp.push(".tmp");
+            tmp
+        })
+        .collect();
+    files.extend(tmps);
+    files
+}
+
 fn ninja_file(prefix: &str, suffix: &str) -> OsString {
     let mut r = FLAGS
         .ninja_dir
@@ -846,13 +1473,16 @@ fn ninja_file(prefix: &str, suffix: &str) -> OsString {
     r
 }
 
+/// `evaluated` holds commands that were already evaluated for some outputs,
+/// so that they are not evaluated again.
 pub fn generate_ninja(
     nodes: &Vec<NamedDepNode>,
     ev: &mut Evaluator,
     orig_args: &[u8],
     start_time: SystemTime,
+    evaluated: HashMap<Symbol, Vec<Command>>,
 ) -> Result<()> {
-    let mut ng = NinjaGenerator::new(CommandEvaluator::new(ev)?, start_time)?;
+    let mut ng = NinjaGenerator::new(CommandEvaluator::new(ev)?, start_time, evaluated)?;
     ng.generate(nodes, orig_args)?;
     Ok(())
 }
@@ -885,93 +1515,50 @@ mod tests {
     }
 
     #[test]
-    fn test_translate_command() {
+    fn test_templatize_command() {
+        let t = |cmd: &str, output: &str, inputs: Option<&str>| {
+            String::from_utf8(templatize_command(
+                cmd.as_bytes(),
+                Some(output.as_bytes()),
+                inputs.map(|i| i.as_bytes()),
+            ))
+            .unwrap()
+        };
+        assert_eq!(t("cp a.c a.o", "a.o", Some("a.c")), "cp $in $out");
+        assert_eq!(t("cat a b > ab", "ab", Some("a b")), "cat $in > $out");
         assert_eq!(
-            NinjaGenerator::translate_command(Bytes::from_static(b"echo Hello")),
-            Bytes::from_static(b"echo Hello")
+            t("gcc -MF a.o.d -o a.o", "a.o", None),
+            "gcc -MF $out.d -o $out"
         );
-    }
-
-    fn get_depfile_and_cmd(cmd: &str) -> Result<(Option<String>, String)> {
-        let mut cmd = BytesMut::from(cmd.as_bytes());
-        let depfile = get_depfile_from_command(&mut cmd)?;
-        Ok((
-            depfile.map(|b| String::from_utf8_lossy(&b).to_string()),
-            String::from_utf8_lossy(&cmd).to_string(),
-        ))
-    }
-    fn get_depfile(cmd: &str) -> Result<Option<String>> {
-        Ok(get_depfile_and_cmd(cmd)?.0)
+        assert_eq!(t("touch xa.o a.ox", "a.o", None), "touch xa.o a.ox");
+        assert_eq!(t("echo \\$$a.o", "a.o", None), "echo \\$$$out");
+        assert_eq!(t("echo $a.o $$$a.o", "a.o", None), "echo $a.o $$$a.o");
     }
 
     #[test]
-    fn test_get_depfile() -> Result<()> {
-        assert!(get_depfile("g++ -c fat.cc -MD ").is_err());
-
-        assert_eq!(get_depfile("g++ -c fat.cc -o fat.o")?, None);
-        assert_eq!(
-            get_depfile("g++ -c fat.cc -MD -o fat.o -o fuga.o")?,
-            Some("fuga.d.tmp".into())
-        );
-        assert_eq!(
-            get_depfile("g++ -c fat.cc -MD -o fat.o")?,
-            Some("fat.d.tmp".into())
-        );
-        assert_eq!(
-            get_depfile("g++ -c fat.cc -MD -o fat")?,
-            Some("fat.d.tmp".into())
-        );
-        assert_eq!(
-            get_depfile("g++ -c fat.cc -MD -MF foo.d -o fat.o")?,
-            Some("foo.d.tmp".into())
-        );
-        assert_eq!(
-            get_depfile("g++ -c fat.cc -MD -o fat.o -MF foo.d")?,
-            Some("foo.d.tmp".into())
-        );
-        // A real example from maloader.
-        assert_eq!(
-            get_depfile(
-                "g++ -g -Iinclude -Wall -MMD -fno-omit-frame-pointer -O -m64 -W -Werror   -c -o fat.o fat.cc"
-            )?,
-            Some("fat.d.tmp".into())
-        );
-        // A real example from Android.
-        assert_eq!(
-            get_depfile_and_cmd(
-                "mkdir -p out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/ && echo \"host C++: llvm-rs-cc <= frameworks/compile/slang/llvm-rs-cc.cpp\" && prebuilts/clang/linux-x86/host/3.6/bin/clang++ -I external/llvm -I external/llvm/include -I external/llvm/host/include -I external/clang/include -I external/clang/lib/CodeGen -I frameworks/compile/libbcc/include -I out/host/linux-x86/gen/EXECUTABLES/llvm-rs-cc_intermediates/include -I external/libcxx/include -I frameworks/compile/slang -I out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates -I out/host/linux-x86/gen/EXECUTABLES/llvm-rs-cc_intermediates -I libnativehelper/include/nativehelper $(cat out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/import_includes) -isystem system/core/include -isystem hardware/libhardware/include -isystem hardware/libhardware_legacy/include -isystem hardware/ril/include -isystem libnativehelper/include -isystem frameworks/native/include -isystem frameworks/native/opengl/include -isystem frameworks/av/include -isystem frameworks/base/include -isystem tools/include -isystem out/host/linux-x86/obj/include -c    -fno-exceptions -Wno-multichar -m64 -Wa,--noexecstack -fPIC -no-canonical-prefixes -include build/core/combo/include/arch/linux-x86/AndroidConfig.h -U_FORTIFY_SOURCE -D_FORTIFY_SOURCE=0 -D__STDC_FORMAT_MACROS -D__STDC_CONSTANT_MACROS -DANDROID -fmessage-length=0 -W -Wall -Wno-unused -Winit-self -Wpointer-arith -O2 -g -fno-strict-aliasing -DNDEBUG -UDEBUG  -D__compiler_offsetof=__builtin_offsetof -Werror=int-conversion -Wno-unused-command-line-argument   --gcc-toolchain=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8/    --gcc-toolchain=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8/ --sysroot=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//sysroot -target x86_64-linux-gnu   -DANDROID -fmessage-length=0 -W -Wall -Wno-unused -Winit-self -Wpointer-arith -Wsign-promo -std=gnu++11 -DNDEBUG -UDEBUG  -Wno-inconsistent-missing-override   --gcc-toolchain=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8/ --sysroot=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//sysroot -isystem prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//x86_64-linux/include/c++/4.8 -isystem prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//x86_64-linux/include/c++/4.8/x86_64-linux -isystem prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//x86_64-linux/include/c++/4.8/backward -target x86_64-linux-gnu    -pedantic -Wcast-qual -Wno-long-long -Wno-sign-promo -Wall -Wno-unused-parameter -Wno-return-type -Werror -std=c++11 -O0 -DTARGET_BUILD_VARIANT=eng -DRS_VERSION=23 -D_GNU_SOURCE -D__STDC_LIMIT_MACROS -O2 -fomit-frame-pointer -Wall -W -Wno-unused-parameter -Wwrite-strings -Dsprintf=sprintf -pedantic -Wcast-qual -Wno-long-long -Wno-sign-promo -Wall -Wno-unused-parameter -Wno-return-type -Werror -std=c++11 -O0 -DTARGET_BUILD_VARIANT=eng -DRS_VERSION=23 -fno-exceptions -fpie -D_USING_LIBCXX   -Wno-sign-promo -fno-rtti -Woverloaded-virtual -Wno-sign-promo -std=c++11 -nostdinc++  -MD -MF out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.d -o out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.o frameworks/compile/slang/llvm-rs-cc.cpp && cp out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.d out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.P; sed -e 's/#.*//' -e 's/^[^:]*: *//' -e 's/ *\\$//' -e '/^$/ d' -e 's/$/ :/' < out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.d >> out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.P; rm -f out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.d"
-            )?,
-            (
-                Some("out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.d".into()),
-                "mkdir -p out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/ && echo \"host C++: llvm-rs-cc <= frameworks/compile/slang/llvm-rs-cc.cpp\" && prebuilts/clang/linux-x86/host/3.6/bin/clang++ -I external/llvm -I external/llvm/include -I external/llvm/host/include -I external/clang/include -I external/clang/lib/CodeGen -I frameworks/compile/libbcc/include -I out/host/linux-x86/gen/EXECUTABLES/llvm-rs-cc_intermediates/include -I external/libcxx/include -I frameworks/compile/slang -I out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates -I out/host/linux-x86/gen/EXECUTABLES/llvm-rs-cc_intermediates -I libnativehelper/include/nativehelper $(cat out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/import_includes) -isystem system/core/include -isystem hardware/libhardware/include -isystem hardware/libhardware_legacy/include -isystem hardware/ril/include -isystem libnativehelper/include -isystem frameworks/native/include -isystem frameworks/native/opengl/include -isystem frameworks/av/include -isystem frameworks/base/include -isystem tools/include -isystem out/host/linux-x86/obj/include -c    -fno-exceptions -Wno-multichar -m64 -Wa,--noexecstack -fPIC -no-canonical-prefixes -include build/core/combo/include/arch/linux-x86/AndroidConfig.h -U_FORTIFY_SOURCE -D_FORTIFY_SOURCE=0 -D__STDC_FORMAT_MACROS -D__STDC_CONSTANT_MACROS -DANDROID -fmessage-length=0 -W -Wall -Wno-unused -Winit-self -Wpointer-arith -O2 -g -fno-strict-aliasing -DNDEBUG -UDEBUG  -D__compiler_offsetof=__builtin_offsetof -Werror=int-conversion -Wno-unused-command-line-argument   --gcc-toolchain=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8/    --gcc-toolchain=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8/ --sysroot=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//sysroot -target x86_64-linux-gnu   -DANDROID -fmessage-length=0 -W -Wall -Wno-unused -Winit-self -Wpointer-arith -Wsign-promo -std=gnu++11 -DNDEBUG -UDEBUG  -Wno-inconsistent-missing-override   --gcc-toolchain=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8/ --sysroot=prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//sysroot -isystem prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//x86_64-linux/include/c++/4.8 -isystem prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//x86_64-linux/include/c++/4.8/x86_64-linux -isystem prebuilts/gcc/linux-x86/host/x86_64-linux-glibc2.15-4.8//x86_64-linux/include/c++/4.8/backward -target x86_64-linux-gnu    -pedantic -Wcast-qual -Wno-long-long -Wno-sign-promo -Wall -Wno-unused-parameter -Wno-return-type -Werror -std=c++11 -O0 -DTARGET_BUILD_VARIANT=eng -DRS_VERSION=23 -D_GNU_SOURCE -D__STDC_LIMIT_MACROS -O2 -fomit-frame-pointer -Wall -W -Wno-unused-parameter -Wwrite-strings -Dsprintf=sprintf -pedantic -Wcast-qual -Wno-long-long -Wno-sign-promo -Wall -Wno-unused-parameter -Wno-return-type -Werror -std=c++11 -O0 -DTARGET_BUILD_VARIANT=eng -DRS_VERSION=23 -fno-exceptions -fpie -D_USING_LIBCXX   -Wno-sign-promo -fno-rtti -Woverloaded-virtual -Wno-sign-promo -std=c++11 -nostdinc++  -MD -MF out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.d -o out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.o frameworks/compile/slang/llvm-rs-cc.cpp && cp out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.d out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.P; sed -e 's/#.*//' -e 's/^[^:]*: *//' -e 's/ *\\$//' -e '/^$/ d' -e 's/$/ :/' < out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.d >> out/host/linux-x86/obj/EXECUTABLES/llvm-rs-cc_intermediates/llvm-rs-cc.P".into()
-            )
-        );
+    fn test_split_shell_commands() {
+        let split = |cmd: &str| {
+            split_shell_commands(cmd.as_bytes())
+                .into_iter()
+                .map(|c| String::from_utf8(c.to_vec()).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(split("gcc -c a.c"), vec!["gcc -c a.c"]);
         assert_eq!(
-            get_depfile(
-                "echo \"target asm: libsonivox <= external/sonivox/arm-wt-22k/lib_src/ARM-E_filter_gnu.s\" && mkdir -p out/target/product/generic/obj/SHARED_LIBRARIES/libsonivox_intermediates/lib_src/ && prebuilts/gcc/linux-x86/arm/arm-linux-androideabi-4.9/bin/arm-linux-androideabi-gcc -I external/sonivox/arm-wt-22k/host_src -I external/sonivox/arm-wt-22k/lib_src -I external/libcxx/include -I external/sonivox/arm-wt-22k -I out/target/product/generic/obj/SHARED_LIBRARIES/libsonivox_intermediates -I out/target/product/generic/gen/SHARED_LIBRARIES/libsonivox_intermediates -I libnativehelper/include/nativehelper $$(cat out/target/product/generic/obj/SHARED_LIBRARIES/libsonivox_intermediates/import_includes) -isystem system/core/include -isystem hardware/libhardware/include -isystem hardware/libhardware_legacy/include -isystem hardware/ril/include -isystem libnativehelper/include -isystem frameworks/native/include -isystem frameworks/native/opengl/include -isystem frameworks/av/include -isystem frameworks/base/include -isystem out/target/product/generic/obj/include -isystem bionic/libc/arch-arm/include -isystem bionic/libc/include -isystem bionic/libc/kernel/uapi -isystem bionic/libc/kernel/uapi/asm-arm -isystem bionic/libm/include -isystem bionic/libm/include/arm -c  -fno-exceptions -Wno-multichar -msoft-float -ffunction-sections -fdata-sections -funwind-tables -fstack-protector -Wa,--noexecstack -Werror=format-security -D_FORTIFY_SOURCE=2 -fno-short-enums -no-canonical-prefixes -fno-canonical-system-headers -march=armv7-a -mfloat-abi=softfp -mfpu=vfpv3-d16 -include build/core/combo/include/arch/linux-arm/AndroidConfig.h -I build/core/combo/include/arch/linux-arm/ -fno-builtin-sin -fno-strict-volatile-bitfields -Wno-psabi -mthumb-interwork -DANDROID -fmessage-length=0 -W -Wall -Wno-unused -Winit-self -Wpointer-arith -Werror=return-type -Werror=non-virtual-dtor -Werror=address -Werror=sequence-point -DNDEBUG -g -Wstrict-aliasing=2 -fgcse-after-reload -frerun-cse-after-loop -frename-registers -DNDEBUG -UDEBUG     -Wa,\"-I\" -Wa,\"external/sonivox/arm-wt-22k/lib_src\" -Wa,\"--defsym\" -Wa,\"SAMPLE_RATE_22050=1\" -Wa,\"--defsym\" -Wa,\"STEREO_OUTPUT=1\" -Wa,\"--defsym\" -Wa,\"FILTER_ENABLED=1\" -Wa,\"--defsym\" -Wa,\"SAMPLES_8_BIT=1\"   -D__ASSEMBLY__ -MD -MF out/target/product/generic/obj/SHARED_LIBRARIES/libsonivox_intermediates/lib_src/ARM-E_filter_gnu.d -o out/target/product/generic/obj/SHARED_LIBRARIES/libsonivox_intermediates/lib_src/ARM-E_filter_gnu.o external/sonivox/arm-wt-22k/lib_src/ARM-E_filter_gnu.s"
-            )?,
-            None
+            split("mkdir -p out && (gcc -c a.c ; true ) | tee log"),
+            vec!["mkdir -p out", "gcc -c a.c", "true", "tee log"]
         );
         assert_eq!(
-            get_depfile(
-                "echo \"RenderScript: Galaxy4 <= packages/wallpapers/Galaxy4/src/com/android/galaxy4/galaxy.rs\" && rm -rf out/target/common/obj/APPS/Galaxy4_intermediates/src/renderscript && mkdir -p out/target/common/obj/APPS/Galaxy4_intermediates/src/renderscript/res/raw && mkdir -p out/target/common/obj/APPS/Galaxy4_intermediates/src/renderscript/src && out/host/linux-x86/bin/llvm-rs-cc -o out/target/common/obj/APPS/Galaxy4_intermediates/src/renderscript/res/raw -p out/target/common/obj/APPS/Galaxy4_intermediates/src/renderscript/src -d out/target/common/obj/APPS/Galaxy4_intermediates/src/renderscript -a out/target/common/obj/APPS/Galaxy4_intermediates/src/RenderScript.stamp -MD -target-api 14 -Wall -Werror  -I prebuilts/sdk/renderscript/clang-include -I prebuilts/sdk/renderscript/include packages/wallpapers/Galaxy4/src/com/android/galaxy4/galaxy.rs && mkdir -p out/target/common/obj/APPS/Galaxy4_intermediates/src/ && touch out/target/common/obj/APPS/Galaxy4_intermediates/src/RenderScript.stamp"
-            )?,
-            None
+            split("echo 'a;b' \\; echo \"&&\""),
+            vec!["echo 'a;b' \\; echo \"&&\""]
         );
+    }
+
+    #[test]
+    fn test_translate_command() {
         assert_eq!(
-            get_depfile_and_cmd(
-                "(echo \"bc: libclcore.bc <= frameworks/rs/driver/runtime/arch/generic.c\") && (mkdir -p out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/) && (prebuilts/clang/linux-x86/host/3.6/bin/clang -Iframeworks/rs/scriptc -Iexternal/clang/lib/Headers -MD -DRS_VERSION=23 -std=c99 -c -O3 -fno-builtin -emit-llvm -target armv7-none-linux-gnueabi -fsigned-char   -Iframeworks/rs/cpu_ref -DRS_DECLARE_EXPIRED_APIS -Xclang -target-feature -Xclang +long64  frameworks/rs/driver/runtime/arch/generic.c -o out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.bc) && (cp out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.d out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.P; sed -e 's/#.*//' -e 's/^[^:]*: *//' -e 's/ *\\$$//' -e '/^$$/ d' -e 's/$$/ :/' < out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.d >> out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.P; rm -f out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.d)"
-            )?,
-            (
-                Some("out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.d".into()),
-                "(echo \"bc: libclcore.bc <= frameworks/rs/driver/runtime/arch/generic.c\") && (mkdir -p out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/) && (prebuilts/clang/linux-x86/host/3.6/bin/clang -Iframeworks/rs/scriptc -Iexternal/clang/lib/Headers -MD -DRS_VERSION=23 -std=c99 -c -O3 -fno-builtin -emit-llvm -target armv7-none-linux-gnueabi -fsigned-char   -Iframeworks/rs/cpu_ref -DRS_DECLARE_EXPIRED_APIS -Xclang -target-feature -Xclang +long64  frameworks/rs/driver/runtime/arch/generic.c -o out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.bc) && (cp out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.d out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.P; sed -e 's/#.*//' -e 's/^[^:]*: *//' -e 's/ *\\$$//' -e '/^$$/ d' -e 's/$$/ :/' < out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.d >> out/target/product/generic/obj/SHARED_LIBRARIES/libclcore.bc_intermediates/arch/generic.P)".into()
-            )
+            NinjaGenerator::translate_command(Bytes::from_static(b"echo Hello")),
+            Bytes::from_static(b"echo Hello")
         );
-        assert_eq!(get_depfile("gcc -c foo.P.c")?, None);
-        assert_eq!(get_depfile("gcc -MMD foo.o -o foo")?, None);
-        // TODO: Fix for automake.
-        // assert_eq!(get_depfile("(/bin/sh ./libtool  --tag=CXX   --mode=compile g++ -DHAVE_CONFIG_H -I. -I./src -I./src     -Wall -Wwrite-strings -Woverloaded-virtual -Wno-sign-compare  -DNO_FRAME_POINTER  -DNDEBUG -g -O2 -MT libglog_la-logging.lo -MD -MP -MF .deps/libglog_la-logging.Tpo -c -o libglog_la-logging.lo `test -f 'src/logging.cc' || echo './'`src/logging.cc) && (mv -f .deps/libglog_la-logging.Tpo .deps/libglog_la-logging.Plo)")?, Some(".deps/libglog_la-logging.Plo".into()));
-        // assert_eq!(get_depfile("(g++ -DHAVE_CONFIG_H -I. -I./src  -I./src  -pthread     -Wall -Wwrite-strings -Woverloaded-virtual -Wno-sign-compare  -DNO_FRAME_POINTER  -g -O2 -MT signalhandler_unittest-signalhandler_unittest.o -MD -MP -MF .deps/signalhandler_unittest-signalhandler_unittest.Tpo -c -o signalhandler_unittest-signalhandler_unittest.o `test -f 'src/signalhandler_unittest.cc' || echo './'`src/signalhandler_unittest.cc) && (mv -f .deps/signalhandler_unittest-signalhandler_unittest.Tpo .deps/signalhandler_unittest-signalhandler_unittest.Po)")?, Some(".deps/signalhandler_unittest-signalhandler_unittest.Po".into()));
-        Ok(())
     }
 }
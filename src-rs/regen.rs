@@ -16,24 +16,46 @@ limitations under the License.
 
 use crate::{
     collect_stats, collect_stats_with_slow_report,
-    fileutil::{glob, run_command},
+    fileutil::{glob, hash_file, run_command},
     flags::FLAGS,
     func::CommandOp,
-    io::{dump_systemtime, load_int, load_string, load_systemtime, load_usize, load_vec_string},
-    ninja::{get_ninja_filename, get_ninja_shell_script_filename, get_ninja_stamp_filename},
+    io::{
+        STAMP_GEN_TIME_OFFSET, dump_systemtime, load_int, load_stamp_header, load_stamp_records,
+        load_string, load_usize, load_vec_string,
+    },
+    ninja::{
+        get_ninja_filename, get_ninja_shard_filename, get_ninja_shell_script_filename,
+        get_ninja_stamp_filename,
+    },
     strutil::format_for_command_substitution,
 };
 use anyhow::Result;
 use bytes::Bytes;
 use parking_lot::Mutex;
+use serde_json::{Value, json};
 use std::{
     ffi::{OsStr, OsString},
     fs::OpenOptions,
-    io::{BufReader, Write},
+    io::{BufReader, Seek, SeekFrom, Write},
     os::unix::ffi::{OsStrExt, OsStringExt},
-    time::SystemTime,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
+/// Whether changes to the environment variable `name` should not cause a
+/// regeneration, per --regen_ignore_env and --regen_track_env.
+pub fn should_ignore_env(name: &[u8]) -> bool {
+    let Some(ignore) = &FLAGS.regen_ignore_env_pattern else {
+        return false;
+    };
+    ignore.matches(name)
+        && !FLAGS
+            .regen_track_env_pattern
+            .as_ref()
+            .map(|p| p.matches(name))
+            .unwrap_or(false)
+}
+
 fn should_ignore_dirty(s: &[u8]) -> bool {
     let Some(ignore) = &FLAGS.ignore_dirty_pattern else {
         return false;
@@ -67,20 +89,32 @@ struct StampChecker {
     globs: Vec<GlobResult>,
     commands: Vec<ShellResult>,
     needs_regen: bool,
+    // Why regeneration is needed, for --regen_explain and --incremental_eval.
+    reasons: Mutex<Vec<Value>>,
 }
 
 macro_rules! load {
-    ($v:expr) => {
+    ($self:ident, $v:expr) => {
         match $v {
             Some(s) => s,
             None => {
                 eprintln!("incomplete kati_stamp, regenerating...");
+                $self.add_reason(json!({"type": "stamp", "error": "incomplete"}));
                 return true;
             }
         }
     };
 }
 
+fn json_time(t: &SystemTime) -> Value {
+    t.duration_since(UNIX_EPOCH)
+        .map_or(Value::Null, |d| json!(d.as_secs_f64()))
+}
+
+fn json_str(s: impl AsRef<[u8]>) -> Value {
+    json!(String::from_utf8_lossy(s.as_ref()))
+}
+
 impl StampChecker {
     fn new() -> Self {
         Self {
@@ -88,11 +122,52 @@ impl StampChecker {
             globs: Vec::new(),
             commands: Vec::new(),
             needs_regen: false,
+            reasons: Mutex::new(Vec::new()),
         }
     }
 
+    // With --regen_explain, every check runs instead of stopping at the
+    // first reason to regenerate. --incremental_eval needs every reason too,
+    // to tell which of its checkpoints are still valid.
+    fn explaining() -> bool {
+        FLAGS.regen_explain.is_some() || FLAGS.incremental_eval
+    }
+
+    fn add_reason(&self, reason: Value) {
+        self.reasons.lock().push(reason);
+    }
+
     fn needs_regen(&mut self, start_time: SystemTime, orig_args: &OsStr) -> bool {
-        if Self::is_missing_outputs() {
+        let needs_regen = self.check(start_time, orig_args);
+        if let Some(filename) = &FLAGS.regen_explain
+            && let Err(err) = self.write_explanation(filename, start_time, needs_regen)
+        {
+            eprintln!("*** {}: {err}", filename.to_string_lossy());
+        }
+        needs_regen
+    }
+
+    fn write_explanation(
+        &self,
+        filename: &OsStr,
+        start_time: SystemTime,
+        needs_regen: bool,
+    ) -> Result<()> {
+        let report = json!({
+            "stamp": get_ninja_stamp_filename().to_string_lossy(),
+            "generated": self.gen_time.as_ref().map_or(Value::Null, json_time),
+            "checked": json_time(&start_time),
+            "needs_regen": needs_regen,
+            "reasons": *self.reasons.lock(),
+        });
+        let mut out = std::fs::File::create(filename)?;
+        serde_json::to_writer_pretty(&mut out, &report)?;
+        writeln!(out)?;
+        Ok(())
+    }
+
+    fn check(&mut self, start_time: SystemTime, orig_args: &OsStr) -> bool {
+        if self.is_missing_outputs() && !Self::explaining() {
             return true;
         }
 
@@ -104,28 +179,40 @@ impl StampChecker {
             return true;
         }
 
+        if !self.reasons.lock().is_empty() {
+            return true;
+        }
+
         if !self.needs_regen {
             let mut opts = OpenOptions::new();
             let Ok(mut fp) = opts.write(true).open(get_ninja_stamp_filename()) else {
                 return true;
             };
+            if fp.seek(SeekFrom::Start(STAMP_GEN_TIME_OFFSET)).is_err() {
+                return true;
+            }
             dump_systemtime(&mut fp, &start_time).unwrap();
         }
         self.needs_regen
     }
 
-    fn is_missing_outputs() -> bool {
-        let f = get_ninja_filename();
-        if !std::fs::exists(&f).is_ok_and(|b| b) {
-            eprintln!("{} is missing, regenerating...", f.to_string_lossy());
-            return true;
-        }
-        let f = get_ninja_shell_script_filename();
-        if !std::fs::exists(&f).is_ok_and(|b| b) {
-            eprintln!("{} is missing, regenerating...", f.to_string_lossy());
-            return true;
+    fn is_missing_outputs(&self) -> bool {
+        let outputs = [get_ninja_filename(), get_ninja_shell_script_filename()]
+            .into_iter()
+            .chain((0..FLAGS.ninja_shards).map(get_ninja_shard_filename))
+            .chain(FLAGS.gen_compdb.clone());
+        let mut missing = false;
+        for f in outputs {
+            if !std::fs::exists(&f).is_ok_and(|b| b) {
+                eprintln!("{} is missing, regenerating...", f.to_string_lossy());
+                self.add_reason(json!({"type": "missing_output", "file": f.to_string_lossy()}));
+                missing = true;
+                if !Self::explaining() {
+                    break;
+                }
+            }
         }
-        false
+        missing
     }
 
     fn check_step1(&mut self, orig_args: &OsStr) -> bool {
@@ -136,26 +223,59 @@ impl StampChecker {
                 if FLAGS.regen_debug {
                     println!("{stamp_filename:?}: {err}")
                 }
+                self.add_reason(json!({"type": "stamp", "error": err.to_string()}));
                 return true;
             }
         };
         let mut fp = BufReader::new(fp);
-        let fp = &mut fp;
 
-        let gen_time = load!(load_systemtime(fp));
+        let gen_time = match load_stamp_header(&mut fp, FLAGS.regen_ignoring_kati_binary) {
+            Ok((gen_time, _)) => gen_time,
+            Err(reason) => {
+                eprintln!(
+                    "{}: {reason}, regenerating...",
+                    stamp_filename.to_string_lossy()
+                );
+                self.add_reason(json!({"type": "stamp", "error": reason}));
+                return true;
+            }
+        };
+        let records = match load_stamp_records(&mut fp) {
+            Ok(records) => records,
+            Err(reason) => {
+                eprintln!(
+                    "{}: {reason}, regenerating...",
+                    stamp_filename.to_string_lossy()
+                );
+                self.add_reason(json!({"type": "stamp", "error": reason}));
+                return true;
+            }
+        };
+        let fp = &mut &records[..];
         self.gen_time = Some(gen_time);
         if FLAGS.regen_debug {
             println!("Generated time: {:?}", self.gen_time);
         }
 
-        let files = load!(load_vec_string(fp));
-        for s in files {
+        let files = load!(self, load_vec_string(fp));
+        let hashes = load!(self, load_vec_string(fp));
+        for (i, s) in files.into_iter().enumerate() {
             let ts = std::fs::metadata(&s).and_then(|m| m.modified());
             if ts.as_ref().is_ok_and(|ts| gen_time >= *ts) {
                 if FLAGS.dump_kati_stamp {
                     println!("file {s:?}: clean ({:?})", ts.unwrap())
                 }
             } else {
+                if ts.is_ok()
+                    && let Some(hash) = hashes.get(i)
+                    && !hash.is_empty()
+                    && hash_file(&s).is_ok_and(|h| h[..] == *hash.as_bytes())
+                {
+                    if FLAGS.regen_debug {
+                        println!("file {s:?}: clean (same content)");
+                    }
+                    continue;
+                }
                 if FLAGS.regen_ignoring_kati_binary && s == std::env::current_exe().unwrap() {
                     eprintln!("{s:?} was modified, ignored.");
                     continue;
@@ -167,16 +287,29 @@ impl StampChecker {
                     continue;
                 }
                 if FLAGS.dump_kati_stamp {
-                    println!("file {s:?}: dirty ({:?})", ts.unwrap());
+                    println!("file {s:?}: dirty ({:?})", ts.as_ref().unwrap());
                 } else {
                     eprintln!("{} was modified, regenerating...", s.to_string_lossy());
                 }
-                return true;
+                self.add_reason(json!({
+                    "type": "file",
+                    "file": s.to_string_lossy(),
+                    "mtime": ts.as_ref().map_or(Value::Null, json_time),
+                }));
+                if !Self::explaining() {
+                    return true;
+                }
             }
         }
 
-        let undefineds = load!(load_vec_string(fp));
+        let undefineds = load!(self, load_vec_string(fp));
         for s in undefineds {
+            if should_ignore_env(s.as_bytes()) {
+                if FLAGS.regen_debug {
+                    println!("env {s:?}: ignored");
+                }
+                continue;
+            }
             if let Ok(v) = std::env::var(&s) {
                 if FLAGS.dump_kati_stamp {
                     println!("env {s:?}: dirty (unset => {v:?})");
@@ -186,17 +319,31 @@ impl StampChecker {
                         s.to_string_lossy()
                     );
                 }
-                return true;
+                self.add_reason(json!({
+                    "type": "env",
+                    "name": s.to_string_lossy(),
+                    "old": null,
+                    "new": v,
+                }));
+                if !Self::explaining() {
+                    return true;
+                }
             } else if FLAGS.dump_kati_stamp {
                 println!("env {s:?}: clean (unset)");
             }
         }
 
-        let num_envs = load!(load_usize(fp));
+        let num_envs = load!(self, load_usize(fp));
         for _ in 0..num_envs {
-            let s = load!(load_string(fp));
+            let s = load!(self, load_string(fp));
             let val = std::env::var_os(&s).unwrap_or_default();
-            let s2 = load!(load_string(fp));
+            let s2 = load!(self, load_string(fp));
+            if should_ignore_env(s.as_bytes()) {
+                if FLAGS.regen_debug {
+                    println!("env {s:?}: ignored");
+                }
+                continue;
+            }
             if val != s2 {
                 if FLAGS.dump_kati_stamp {
                     println!("env {s:?}: dirty ({s2:?} => {val:?})")
@@ -208,16 +355,24 @@ impl StampChecker {
                         val.to_string_lossy(),
                     );
                 }
-                return true;
+                self.add_reason(json!({
+                    "type": "env",
+                    "name": s.to_string_lossy(),
+                    "old": s2.to_string_lossy(),
+                    "new": val.to_string_lossy(),
+                }));
+                if !Self::explaining() {
+                    return true;
+                }
             } else if FLAGS.dump_kati_stamp {
                 println!("env {s:?}: clean ({val:?})")
             }
         }
 
-        let num_globs = load!(load_usize(fp));
+        let num_globs = load!(self, load_usize(fp));
         for _ in 0..num_globs {
-            let pat = load!(load_string(fp));
-            let result = load!(load_vec_string(fp));
+            let pat = load!(self, load_string(fp));
+            let result = load!(self, load_vec_string(fp));
             self.globs.push(GlobResult {
                 pat: Bytes::from(pat.into_vec()),
                 result: result
@@ -227,13 +382,13 @@ impl StampChecker {
             })
         }
 
-        let num_crs = load!(load_usize(fp));
+        let num_crs = load!(self, load_usize(fp));
         for _ in 0..num_crs {
-            let op = load!(load_int(fp).and_then(CommandOp::from_int));
-            let shell = load!(load_string(fp));
-            let shellflag = load!(load_string(fp));
-            let cmd = load!(load_string(fp));
-            let result = load!(load_string(fp));
+            let op = load!(self, load_int(fp).and_then(CommandOp::from_int));
+            let shell = load!(self, load_string(fp));
+            let shellflag = load!(self, load_string(fp));
+            let cmd = load!(self, load_string(fp));
+            let result = load!(self, load_string(fp));
             let mut sr = ShellResult {
                 op,
                 shell,
@@ -246,27 +401,34 @@ impl StampChecker {
             };
 
             // Ignore debug info
-            load!(load_string(fp));
-            load!(load_int(fp));
+            load!(self, load_string(fp));
+            load!(self, load_int(fp));
 
             if op == CommandOp::Find {
-                sr.missing_dirs = load!(load_vec_string(fp));
-                sr.files = load!(load_vec_string(fp));
-                sr.read_dirs = load!(load_vec_string(fp));
+                sr.missing_dirs = load!(self, load_vec_string(fp));
+                sr.files = load!(self, load_vec_string(fp));
+                sr.read_dirs = load!(self, load_vec_string(fp));
             }
             self.commands.push(sr);
         }
 
-        let s = load!(load_string(fp));
+        let s = load!(self, load_string(fp));
         if orig_args != s {
             eprintln!("arguments changed, regenerating...");
-            return true;
+            self.add_reason(json!({
+                "type": "args",
+                "old": s.to_string_lossy(),
+                "new": orig_args.to_string_lossy(),
+            }));
+            if !Self::explaining() {
+                return true;
+            }
         }
 
         self.needs_regen
     }
 
-    fn check_glob_result(gr: &GlobResult, err: &mut String) -> bool {
+    fn check_glob_result(&self, gr: &GlobResult, err: &mut String) -> bool {
         collect_stats!("glob time (regen)");
         let files = glob(gr.pat.clone());
         let needs_regen = if let Ok(files) = files.as_ref() {
@@ -289,6 +451,12 @@ impl StampChecker {
                     String::from_utf8_lossy(&gr.pat)
                 );
             }
+            self.add_reason(json!({
+                "type": "glob",
+                "pattern": json_str(&gr.pat),
+                "old": gr.result.iter().map(json_str).collect::<Vec<_>>(),
+                "new": files.as_ref().as_ref().ok().map(|files| files.iter().map(json_str).collect::<Vec<_>>()),
+            }));
         } else if FLAGS.dump_kati_stamp {
             println!("wildcard {:?}: clean", gr.pat);
         }
@@ -340,6 +508,7 @@ impl StampChecker {
     }
 
     fn check_shell_result(
+        &self,
         sr: &ShellResult,
         gen_time: SystemTime,
         err: &mut String,
@@ -354,6 +523,14 @@ impl StampChecker {
                         sr.cmd.to_string_lossy()
                     );
                 }
+                self.add_reason(json!({
+                    "type": "read",
+                    "file": sr.cmd.to_string_lossy(),
+                    "mtime": std::fs::metadata(&sr.cmd)
+                        .and_then(|md| md.modified())
+                        .as_ref()
+                        .map_or(Value::Null, json_time),
+                }));
                 return Ok(true);
             }
             if FLAGS.dump_kati_stamp {
@@ -364,7 +541,7 @@ impl StampChecker {
 
         if sr.op == CommandOp::Read {
             let ts = std::fs::metadata(&sr.cmd).and_then(|md| md.modified());
-            if ts.is_ok_and(|ts| gen_time < ts) {
+            if ts.as_ref().is_ok_and(|ts| gen_time < *ts) {
                 if FLAGS.dump_kati_stamp {
                     println!("file {:?}: dirty", sr.cmd);
                 } else {
@@ -373,6 +550,11 @@ impl StampChecker {
                         sr.cmd.to_string_lossy()
                     );
                 }
+                self.add_reason(json!({
+                    "type": "read",
+                    "file": sr.cmd.to_string_lossy(),
+                    "mtime": ts.as_ref().map_or(Value::Null, json_time),
+                }));
                 return Ok(true);
             }
             if FLAGS.dump_kati_stamp {
@@ -429,6 +611,12 @@ impl StampChecker {
                     sr.cmd.to_string_lossy()
                 );
             }
+            self.add_reason(json!({
+                "type": if sr.op == CommandOp::Find { "find" } else { "shell" },
+                "command": sr.cmd.to_string_lossy(),
+                "old": json_str(&sr.result),
+                "new": json_str(&output),
+            }));
             return Ok(true);
         } else if FLAGS.regen_debug {
             println!("shell {:?}: clean (rerun)", sr.cmd);
@@ -436,45 +624,112 @@ impl StampChecker {
         Ok(false)
     }
 
+    /// Checks `commands` on up to `FLAGS.num_cpus` threads. Once one of them
+    /// reports a change, `stop` is set and the remaining ones are skipped,
+    /// unless every reason is being collected for `--regen_explain`.
+    fn check_shell_results(
+        &self,
+        commands: &[&ShellResult],
+        needs_regen: &Mutex<Result<bool>>,
+        stop: &AtomicBool,
+    ) {
+        let gen_time = self.gen_time.unwrap();
+        let next = AtomicUsize::new(0);
+        let num_threads = FLAGS.num_cpus.clamp(1, commands.len().max(1));
+        std::thread::scope(|s| {
+            for _ in 0..num_threads {
+                s.spawn(|| {
+                    let mut err = String::new();
+                    while !stop.load(Ordering::Relaxed) {
+                        let Some(sr) = commands.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                            break;
+                        };
+                        let start = Instant::now();
+                        let result = {
+                            collect_stats_with_slow_report!("regen check time", &sr.cmd);
+                            self.check_shell_result(sr, gen_time, &mut err)
+                        };
+                        if FLAGS.regen_debug {
+                            println!(
+                                "shell {:?}: checked in {:.3}s",
+                                sr.cmd,
+                                start.elapsed().as_secs_f64()
+                            );
+                        }
+                        match result {
+                            Ok(true) => {
+                                let mut needs_regen = needs_regen.lock();
+                                if let Ok(false) = *needs_regen {
+                                    *needs_regen = Ok(true);
+                                    eprintln!("{err}");
+                                }
+                            }
+                            Ok(false) => continue,
+                            Err(e) => {
+                                self.add_reason(json!({
+                                    "type": "error",
+                                    "command": sr.cmd.to_string_lossy(),
+                                    "error": e.to_string(),
+                                }));
+                                let mut needs_regen = needs_regen.lock();
+                                if let Ok(false) = *needs_regen {
+                                    *needs_regen = Err(e);
+                                }
+                            }
+                        }
+                        if !Self::explaining() {
+                            stop.store(true, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+    }
+
     fn check_step2(&mut self) -> Result<bool> {
         let needs_regen: Mutex<Result<bool>> = Mutex::new(Ok(false));
+        let stop = AtomicBool::new(false);
 
         std::thread::scope(|s| {
             s.spawn(|| {
                 let mut err = String::new();
                 for gr in &self.globs {
-                    if Self::check_glob_result(gr, &mut err) {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    if self.check_glob_result(gr, &mut err) {
                         let mut needs_regen = needs_regen.lock();
                         if let Ok(false) = *needs_regen {
                             *needs_regen = Ok(true);
                             eprintln!("{err}");
                         }
-                        break;
+                        if !Self::explaining() {
+                            stop.store(true, Ordering::Relaxed);
+                        }
                     }
                 }
             });
             s.spawn(|| {
-                let mut err = String::new();
+                let mut pending = Vec::new();
                 for sr in &self.commands {
-                    match Self::check_shell_result(sr, self.gen_time.unwrap(), &mut err) {
-                        Ok(true) => {
-                            let mut needs_regen = needs_regen.lock();
-                            if let Ok(false) = *needs_regen {
-                                *needs_regen = Ok(true);
-                                eprintln!("{err}");
-                            }
-                            break;
-                        }
-                        Ok(false) => {}
-                        Err(e) => {
-                            let mut needs_regen = needs_regen.lock();
-                            if let Ok(false) = *needs_regen {
-                                *needs_regen = Err(e);
-                            }
-                            break;
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    // $(file >) and $(file >>) may feed the commands that
+                    // follow them, so they are replayed in order.
+                    if sr.op == CommandOp::Write || sr.op == CommandOp::Append {
+                        self.check_shell_results(&pending, &needs_regen, &stop);
+                        pending.clear();
+                        if !stop.load(Ordering::Relaxed) {
+                            self.check_shell_results(&[sr], &needs_regen, &stop);
                         }
+                    } else {
+                        pending.push(sr);
                     }
                 }
+                if !stop.load(Ordering::Relaxed) {
+                    self.check_shell_results(&pending, &needs_regen, &stop);
+                }
             });
         });
 
@@ -485,3 +740,11 @@ impl StampChecker {
 pub fn needs_regen(start_time: SystemTime, orig_args: &OsStr) -> bool {
     StampChecker::new().needs_regen(start_time, orig_args)
 }
+
+/// Like `needs_regen`, but also returns every reason to regenerate, in the
+/// format of --regen_explain.
+pub fn needs_regen_with_reasons(start_time: SystemTime, orig_args: &OsStr) -> (bool, Vec<Value>) {
+    let mut checker = StampChecker::new();
+    let needs_regen = checker.needs_regen(start_time, orig_args);
+    (needs_regen, checker.reasons.into_inner())
+}
@@ -14,19 +14,29 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
-use std::{io::BufReader, os::unix::ffi::OsStrExt};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::{OsStr, OsString},
+    io::BufReader,
+    os::unix::ffi::OsStrExt,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{Context, Result};
+use serde_json::{Value, json};
 
 use crate::{
     func::CommandOp,
-    io::{load_int, load_string, load_systemtime, load_usize, load_vec_string},
+    io::{
+        load_int, load_stamp_header, load_stamp_records, load_string, load_usize, load_vec_string,
+    },
 };
 
 // This command will dump the contents of a kati stamp file into a more portable
-// format for use by other tools. For now, it just exports the files read.
-// Later, this will be expanded to include the Glob and Shell commands, but
-// those require a more complicated output format.
+// format for use by other tools: the ad hoc text format below, JSON with
+// --json, or the differences between two stamps with --diff.
+
+const USAGE: &str = "Usage: rkati --dump_stamp_tool [--env] [--files] [--globs] [--cmds] [--finds] [--json] <stamp>\n       rkati --dump_stamp_tool [--json] --diff <stampA> <stampB>";
 
 pub fn stamp_dump_main() -> Result<()> {
     let mut dump_files = false;
@@ -34,163 +44,484 @@ pub fn stamp_dump_main() -> Result<()> {
     let mut dump_globs = false;
     let mut dump_cmds = false;
     let mut dump_finds = false;
+    let mut json = false;
+    let mut diff = false;
+    let mut stamps = Vec::new();
 
-    let args = std::env::args().skip(2).collect::<Vec<String>>();
-    if args.is_empty() {
-        anyhow::bail!(
-            "Usage: rkati --dump_stamp_tool [--env] [--files] [--globs] [--cmds] [--finds] <stamp>"
-        );
-    }
-
-    for arg in args[..args.len() - 1].iter() {
+    for arg in std::env::args().skip(2) {
         match arg.as_str() {
             "--env" => dump_env = true,
             "--files" => dump_files = true,
             "--globs" => dump_globs = true,
             "--cmds" => dump_cmds = true,
             "--finds" => dump_finds = true,
-            _ => {
+            "--json" => json = true,
+            "--diff" => diff = true,
+            _ if arg.starts_with("--") => {
                 anyhow::bail!("Unknown option: {}", arg);
             }
+            _ => stamps.push(arg),
+        }
+    }
+
+    if stamps.len() != if diff { 2 } else { 1 } {
+        anyhow::bail!("{USAGE}");
+    }
+
+    if diff {
+        let a = Stamp::load(&stamps[0])?;
+        let b = Stamp::load(&stamps[1])?;
+        let diffs = a.diff(&b);
+        if json {
+            println!("{}", serde_json::to_string_pretty(&diffs)?);
+        } else {
+            for d in &diffs {
+                let section = d["section"].as_str().unwrap();
+                let name = d["name"].as_str().unwrap();
+                let what = if name.is_empty() {
+                    section.to_string()
+                } else {
+                    format!("{section} {name}")
+                };
+                println!(
+                    "{what}: {} => {}",
+                    diff_value_text(&d["a"]),
+                    diff_value_text(&d["b"]),
+                );
+            }
         }
+        return Ok(());
+    }
+
+    let stamp = Stamp::load(&stamps[0])?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stamp.to_json())?);
+        return Ok(());
     }
 
     if !dump_files && !dump_env && !dump_globs && !dump_cmds && !dump_finds {
         dump_files = true;
     }
+    stamp.dump_text(dump_files, dump_env, dump_globs, dump_cmds, dump_finds);
 
-    let fp = std::fs::File::open(&args[args.len() - 1])?;
-    let mut fp = BufReader::new(fp);
+    Ok(())
+}
 
-    inner(
-        &mut fp, dump_files, dump_env, dump_globs, dump_cmds, dump_finds,
-    )
-    .context("Incomplete stamp file")?;
+pub(crate) struct StampFind {
+    pub(crate) missing_dirs: Vec<OsString>,
+    pub(crate) files: Vec<OsString>,
+    pub(crate) read_dirs: Vec<OsString>,
+}
 
-    Ok(())
+pub(crate) struct StampCommand {
+    pub(crate) op: CommandOp,
+    shell: OsString,
+    shellflag: OsString,
+    pub(crate) cmd: OsString,
+    result: OsString,
+    file: OsString,
+    line: i32,
+    pub(crate) find: Option<StampFind>,
 }
 
-fn inner(
-    fp: &mut impl std::io::Read,
-    dump_files: bool,
-    dump_env: bool,
-    dump_globs: bool,
-    dump_cmds: bool,
-    dump_finds: bool,
-) -> Option<()> {
-    let _gen_time = load_systemtime(fp)?;
-
-    //
-    // See regen.rs check_step1 for how this is read normally
-    //
-
-    {
-        let files = load_vec_string(fp)?;
-        if dump_files {
-            for file in files {
-                println!("{}", file.display());
-            }
+impl StampCommand {
+    fn type_name(&self) -> &'static str {
+        match self.op {
+            CommandOp::Shell => "SHELL",
+            CommandOp::Find => "FIND",
+            CommandOp::Read => "READ",
+            CommandOp::ReadMissing => "READ_MISSING",
+            CommandOp::Write => "WRITE",
+            CommandOp::Append => "APPEND",
         }
     }
 
-    {
-        let undefined = load_vec_string(fp)?;
-        if dump_env {
-            for var in undefined {
-                println!("undefined: {}", var.display());
-            }
-        }
+    fn loc(&self) -> String {
+        format!("{}:{}", self.file.display(), self.line)
     }
+}
 
-    let num_envs = load_usize(fp)?;
-    for _ in 0..num_envs {
-        let name = load_string(fp)?;
-        let value = load_string(fp)?;
-        if dump_env {
-            println!("{}: {}", name.display(), value.display());
-        }
+/// The parsed contents of a kati stamp.
+pub(crate) struct Stamp {
+    gen_time: SystemTime,
+    // The kati build that wrote the stamp.
+    writer: OsString,
+    pub(crate) files: Vec<OsString>,
+    // Empty unless the stamp was written with --regen_content_hash.
+    hashes: Vec<OsString>,
+    undefined: Vec<OsString>,
+    envs: Vec<(OsString, OsString)>,
+    pub(crate) globs: Vec<(OsString, Vec<OsString>)>,
+    pub(crate) commands: Vec<StampCommand>,
+    orig_args: OsString,
+}
+
+fn json_str(s: &OsStr) -> Value {
+    json!(s.to_string_lossy())
+}
+
+fn json_strs(v: &[OsString]) -> Value {
+    Value::Array(v.iter().map(|s| json_str(s)).collect())
+}
+
+fn hex(s: &OsStr) -> String {
+    s.as_bytes().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// Short command output is shown inline, anything else by its size.
+fn abbreviate_output(result: &OsStr) -> String {
+    if !result.is_empty() && result.len() < 500 && !result.as_bytes().contains(&b'\n') {
+        result.display().to_string()
+    } else {
+        format!("<{} bytes>", result.len())
+    }
+}
+
+fn diff_value_text(v: &Value) -> String {
+    match v {
+        Value::Null => "(none)".to_string(),
+        Value::String(s) => format!("{s:?}"),
+        _ => v.to_string(),
     }
+}
 
-    let num_globs = load_usize(fp)?;
-    for _ in 0..num_globs {
-        let pat = load_string(fp)?;
+impl Stamp {
+    pub(crate) fn load(filename: impl AsRef<OsStr>) -> Result<Stamp> {
+        let filename = filename.as_ref();
+        let fp = std::fs::File::open(filename).with_context(|| filename.display().to_string())?;
+        let mut fp = BufReader::new(fp);
+        let (gen_time, writer) = match load_stamp_header(&mut fp, true) {
+            Ok(header) => header,
+            Err(reason) => anyhow::bail!("Unusable stamp file {}: {reason}", filename.display()),
+        };
+        let records = match load_stamp_records(&mut fp) {
+            Ok(records) => records,
+            Err(reason) => anyhow::bail!("Corrupt stamp file {}: {reason}", filename.display()),
+        };
+
+        Self::load_records(&mut &records[..], gen_time, writer)
+            .with_context(|| format!("Incomplete stamp file {}", filename.display()))
+    }
+
+    fn load_records(
+        fp: &mut impl std::io::Read,
+        gen_time: SystemTime,
+        writer: OsString,
+    ) -> Option<Stamp> {
+        //
+        // See regen.rs check_step1 for how this is read normally
+        //
 
         let files = load_vec_string(fp)?;
-        if dump_globs {
-            println!("{}", pat.display());
+        let hashes = load_vec_string(fp)?;
+        let undefined = load_vec_string(fp)?;
 
-            for s in files {
-                println!("  {}", s.display());
+        let num_envs = load_usize(fp)?;
+        let mut envs = Vec::with_capacity(num_envs);
+        for _ in 0..num_envs {
+            let name = load_string(fp)?;
+            let value = load_string(fp)?;
+            envs.push((name, value));
+        }
+
+        let num_globs = load_usize(fp)?;
+        let mut globs = Vec::with_capacity(num_globs);
+        for _ in 0..num_globs {
+            let pat = load_string(fp)?;
+            let files = load_vec_string(fp)?;
+            globs.push((pat, files));
+        }
+
+        let num_cmds = load_usize(fp)?;
+        let mut commands = Vec::with_capacity(num_cmds);
+        for _ in 0..num_cmds {
+            let op = CommandOp::from_int(load_int(fp)?)?;
+            let shell = load_string(fp)?;
+            let shellflag = load_string(fp)?;
+            let cmd = load_string(fp)?;
+            let result = load_string(fp)?;
+            let file = load_string(fp)?;
+            let line = load_int(fp)?;
+            if line < 0 {
+                return None;
             }
+
+            let find = if op == CommandOp::Find {
+                Some(StampFind {
+                    missing_dirs: load_vec_string(fp)?,
+                    files: load_vec_string(fp)?,
+                    read_dirs: load_vec_string(fp)?,
+                })
+            } else {
+                None
+            };
+            commands.push(StampCommand {
+                op,
+                shell,
+                shellflag,
+                cmd,
+                result,
+                file,
+                line,
+                find,
+            });
         }
+
+        let orig_args = load_string(fp)?;
+
+        Some(Stamp {
+            gen_time,
+            writer,
+            files,
+            hashes,
+            undefined,
+            envs,
+            globs,
+            commands,
+            orig_args,
+        })
     }
 
-    let num_cmds = load_usize(fp)?;
-    for _ in 0..num_cmds {
-        let op = CommandOp::from_int(load_int(fp)?)?;
-        let shell = load_string(fp)?;
-        let shellflag = load_string(fp)?;
-        let cmd = load_string(fp)?;
-        let result = load_string(fp)?;
-        let file = load_string(fp)?;
-        let line = load_int(fp)?;
-        if line < 0 {
-            return None;
+    fn dump_text(
+        &self,
+        dump_files: bool,
+        dump_env: bool,
+        dump_globs: bool,
+        dump_cmds: bool,
+        dump_finds: bool,
+    ) {
+        if dump_files {
+            for file in &self.files {
+                println!("{}", file.display());
+            }
         }
 
-        if op == CommandOp::Find {
-            let missing_dirs = load_vec_string(fp)?;
-            let files = load_vec_string(fp)?;
-            let read_dirs = load_vec_string(fp)?;
+        if dump_env {
+            for var in &self.undefined {
+                println!("undefined: {}", var.display());
+            }
+            for (name, value) in &self.envs {
+                println!("{}: {}", name.display(), value.display());
+            }
+        }
 
-            if dump_finds {
-                println!("cmd type: FIND");
-                println!("  shell: {}", shell.display());
-                println!("  shell flagss: {}", shellflag.display());
-                println!("  loc: {}:{line}", file.display());
-                println!("  cmd: {}", cmd.display());
-                if !result.is_empty() && result.len() < 500 && !result.as_bytes().contains(&b'\n') {
-                    println!("  output: {}", result.display());
-                } else {
-                    println!("  output: <{} bytes>", result.len());
+        if dump_globs {
+            for (pat, files) in &self.globs {
+                println!("{}", pat.display());
+                for s in files {
+                    println!("  {}", s.display());
+                }
+            }
+        }
+
+        for c in &self.commands {
+            if let Some(find) = &c.find {
+                if !dump_finds {
+                    continue;
                 }
+                println!("cmd type: FIND");
+                println!("  shell: {}", c.shell.display());
+                println!("  shell flagss: {}", c.shellflag.display());
+                println!("  loc: {}", c.loc());
+                println!("  cmd: {}", c.cmd.display());
+                println!("  output: {}", abbreviate_output(&c.result));
                 println!("  missing dirs:");
-                for d in missing_dirs {
+                for d in &find.missing_dirs {
                     println!("    {}", d.display());
                 }
                 println!("  files:");
-                for f in files {
+                for f in &find.files {
                     println!("    {}", f.display());
                 }
                 println!("  read dirs:");
-                for d in read_dirs {
+                for d in &find.read_dirs {
                     println!("    {}", d.display());
                 }
                 println!();
+            } else if dump_cmds {
+                println!("cmd type: {}", c.type_name());
+                if c.op == CommandOp::Shell {
+                    println!("  shell: {}", c.shell.display());
+                    println!("  shell flagss: {}", c.shellflag.display());
+                }
+                println!("  loc: {}", c.loc());
+                println!("  cmd: {}", c.cmd.display());
+                println!("  output: {}", abbreviate_output(&c.result));
+                println!();
             }
-        } else if dump_cmds {
-            match op {
-                CommandOp::Shell => {
-                    println!("cmd type: SHELL");
-                    println!("  shell: {}", shell.display());
-                    println!("  shell flagss: {}", shellflag.display());
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let files: Vec<Value> = self
+            .files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| {
+                json!({
+                    "name": json_str(f),
+                    "hash": self.hashes.get(i).map_or(Value::Null, |h| json!(hex(h))),
+                })
+            })
+            .collect();
+        let envs: serde_json::Map<String, Value> = self
+            .envs
+            .iter()
+            .map(|(k, v)| (k.to_string_lossy().into_owned(), json_str(v)))
+            .collect();
+        let globs: Vec<Value> = self
+            .globs
+            .iter()
+            .map(|(pat, files)| json!({"pattern": json_str(pat), "files": json_strs(files)}))
+            .collect();
+        let commands: Vec<Value> = self
+            .commands
+            .iter()
+            .map(|c| {
+                let mut v = json!({
+                    "type": c.type_name(),
+                    "shell": json_str(&c.shell),
+                    "shellflag": json_str(&c.shellflag),
+                    "cmd": json_str(&c.cmd),
+                    "result": json_str(&c.result),
+                    "file": json_str(&c.file),
+                    "line": c.line,
+                });
+                if let Some(find) = &c.find {
+                    v["find"] = json!({
+                        "missing_dirs": json_strs(&find.missing_dirs),
+                        "files": json_strs(&find.files),
+                        "read_dirs": json_strs(&find.read_dirs),
+                    });
                 }
-                CommandOp::Read => println!("cmd type: READ"),
-                CommandOp::ReadMissing => println!("cmd type: READ_MISSING"),
-                CommandOp::Write => println!("cmd type: WRITE"),
-                CommandOp::Append => println!("cmd type: APPEND"),
-                CommandOp::Find => unreachable!(),
+                v
+            })
+            .collect();
+        json!({
+            "generated": self
+                .gen_time
+                .duration_since(UNIX_EPOCH)
+                .map_or(Value::Null, |d| json!(d.as_secs_f64())),
+            "writer": json_str(&self.writer),
+            "files": files,
+            "undefined_envs": json_strs(&self.undefined),
+            "envs": envs,
+            "globs": globs,
+            "commands": commands,
+            "args": json_str(&self.orig_args),
+        })
+    }
+
+    /// Lists what differs between this stamp and `other`, as
+    /// `{section, name, a, b}` objects where a missing side is null.
+    fn diff(&self, other: &Stamp) -> Vec<Value> {
+        let mut diffs = Vec::new();
+        let mut add = |section: &str, name: &OsStr, a: Value, b: Value| {
+            if a != b {
+                diffs.push(json!({"section": section, "name": json_str(name), "a": a, "b": b}));
             }
-            println!("  loc: {}:{line}", file.display());
-            println!("  cmd: {}", cmd.display());
-            if !result.is_empty() && result.len() < 500 && !result.as_bytes().contains(&b'\n') {
-                println!("  output: {}", result.display());
-            } else {
-                println!("  output: <{} bytes>", result.len());
+        };
+
+        // Files are compared by presence, and by content when both stamps
+        // have hashes for them.
+        let files = |s: &Stamp| -> BTreeMap<OsString, Value> {
+            s.files
+                .iter()
+                .enumerate()
+                .map(|(i, f)| {
+                    let v = s.hashes.get(i).map_or(json!(true), |h| json!(hex(h)));
+                    (f.clone(), v)
+                })
+                .collect()
+        };
+        let (fa, fb) = (files(self), files(other));
+        for name in fa.keys().chain(fb.keys()).collect::<BTreeSet<_>>() {
+            let (a, b) = (fa.get(name).cloned(), fb.get(name).cloned());
+            let (a, b) = match (a, b) {
+                (Some(Value::String(_)), Some(Value::Bool(_)))
+                | (Some(Value::Bool(_)), Some(Value::String(_))) => continue,
+                (a, b) => (a.unwrap_or(Value::Null), b.unwrap_or(Value::Null)),
+            };
+            add("file", name, a, b);
+        }
+
+        // Variables that were undefined are compared as null.
+        let envs = |s: &Stamp| -> BTreeMap<OsString, Value> {
+            let mut m: BTreeMap<OsString, Value> = s
+                .undefined
+                .iter()
+                .map(|k| (k.clone(), Value::Null))
+                .collect();
+            m.extend(s.envs.iter().map(|(k, v)| (k.clone(), json_str(v))));
+            m
+        };
+        let (ea, eb) = (envs(self), envs(other));
+        for name in ea.keys().chain(eb.keys()).collect::<BTreeSet<_>>() {
+            match (ea.get(name), eb.get(name)) {
+                (Some(a), Some(b)) => add("env", name, a.clone(), b.clone()),
+                // Only recorded by one of the stamps.
+                (a, b) => add(
+                    "env",
+                    name,
+                    a.map_or(json!("(not recorded)"), |v| v.clone()),
+                    b.map_or(json!("(not recorded)"), |v| v.clone()),
+                ),
             }
-            println!();
         }
-    }
 
-    Some(())
+        let globs = |s: &Stamp| -> BTreeMap<OsString, Value> {
+            s.globs
+                .iter()
+                .map(|(pat, files)| (pat.clone(), json_strs(files)))
+                .collect()
+        };
+        let (ga, gb) = (globs(self), globs(other));
+        for pat in ga.keys().chain(gb.keys()).collect::<BTreeSet<_>>() {
+            add(
+                "glob",
+                pat,
+                ga.get(pat).cloned().unwrap_or(Value::Null),
+                gb.get(pat).cloned().unwrap_or(Value::Null),
+            );
+        }
+
+        // The same command may be evaluated more than once, so all of its
+        // results are compared together.
+        let commands = |s: &Stamp| -> BTreeMap<(&'static str, OsString), Value> {
+            let mut m: BTreeMap<(&'static str, OsString), Vec<Value>> = BTreeMap::new();
+            for c in &s.commands {
+                m.entry((c.type_name(), c.cmd.clone()))
+                    .or_default()
+                    .push(json_str(&c.result));
+            }
+            m.into_iter()
+                .map(|(k, v)| {
+                    let v = if v.len() == 1 {
+                        v.into_iter().next().unwrap()
+                    } else {
+                        Value::Array(v)
+                    };
+                    (k, v)
+                })
+                .collect()
+        };
+        let (ca, cb) = (commands(self), commands(other));
+        for key in ca.keys().chain(cb.keys()).collect::<BTreeSet<_>>() {
+            add(
+                &key.0.to_ascii_lowercase(),
+                &key.1,
+                ca.get(key).cloned().unwrap_or(Value::Null),
+                cb.get(key).cloned().unwrap_or(Value::Null),
+            );
+        }
+
+        add(
+            "args",
+            OsStr::new(""),
+            json_str(&self.orig_args),
+            json_str(&other.orig_args),
+        );
+
+        diffs
+    }
 }
@@ -268,5 +268,17 @@ pub fn report_all_stats() {
         }
         eprintln!("*kati*: {} symbols", symbol_count());
         eprintln!("*kati*: {} find nodes", crate::find::get_node_count());
+        if FLAGS.find_cache {
+            eprintln!(
+                "*kati*: {} find cache hits",
+                crate::find::get_find_cache_hit_count()
+            );
+        }
+        if FLAGS.ast_cache {
+            eprintln!(
+                "*kati*: {} AST cache hits",
+                crate::ast_cache::get_ast_cache_hit_count()
+            );
+        }
     }
 }
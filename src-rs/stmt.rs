@@ -20,6 +20,7 @@ use parking_lot::Mutex;
 use std::{fmt::Debug, sync::Arc};
 
 use crate::{
+    ast_cache::{AstWriter, StmtKind},
     error_loc,
     eval::Evaluator,
     expr::{Evaluable, Value},
@@ -35,6 +36,13 @@ pub trait Statement: Debug {
     fn orig(&self) -> Bytes;
 
     fn eval(&self, ev: &mut Evaluator) -> Result<()>;
+
+    /// Serializes this statement for the --ast_cache.
+    fn dump(&self, w: &mut AstWriter) -> Result<()>;
+
+    fn as_include(&self) -> Option<&IncludeStmt> {
+        None
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -88,6 +96,13 @@ impl Statement for RuleStmt {
     fn eval(&self, ev: &mut Evaluator) -> Result<()> {
         ev.eval_rule(self)
     }
+
+    fn dump(&self, w: &mut AstWriter) -> Result<()> {
+        w.kind(StmtKind::Rule, &self.loc)?;
+        w.value(&self.lhs)?;
+        w.int(self.sep as i32)?;
+        w.opt_value(self.rhs.as_deref())
+    }
 }
 
 impl Debug for RuleStmt {
@@ -138,6 +153,19 @@ impl Statement for AssignStmt {
     fn eval(&self, ev: &mut Evaluator) -> Result<()> {
         ev.eval_assign(self)
     }
+
+    fn dump(&self, w: &mut AstWriter) -> Result<()> {
+        w.kind(StmtKind::Assign, &self.loc)?;
+        w.value(&self.lhs)?;
+        w.value(&self.rhs)?;
+        w.bytes(&self.orig_rhs)?;
+        w.int(self.op as i32)?;
+        match self.directive {
+            Some(d) => w.int(d.is_override as i32 | (d.export as i32) << 1)?,
+            None => w.int(-1)?,
+        }
+        w.bool(self.is_final)
+    }
 }
 
 impl Debug for AssignStmt {
@@ -216,6 +244,11 @@ impl Statement for CommandStmt {
     fn eval(&self, ev: &mut Evaluator) -> Result<()> {
         ev.eval_command(self)
     }
+    fn dump(&self, w: &mut AstWriter) -> Result<()> {
+        w.kind(StmtKind::Command, &self.loc)?;
+        w.bytes(&self.orig)?;
+        w.value(&self.expr)
+    }
 }
 
 impl Debug for CommandStmt {
@@ -251,6 +284,14 @@ impl Statement for IfStmt {
     fn eval(&self, ev: &mut Evaluator) -> Result<()> {
         ev.eval_if(self)
     }
+    fn dump(&self, w: &mut AstWriter) -> Result<()> {
+        w.kind(StmtKind::If, &self.loc)?;
+        w.int(self.op as i32)?;
+        w.value(&self.lhs)?;
+        w.opt_value(self.rhs.as_deref())?;
+        w.stmts(&self.true_stmts.lock())?;
+        w.stmts(&self.false_stmts.lock())
+    }
 }
 
 impl Debug for IfStmt {
@@ -300,6 +341,14 @@ impl Statement for IncludeStmt {
     fn eval(&self, ev: &mut Evaluator) -> Result<()> {
         ev.eval_include(self)
     }
+    fn as_include(&self) -> Option<&IncludeStmt> {
+        Some(self)
+    }
+    fn dump(&self, w: &mut AstWriter) -> Result<()> {
+        w.kind(StmtKind::Include, &self.loc)?;
+        w.value(&self.expr)?;
+        w.bool(self.should_exist)
+    }
 }
 
 impl Debug for IncludeStmt {
@@ -337,6 +386,11 @@ impl Statement for ExportStmt {
     fn eval(&self, ev: &mut Evaluator) -> Result<()> {
         ev.eval_export(self)
     }
+    fn dump(&self, w: &mut AstWriter) -> Result<()> {
+        w.kind(StmtKind::Export, &self.loc)?;
+        w.value(&self.expr)?;
+        w.bool(self.is_export)
+    }
 }
 
 impl Debug for ExportStmt {
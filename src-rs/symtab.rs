@@ -15,7 +15,7 @@ limitations under the License.
 */
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::{Debug, Display},
     num::NonZeroUsize,
     sync::LazyLock,
@@ -35,6 +35,7 @@ static SYMTAB: LazyLock<Mutex<Symtab>> = LazyLock::new(|| Mutex::new(Symtab::new
 pub static SHELL_SYM: LazyLock<Symbol> = LazyLock::new(|| intern("SHELL"));
 pub static ALLOW_RULES_SYM: LazyLock<Symbol> = LazyLock::new(|| intern(".KATI_ALLOW_RULES"));
 pub static KATI_READONLY_SYM: LazyLock<Symbol> = LazyLock::new(|| intern(".KATI_READONLY"));
+pub static KATI_MEMOIZE_SYM: LazyLock<Symbol> = LazyLock::new(|| intern(".KATI_MEMOIZE"));
 pub static VARIABLES_SYM: LazyLock<Symbol> = LazyLock::new(|| intern(".VARIABLES"));
 pub static KATI_SYMBOLS_SYM: LazyLock<Symbol> = LazyLock::new(|| intern(".KATI_SYMBOLS"));
 pub static MAKEFILE_LIST: LazyLock<Symbol> = LazyLock::new(|| intern("MAKEFILE_LIST"));
@@ -90,6 +91,14 @@ impl Symbol {
         let mut r = SYMTAB.lock();
         r.set_global_var(self, var, is_override, readonly)
     }
+
+    /// Records that the global variable was changed in place, see
+    /// `track_global_var_writes`.
+    pub fn mark_global_var_written(&self) {
+        if let Some(written) = &mut SYMTAB.lock().written {
+            written.insert(*self);
+        }
+    }
 }
 
 pub struct ScopedGlobalVar {
@@ -122,6 +131,9 @@ struct Symtab {
     symbols: Vec<Bytes>,
     symbol_data: Vec<Option<Var>>,
     symtab: HashMap<Bytes, Symbol>,
+    // The global variables written since the last call to
+    // `take_written_global_vars`, while tracking.
+    written: Option<HashSet<Symbol>>,
 }
 
 impl Symtab {
@@ -130,6 +142,7 @@ impl Symtab {
             symbols: vec![Bytes::new()],
             symbol_data: vec![],
             symtab: HashMap::new(),
+            written: None,
         };
         for i in 1u8..=255 {
             assert!(symtab.symbols.len() == i as usize);
@@ -139,35 +152,35 @@ impl Symtab {
             symtab.symtab.insert(name, sym);
         }
 
-        let shell_status_sym = symtab.intern(".SHELLSTATUS");
-        symtab
-            .set_global_var(
-                &shell_status_sym,
-                Variable::new_shell_status_var(),
-                false,
-                None,
-            )
-            .unwrap();
-        let variables_sym = symtab.intern(".VARIABLES");
-        symtab
-            .set_global_var(
-                &variables_sym,
-                Variable::new_variable_names(b".VARIABLES", true),
-                false,
-                None,
-            )
-            .unwrap();
-        let symbols_sym = symtab.intern(".KATI_SYMBOLS");
+        symtab.set_builtin_vars();
         symtab
-            .set_global_var(
-                &symbols_sym,
-                Variable::new_variable_names(b".KATI_SYMBOLS", false),
-                false,
-                None,
-            )
-            .unwrap();
+    }
 
-        symtab
+    fn set_builtin_vars(&mut self) {
+        let shell_status_sym = self.intern(".SHELLSTATUS");
+        self.set_global_var(
+            &shell_status_sym,
+            Variable::new_shell_status_var(),
+            false,
+            None,
+        )
+        .unwrap();
+        let variables_sym = self.intern(".VARIABLES");
+        self.set_global_var(
+            &variables_sym,
+            Variable::new_variable_names(b".VARIABLES", true),
+            false,
+            None,
+        )
+        .unwrap();
+        let symbols_sym = self.intern(".KATI_SYMBOLS");
+        self.set_global_var(
+            &symbols_sym,
+            Variable::new_variable_names(b".KATI_SYMBOLS", false),
+            false,
+            None,
+        )
+        .unwrap();
     }
 
     fn intern<T: Into<Bytes> + AsRef<[u8]>>(&mut self, s: T) -> Symbol {
@@ -216,11 +229,15 @@ impl Symtab {
             if origin == VarOrigin::CommandLine && var.read().origin() == VarOrigin::File {
                 return Ok(());
             }
-            if origin == VarOrigin::Automatic {
+            // Each CommandEvaluator registers its own automatic variables.
+            if origin == VarOrigin::Automatic && var.read().origin() != VarOrigin::Automatic {
                 error!("overriding automatic variable is not implemented yet");
             }
         }
         *entry = Some(var);
+        if let Some(written) = &mut self.written {
+            written.insert(*sym);
+        }
         Ok(())
     }
 }
@@ -259,6 +276,50 @@ pub fn get_symbol_names<T: Fn(Var) -> bool>(filter: T) -> Vec<(Symbol, Bytes)> {
         .collect::<Vec<_>>()
 }
 
+/// Drops every global variable, so the makefiles can be evaluated again from
+/// scratch. Symbols stay interned.
+pub fn clear_global_vars() {
+    let mut s = SYMTAB.lock();
+    s.symbol_data.clear();
+    s.set_builtin_vars();
+}
+
+/// Starts or stops recording which global variables are assigned, for
+/// --incremental_eval. Variables changed in place are recorded by
+/// `Symbol::mark_global_var_written`.
+pub fn track_global_var_writes(enabled: bool) {
+    SYMTAB.lock().written = enabled.then(HashSet::new);
+}
+
+/// Deep copies of the global variables written since the last call, see
+/// `Variable::snapshot`.
+pub fn take_written_global_vars() -> Vec<(Symbol, Option<Var>)> {
+    let mut s = SYMTAB.lock();
+    let Some(written) = s.written.as_mut().map(std::mem::take) else {
+        return Vec::new();
+    };
+    written
+        .into_iter()
+        .map(|sym| {
+            let var = s.symbol_data.get(sym.0.get()).cloned().flatten();
+            (sym, var.as_ref().map(Variable::snapshot))
+        })
+        .collect()
+}
+
+/// Sets global variables to copies of the ones `take_written_global_vars`
+/// returned.
+pub fn restore_global_vars(vars: &[(Symbol, Option<Var>)]) {
+    let mut s = SYMTAB.lock();
+    for (sym, var) in vars {
+        let idx = sym.0.get();
+        if idx >= s.symbol_data.len() {
+            s.symbol_data.resize(idx + 1, None);
+        }
+        s.symbol_data[idx] = var.as_ref().map(Variable::snapshot);
+    }
+}
+
 pub fn symbol_count() -> usize {
     let s = SYMTAB.lock();
     s.symbols.len()
@@ -14,7 +14,35 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A time that is older than the mtime of any file written from now on, used
+/// as the start time of a run. File mtimes come from the kernel's coarse
+/// clock, which lags `SystemTime::now()`, so a file written right after a
+/// fast run could look older than the stamp. Going one tick back from the
+/// coarse clock keeps every later write strictly newer, at the cost of rarely
+/// regenerating once more.
+pub fn start_time() -> SystemTime {
+    // Other systems have no coarse clock; going back one tick of the
+    // regular one is still conservative.
+    #[cfg(target_os = "linux")]
+    const CLOCK: libc::clockid_t = libc::CLOCK_REALTIME_COARSE;
+    #[cfg(not(target_os = "linux"))]
+    const CLOCK: libc::clockid_t = libc::CLOCK_REALTIME;
+
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    let mut res = now;
+    // SAFETY: now and res are valid for writes.
+    unsafe {
+        libc::clock_gettime(CLOCK, &mut now);
+        libc::clock_getres(CLOCK, &mut res);
+    }
+    let now = UNIX_EPOCH + Duration::new(now.tv_sec as u64, now.tv_nsec as u32);
+    now - Duration::new(res.tv_sec as u64, res.tv_nsec as u32)
+}
 
 pub struct ScopedTimeReporter {
     name: String,
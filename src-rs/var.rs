@@ -20,7 +20,10 @@ use std::{
     ffi::OsString,
     fmt::Debug,
     os::unix::ffi::OsStrExt,
-    sync::{Arc, LazyLock},
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
 };
 
 use anyhow::Result;
@@ -83,9 +86,30 @@ pub struct Variable {
 
     visibility_prefix: Option<Vec<OsString>>,
 
+    // Bumped whenever the value changes, so that memoized expansions
+    // referencing this variable can tell they are stale.
+    generation: u64,
+    pub memoize: bool,
+    memo: Mutex<Option<Memo>>,
+
     value: InnerVar,
 }
 
+/// A cached expansion of a `.KATI_MEMOIZE` variable, along with the
+/// generation of every variable looked up while computing it. `None`
+/// records a lookup of an undefined variable.
+#[derive(Debug, Clone)]
+struct Memo {
+    value: Bytes,
+    deps: Vec<(Symbol, Option<u64>)>,
+}
+
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+
+fn next_generation() -> u64 {
+    NEXT_GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
 #[derive(Debug)]
 pub enum InnerVar {
     Simple(Vec<u8>),
@@ -111,6 +135,13 @@ impl Variable {
     pub fn set_obsolete(&mut self, message: Arc<String>) {
         self.obsolete = Some(message);
     }
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+    fn value_changed(&mut self) {
+        self.generation = next_generation();
+        *self.memo.get_mut() = None;
+    }
     pub fn flavor(&self) -> &'static str {
         match &self.value {
             InnerVar::Simple(_) => "simple",
@@ -137,6 +168,39 @@ impl Variable {
         }
         Ok(())
     }
+    /// Copies a variable so that in-place changes to the copy and to the
+    /// original don't affect each other. Automatic variables are shared, as
+    /// evaluation never changes them.
+    pub fn snapshot(var: &Var) -> Var {
+        let v = var.read();
+        let value = match &v.value {
+            InnerVar::Simple(s) => InnerVar::Simple(s.clone()),
+            InnerVar::Recursive { v, orig } => InnerVar::Recursive {
+                v: v.clone(),
+                orig: orig.clone(),
+            },
+            InnerVar::AutoCommand(_, _) => return var.clone(),
+            InnerVar::ShellStatus => InnerVar::ShellStatus,
+            InnerVar::VariableNames { name, all } => InnerVar::VariableNames {
+                name: name.clone(),
+                all: *all,
+            },
+        };
+        Arc::new(RwLock::new(Self {
+            loc: v.loc.clone(),
+            definition: v.definition.clone(),
+            origin: v.origin,
+            assign_op: v.assign_op,
+            readonly: v.readonly,
+            deprecated: v.deprecated.clone(),
+            obsolete: v.obsolete.clone(),
+            visibility_prefix: v.visibility_prefix.clone(),
+            generation: v.generation,
+            memoize: v.memoize,
+            memo: Mutex::new(None),
+            value,
+        }))
+    }
     pub fn immediate_eval(&self) -> bool {
         matches!(&self.value, InnerVar::Simple(_))
     }
@@ -160,6 +224,7 @@ impl Variable {
                     ],
                 ));
                 self.definition = Some(frame);
+                self.value_changed();
             }
             InnerVar::AutoCommand(sym, _) => {
                 error_loc!(loc, "appending to ${sym} is not supported");
@@ -175,6 +240,7 @@ impl Variable {
                 s.push(b' ');
                 s.extend_from_slice(buf);
                 self.definition = Some(frame);
+                self.value_changed();
             }
             InnerVar::Recursive { v: prev, .. } => {
                 *prev = Arc::new(Value::List(
@@ -186,6 +252,7 @@ impl Variable {
                     ],
                 ));
                 self.definition = Some(frame);
+                self.value_changed();
             }
             InnerVar::AutoCommand(sym, _) => {
                 error!("appending to ${sym} is not supported");
@@ -252,6 +319,9 @@ impl Variable {
             deprecated: None,
             obsolete: None,
             visibility_prefix: None,
+            generation: next_generation(),
+            memoize: false,
+            memo: Mutex::new(None),
             value: InnerVar::Simple(Vec::new()),
         }))
     }
@@ -271,6 +341,9 @@ impl Variable {
             deprecated: None,
             obsolete: None,
             visibility_prefix: None,
+            generation: next_generation(),
+            memoize: false,
+            memo: Mutex::new(None),
             value: InnerVar::Simple(value.to_vec()),
         }))
     }
@@ -292,6 +365,9 @@ impl Variable {
             deprecated: None,
             obsolete: None,
             visibility_prefix: None,
+            generation: next_generation(),
+            memoize: false,
+            memo: Mutex::new(None),
             value: InnerVar::Simple(value.to_vec()),
         })))
     }
@@ -312,6 +388,9 @@ impl Variable {
             deprecated: None,
             obsolete: None,
             visibility_prefix: None,
+            generation: next_generation(),
+            memoize: false,
+            memo: Mutex::new(None),
             value: InnerVar::Recursive { v, orig },
         }))
     }
@@ -326,6 +405,9 @@ impl Variable {
             deprecated: None,
             obsolete: None,
             visibility_prefix: None,
+            generation: next_generation(),
+            memoize: false,
+            memo: Mutex::new(None),
             value: InnerVar::AutoCommand(sym, a),
         }))
     }
@@ -340,6 +422,9 @@ impl Variable {
             deprecated: None,
             obsolete: None,
             visibility_prefix: None,
+            generation: next_generation(),
+            memoize: false,
+            memo: Mutex::new(None),
             value: InnerVar::ShellStatus,
         }))
     }
@@ -354,6 +439,9 @@ impl Variable {
             deprecated: None,
             obsolete: None,
             visibility_prefix: None,
+            generation: next_generation(),
+            memoize: false,
+            memo: Mutex::new(None),
             value: InnerVar::VariableNames {
                 name: Bytes::from_static(name),
                 all,
@@ -369,7 +457,24 @@ impl Evaluable for Variable {
                 out.put_slice(v);
             }
             InnerVar::Recursive { v, .. } => {
-                v.eval(ev, out)?;
+                // Automatic variables change from rule to rule without being
+                // reassigned, so never memoize while evaluating commands.
+                if !self.memoize || ev.is_evaluating_command {
+                    return v.eval(ev, out);
+                }
+                if let Some(memo) = self.memo.lock().clone()
+                    && ev.memo_is_valid(&memo.deps)
+                {
+                    ev.add_memo_deps(&memo.deps);
+                    out.put_slice(&memo.value);
+                    return Ok(());
+                }
+                ev.begin_memo();
+                let value = v.eval_to_buf(ev);
+                let deps = ev.end_memo();
+                let value = value?;
+                out.put_slice(&value);
+                *self.memo.lock() = Some(Memo { value, deps });
             }
             InnerVar::AutoCommand(_, a) => {
                 a.eval(ev, out)?;
@@ -424,6 +529,14 @@ pub fn set_shell_status_var(status: i32) {
     *SHELL_STATUS.lock() = Some(status)
 }
 
+pub fn get_shell_status_var() -> Option<i32> {
+    *SHELL_STATUS.lock()
+}
+
+pub fn restore_shell_status_var(status: Option<i32>) {
+    *SHELL_STATUS.lock() = status
+}
+
 pub static USED_ENV_VARS: LazyLock<Mutex<HashSet<Symbol>>> =
     LazyLock::new(|| Mutex::new(HashSet::new()));
 
@@ -477,6 +590,16 @@ impl Vars {
         Ok(())
     }
 
+    /// A deep copy, see `Variable::snapshot`.
+    pub fn snapshot(&self) -> Vars {
+        let vars = self.0.lock();
+        Vars(Mutex::new(
+            vars.iter()
+                .map(|(sym, var)| (*sym, Variable::snapshot(var)))
+                .collect(),
+        ))
+    }
+
     pub fn merge_from(&self, vars: &Vars) {
         let mut to = self.0.lock();
         let from = vars.0.lock();
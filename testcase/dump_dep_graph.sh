@@ -0,0 +1,64 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+set -eu
+
+mk="$@"
+
+touch a.c b.c
+
+cat <<'EOF' > Makefile
+all: a.o b.o | dir
+dir:
+	mkdir -p $@
+%.o: %.c
+	gcc -c -o $@ $< $(shell echo x)
+.PHONY: all
+EOF
+
+if echo "${mk}" | grep -qv "kati"; then
+  # Make doesn't support --dump_dep_graph.
+  cat <<'EOF'
+[
+{"commands":[],"deps":["a.o","b.o"],"has_rule":true,"implicit_outputs":[],"is_phony":true,"is_restat":false,"loc":"Makefile:1","order_onlys":["dir"],"output":"all","pattern":null,"validations":[]},
+{"commands":["gcc -c -o a.o a.c $(echo x)"],"deps":["a.c"],"has_rule":true,"implicit_outputs":[],"is_phony":false,"is_restat":false,"loc":"Makefile:5","order_onlys":[],"output":"a.o","pattern":"%.o","validations":[]},
+{"commands":[],"deps":[],"has_rule":false,"implicit_outputs":[],"is_phony":false,"is_restat":false,"loc":null,"order_onlys":[],"output":"a.c","pattern":null,"validations":[]},
+{"commands":["gcc -c -o b.o b.c $(echo x)"],"deps":["b.c"],"has_rule":true,"implicit_outputs":[],"is_phony":false,"is_restat":false,"loc":"Makefile:5","order_onlys":[],"output":"b.o","pattern":"%.o","validations":[]},
+{"commands":[],"deps":[],"has_rule":false,"implicit_outputs":[],"is_phony":false,"is_restat":false,"loc":null,"order_onlys":[],"output":"b.c","pattern":null,"validations":[]},
+{"commands":["mkdir -p dir"],"deps":[],"has_rule":true,"implicit_outputs":[],"is_phony":false,"is_restat":false,"loc":"Makefile:3","order_onlys":[],"output":"dir","pattern":null,"validations":[]}
+]
+digraph kati {
+  "all" [shape=ellipse];
+  "all" -> "a.o";
+  "all" -> "b.o";
+  "all" -> "dir" [style=dashed];
+  "a.o" [shape=box];
+  "a.o" -> "a.c";
+  "a.c" [shape=plaintext];
+  "b.o" [shape=box];
+  "b.o" -> "b.c";
+  "b.c" [shape=plaintext];
+  "dir" [shape=box];
+}
+EOF
+  echo 'ninja: gcc -c -o a.o a.c \$$(echo x)'
+else
+  ${mk} -c --dump_dep_graph=graph.json --dump_dep_graph_commands
+  ${mk} -c --dump_dep_graph=graph.dot --dump_dep_graph_format=dot
+  cat graph.json graph.dot
+  # Ninja generation reuses the commands evaluated for the dump.
+  ${mk} --ninja --dump_dep_graph=ninja_graph.json --dump_dep_graph_commands > /dev/null 2>&1
+  echo "ninja: $(grep -o 'gcc -c -o a.o a.c [^"]*' build.ninja)"
+fi
@@ -0,0 +1,40 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+mk="$@"
+
+if echo "${mk}" | grep -q rkati; then
+  cat <<'EOF' > Makefile
+check = $(KATI_assert $(filter $(1),$(2)),$(1) is not in $(2))
+$(call check,c,a b)
+EOF
+else
+  cat <<'EOF' > Makefile
+
+$(error Assertion failed. c is not in a b)
+EOF
+fi
+
+cat <<'EOF' >> Makefile
+test:
+	echo FAIL
+EOF
+
+${mk} 2>&1
+if ! echo "${mk}" | grep -q rkati; then
+  # GNU make doesn't print how the error was reached.
+  echo 'Makefile:2: from $(call check)'
+fi
@@ -0,0 +1,38 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+mk="$@"
+
+if echo "${mk}" | grep -q rkati; then
+  cat <<'EOF' > Makefile
+FOO := a
+EMPTY :=
+$(KATI_check_defined FOO BAR EMPTY BAZ,set them in the product config)
+EOF
+else
+  cat <<'EOF' > Makefile
+
+
+$(error Required variables not defined: BAR EMPTY BAZ. set them in the product config)
+EOF
+fi
+
+cat <<'EOF' >> Makefile
+test:
+	echo FAIL
+EOF
+
+${mk} 2>&1
@@ -0,0 +1,36 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+mk="$@"
+
+if echo "${mk}" | grep -q rkati; then
+  cat <<'EOF' > Makefile
+bad := foo(
+$(KATI_regex_filter $(bad),foo bar)
+EOF
+else
+  cat <<'EOF' > Makefile
+bad := foo(
+$(error invalid regular expression in `KATI_regex_filter': '$(bad)': unclosed group)
+EOF
+fi
+
+cat <<'EOF' >> Makefile
+test:
+	echo FAIL
+EOF
+
+${mk} 2>&1
@@ -0,0 +1,58 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+set -eu
+
+mk="$@"
+
+touch a.c b.cpp
+
+cat <<'EOF' > Makefile
+all: a.o b.o lib.a
+%.o: %.c
+	@mkdir -p $(dir $@) && gcc -Wall -MD -MF $@.d -c -o $@ $<
+%.o: %.cpp
+	g++ -c $< -o $@ ; true
+lib.a: a.o
+	ar rcs $@ $^
+EOF
+
+if echo "${mk}" | grep -qv "kati"; then
+  # Make doesn't support --gen_compdb.
+  cat <<'EOF'
+[
+  {
+    "command": "gcc -Wall -MD -MF a.o.d -c -o a.o a.c",
+    "directory": ".",
+    "file": "a.c",
+    "output": "a.o"
+  },
+  {
+    "command": "g++ -c b.cpp -o b.o",
+    "directory": ".",
+    "file": "b.cpp",
+    "output": "b.o"
+  }
+]
+EOF
+  echo "regenerated after removal"
+else
+  ${mk} --ninja --regen --gen_compdb=compile_commands.json > /dev/null 2>&1
+  sed "s|\"$(pwd)\"|\".\"|" compile_commands.json
+  # The stamp is fresh, but the compilation database is an output too.
+  rm compile_commands.json
+  ${mk} --ninja --regen --gen_compdb=compile_commands.json > /dev/null 2>&1
+  [ -f compile_commands.json ] && echo "regenerated after removal"
+fi
@@ -0,0 +1,87 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+set -eu
+
+log=stderr_log
+mk="$@"
+
+cat <<'EOF' > modules.json
+{
+  "modules": {
+    "libfoo": {"srcs": ["foo.c", "bar.c"], "shared": true, "version": 3},
+    "libbar": {"srcs": ["baz.c"], "shared": false, "owner": null}
+  }
+}
+EOF
+
+if echo "${mk}" | grep -q rkati; then
+  cat <<'EOF' > Makefile
+MODULES := $(KATI_json_keys modules.json,modules)
+SRCS := $(foreach m,$(MODULES),$(KATI_json_get modules.json,modules.$(m).srcs))
+SHARED := $(KATI_json_get modules.json,modules.libfoo.shared)
+VERSION := $(KATI_json_get modules.json,modules.libfoo.version)
+FIRST := $(KATI_json_get modules.json,modules.libfoo.srcs.0)
+OWNER := $(KATI_json_get modules.json,modules.libbar.owner)
+MISSING := $(KATI_json_get modules.json,modules.libqux.srcs)
+$(file >out.json,$(KATI_json_encode $(SRCS) "quoted"))
+EOF
+else
+  # Other implementations don't have the functions, so manually set the
+  # results.
+  cat <<'EOF' > Makefile
+MODULES := libbar libfoo
+SRCS := baz.c foo.c bar.c
+SHARED := true
+VERSION := 3
+FIRST := foo.c
+OWNER :=
+MISSING :=
+$(file >out.json,["baz.c","foo.c","bar.c","\"quoted\""])
+EOF
+fi
+
+cat <<'EOF' >> Makefile
+all:
+	echo $(MODULES)
+	echo $(SRCS)
+	echo $(SHARED) $(VERSION) $(FIRST)
+	echo "[$(OWNER)$(MISSING)]"
+	cat out.json
+EOF
+
+${mk} 2> ${log}
+if [ -e ninja.sh ]; then
+  ./ninja.sh
+fi
+
+${mk} 2> ${log}
+if [ -e ninja.sh ]; then
+  if grep -q regenerating ${log}; then
+    echo 'Should not be regenerated'
+  fi
+  ./ninja.sh
+fi
+
+touch modules.json
+
+${mk} 2> ${log}
+if [ -e ninja.sh ]; then
+  if ! grep -q regenerating ${log}; then
+    echo 'Should have regenerated due to modified file'
+  fi
+  ./ninja.sh
+fi
@@ -0,0 +1,79 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+mk="$@"
+
+if echo "${mk}" | grep -q rkati; then
+  cat <<'EOF' > Makefile
+A := a
+B = b
+LIST = $(info expanding LIST)$(A) $(B)
+.KATI_MEMOIZE := LIST
+$(info 1: $(LIST))
+$(info 2: $(LIST))
+A += x
+$(info 3: $(LIST))
+$(info 4: $(LIST))
+B = c $(UNDEF)
+$(info 5: $(LIST))
+UNDEF := u
+$(info 6: $(LIST))
+LIST = $(info expanding new LIST)new $(A)
+$(info 7: $(LIST))
+$(info 8: $(LIST))
+WRAP = $(info expanding WRAP)[$(LIST)]
+.KATI_MEMOIZE := WRAP
+$(info 9: $(WRAP))
+A := reset
+$(info 10: $(WRAP))
+ARG = $(info expanding ARG)<$(1)>
+.KATI_MEMOIZE := ARG
+$(info 11: $(call ARG,p) $(call ARG,q))
+$(info 12: $(foreach x,1 2,$(LIST)))
+EOF
+else
+  cat <<'EOF' > Makefile
+$(info expanding LIST)
+$(info 1: a b)
+$(info 2: a b)
+$(info expanding LIST)
+$(info 3: a x b)
+$(info 4: a x b)
+$(info expanding LIST)
+$(info 5: a x c )
+$(info expanding LIST)
+$(info 6: a x c u)
+$(info expanding new LIST)
+$(info 7: new a x)
+$(info 8: new a x)
+$(info expanding WRAP)
+$(info 9: [new a x])
+$(info expanding WRAP)
+$(info expanding new LIST)
+$(info 10: [new reset])
+$(info expanding ARG)
+$(info expanding ARG)
+$(info 11: <p> <q>)
+$(info 12: new reset new reset)
+EOF
+fi
+
+cat <<'EOF' >> Makefile
+test:
+	@:
+EOF
+
+${mk} 2>&1
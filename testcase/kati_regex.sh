@@ -0,0 +1,49 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+set -e
+
+mk="$@"
+
+if echo "${mk}" | grep -q rkati; then
+  cat <<'EOF' > Makefile
+files := foo.c bar.cc baz.h lib/qux.cpp README
+sources := $(KATI_regex_filter \.(c|cc|cpp)$$,$(files))
+stems := $(KATI_regex_match ^(?:.*/)?([a-z]+)\.c+p*$$,$(files))
+objs := $(KATI_regex_replace \.(c|cc|cpp)$$,.o,$(files))
+swapped := $(KATI_regex_replace ^([a-z]+)\.([a-z]+)$$,$${2}.$${1},$(files))
+EOF
+else
+  # Other implementations don't have the functions, so manually set the
+  # results.
+  cat <<'EOF' > Makefile
+sources := foo.c bar.cc lib/qux.cpp
+stems := foo bar qux
+objs := foo.o bar.o baz.h lib/qux.o README
+swapped := c.foo cc.bar h.baz lib/qux.cpp README
+EOF
+fi
+
+cat <<'EOF' >> Makefile
+test:
+	echo $(sources)
+	echo $(stems)
+	echo $(objs)
+	echo $(swapped)
+EOF
+
+${mk} 2>&1
+if [ -e ninja.sh ]; then ./ninja.sh; fi
@@ -0,0 +1,43 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+mk="$@"
+
+if echo "${mk}" | grep -q rkati; then
+  cat <<'EOF' > Makefile
+FOO := a
+BAR := x
+ARCH := arm64
+$(KATI_assert $(FOO),FOO must be set)
+$(KATI_assert $(filter arm64 x86_64,$(ARCH)))
+$(KATI_check_defined FOO BAR)
+$(KATI_check_defined FOO,needed for the build)
+$(KATI_check_one_of ARCH,arm arm64 x86 x86_64)
+$(KATI_check_one_of ARCH, arm64 )
+RESULT := [$(KATI_assert 1)$(KATI_check_defined FOO)$(KATI_check_one_of ARCH,arm64)]
+EOF
+else
+  cat <<'EOF' > Makefile
+RESULT := []
+EOF
+fi
+
+cat <<'EOF' >> Makefile
+test:
+	echo $(RESULT)
+EOF
+
+${mk} 2>&1
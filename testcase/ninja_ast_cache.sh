@@ -0,0 +1,67 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+set -e
+
+log=stderr_log
+mk="$@"
+
+cat <<EOF2 > Makefile
+all: foo
+	echo \$(call greet,\$(NAME))
+include sub.mk
+EOF2
+
+cat <<EOF2 > sub.mk
+NAME := world
+define greet
+hello \$(subst o,0,\$(1))
+endef
+ifeq (\$(NAME),world)
+foo:
+	echo \$@ \$(NAME:%d=%D)
+endif
+EOF2
+
+args=
+if echo "${mk}" | grep -q rkati; then
+  args="--ast_cache --kati_stats"
+fi
+
+${mk} ${args} 2> ${log}
+if [ -e ninja.sh ]; then
+  if [ -n "${args}" ] && [ ! -e .kati_ast_cache ]; then
+    echo 'Should write the AST cache'
+  fi
+  ./ninja.sh
+fi
+
+sleep 1
+echo 'NAME2 := unused' >> Makefile
+${mk} ${args} 2> ${log}
+if [ -e ninja.sh ]; then
+  if [ -n "${args}" ] && ! grep -q '^\*kati\*: 1 AST cache hits' ${log}; then
+    echo 'Should reuse the AST of sub.mk'
+  fi
+  ./ninja.sh
+fi
+
+sleep 1
+sed -i 's/world/there/g' sub.mk
+${mk} ${args} 2> ${log}
+if [ -e ninja.sh ]; then
+  ./ninja.sh
+fi
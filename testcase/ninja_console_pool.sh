@@ -0,0 +1,70 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+set -e
+
+log=stderr_log
+mk="$@"
+
+cat <<EOF > Makefile
+
+test: test_console test_console_var test_default test_phony
+
+.KATI_CONSOLE: test_console test_phony
+
+test_console:
+	echo "PASS"
+
+test_console_var: .KATI_NINJA_POOL := console
+test_console_var:
+	echo "PASS"
+
+test_default:
+	echo "PASS"
+
+.PHONY: test_phony
+test_phony: test_default
+EOF
+
+args=
+if ! echo "${mk}" | grep -qv "kati"; then
+  args=--default_pool=default_pool
+fi
+
+${mk} ${args} 2>${log}
+if [ -e ninja.sh ]; then
+  mv build.ninja kati.ninja
+  cat <<EOF > build.ninja
+pool default_pool
+  depth = 1
+include kati.ninja
+EOF
+  ./ninja.sh
+fi
+if [ -e ninja.sh ]; then
+  if ! grep -A1 "build test_console:" kati.ninja | grep -q "pool = console"; then
+    echo "console not present for test_console rule in build.ninja"
+  fi
+  if ! grep -A1 "build test_console_var:" kati.ninja | grep -q "pool = console"; then
+    echo "console not present for test_console_var rule in build.ninja"
+  fi
+  if ! grep -A1 "build test_default:" kati.ninja | grep -q "pool = default_pool"; then
+    echo "default_pool not present for test_default rule in build.ninja"
+  fi
+  if grep -A1 "build test_phony:" kati.ninja | grep -q "pool = "; then
+    echo "unexpected pool present for test_phony rule in build.ninja"
+  fi
+fi
@@ -0,0 +1,60 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+set -e
+
+mk="$@"
+
+cat <<EOF2 > Makefile
+include a.mk
+X := \$(wildcard src/*.c)
+all:
+	echo \$(A) \$(X)
+EOF2
+echo 'A := one' > a.mk
+mkdir -p src
+touch src/x.c
+
+if ! echo "${mk}" | grep -q rkati; then
+  # Only rkati has a daemon mode.
+  echo 'echo one src/x.c'
+  echo 'echo two src/x.c'
+  echo 'echo two src/x.c src/y.c'
+  exit 0
+fi
+
+${mk} --daemon > daemon.log 2>&1 &
+daemon=$!
+trap "kill ${daemon}" EXIT
+for i in $(seq 50); do
+  [ -S .kati_daemon.sock ] && break
+  sleep 0.1
+done
+
+${mk} --daemon_wait
+grep -o 'echo one[^"]*' build.ninja
+
+# Changes are picked up without a client asking.
+echo 'A := two' > a.mk
+for i in $(seq 50); do
+  grep -q 'echo two' build.ninja && break
+  sleep 0.1
+done
+grep -o 'echo two[^"]*' build.ninja
+
+touch src/y.c
+${mk} --daemon_wait
+grep -o 'echo two[^"]*' build.ninja
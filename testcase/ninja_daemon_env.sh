@@ -0,0 +1,54 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+
+set -e
+
+mk="$@"
+
+cat <<EOF2 > Makefile
+all:
+	echo \$(FOO)
+EOF2
+
+if ! echo "${mk}" | grep -q rkati; then
+  # Only rkati has a daemon mode.
+  echo 'echo one'
+  echo 'echo two'
+  echo 'different arguments'
+  exit 0
+fi
+
+export FOO=one
+${mk} --daemon > daemon.log 2>&1 &
+daemon=$!
+trap "kill ${daemon}" EXIT
+for i in $(seq 50); do
+  [ -S .kati_daemon.sock ] && break
+  sleep 0.1
+done
+
+${mk} --daemon_wait
+grep -o 'echo one' build.ninja
+
+# The client's environment is checked, not the daemon's.
+export FOO=two
+${mk} --daemon_wait
+grep -o 'echo two' build.ninja
+
+if ! ${mk} BAR=1 --daemon_wait 2> err.log; then
+  grep -o 'different arguments' err.log
+fi
@@ -0,0 +1,44 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+set -eu
+
+mk="$@"
+
+echo a > a.c
+echo b > b.c
+echo c > c.c
+
+cat <<'EOF' > Makefile
+all: a.o b.o c.o sub/d.o
+
+%.o: %.c
+	cp $< $@
+
+sub/d.o: a.c b.c
+	mkdir -p sub && cat $^ > $@
+EOF
+
+if echo "${mk}" | grep -qv "kati"; then
+  # Make doesn't support --ninja_dedup_rules.
+  ${mk} > /dev/null
+  echo "rules: 2"
+else
+  ${mk} --ninja --ninja_dedup_rules > /dev/null
+  echo "rules: $(grep -c '^rule ' build.ninja)"
+  ./ninja.sh -j1 > /dev/null
+fi
+
+cat a.o b.o c.o sub/d.o
@@ -0,0 +1,47 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+set -eu
+
+mk="$@"
+
+cat <<'EOF' > Makefile
+all: a.o b.o c.jar
+
+a.o:
+	: gcc -c a.c -MD -o $@
+
+b.o:
+	: clang -c b.c -MD --dependency-file=b.dep -o $@
+
+c.jar:
+	: tools/javac --depfile c.d -d out C.java
+EOF
+
+if echo "${mk}" | grep -qv "kati"; then
+  # Make doesn't support --depfile_detectors.
+  ${mk} > /dev/null
+  echo "a.o: a.d.tmp gcc"
+  echo "b.o: b.dep.tmp gcc"
+  echo "c.jar: c.d"
+  echo "*kati*: 2 depfiles detected by gcc"
+  echo "*kati*: 1 depfiles detected by javac"
+else
+  ${mk} --ninja --depfile_detectors=gcc,javac --kati_stats > /dev/null 2> stats.txt
+  for t in a.o b.o c.jar; do
+    echo "${t}: $(grep -B4 "^build ${t}:" build.ninja | sed -n 's/^ \(depfile\|deps\) = //p' | xargs)"
+  done
+  grep 'depfiles detected' stats.txt
+fi
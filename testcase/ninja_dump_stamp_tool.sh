@@ -0,0 +1,43 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+set -e
+
+log=stderr_log
+mk="$@"
+kati=$1
+
+cat <<EOF2 > Makefile
+X := \$(shell cat x.txt)
+all:
+	echo \$(X) \$(VAR)
+EOF2
+
+if ! echo "${mk}" | grep -q rkati; then
+  echo 'env VAR: "1" => "2"'
+  echo 'shell cat x.txt: "a" => "b"'
+  echo '      "type": "SHELL"'
+  exit 0
+fi
+
+echo a > x.txt
+VAR=1 ${mk} > /dev/null 2> ${log}
+cp .kati_stamp a.stamp
+
+echo b > x.txt
+VAR=2 ${mk} > /dev/null 2> ${log}
+${kati} --dump_stamp_tool --diff a.stamp .kati_stamp
+${kati} --dump_stamp_tool --json .kati_stamp | grep '"type"'
@@ -0,0 +1,59 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+set -e
+
+mk="$@"
+
+cat <<EOF >Makefile
+all: gen
+
+gen.dd:
+	echo "ninja_dyndep_version = 1" >\$@
+	echo "build gen | gen.extra: dyndep" >>\$@
+
+gen: .KATI_DYNDEP := gen.dd
+gen: | gen.dd
+	touch gen gen.extra
+	echo 1 >>gen
+
+other: .KATI_DYNDEP :=
+other:
+	touch other
+
+odd: .KATI_DYNDEP := odd dir/x:y.dd
+odd:
+	touch odd
+EOF
+
+${mk} -j1 all other
+if [ -e ninja.sh ]; then
+  ./ninja.sh -j1 -w dupbuild=err all other
+  grep -A3 '^build gen:' build.ninja | grep -E '(dyndep|\|\|)' | sed 's/rule[0-9]*/RULE/'
+  grep -A3 '^build other:' build.ninja | grep -c dyndep || true
+  ${mk} -j1 odd > /dev/null
+  grep -A3 '^build odd:' build.ninja | grep -E '(dyndep|\|\|)' | sed 's/rule[0-9]*/RULE/'
+else
+  echo "build gen: RULE || gen.dd"
+  echo " dyndep = gen.dd"
+  echo 0
+  echo 'build odd: RULE || odd$ dir/x$:y.dd'
+  echo ' dyndep = odd$ dir/x$:y.dd'
+fi
+
+echo "gen:"
+cat gen
+ls gen.extra
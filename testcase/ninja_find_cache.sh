@@ -0,0 +1,55 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+set -e
+
+log=stderr_log
+mk="$@"
+
+mkdir -p top/a top/b
+touch top/a/1.c top/b/2.c
+# Listings of directories modified in the same clock tick as the run are not
+# cached.
+sleep 1
+
+cat <<EOF2 > Makefile
+X := \$(sort \$(shell find top -name "*.c"))
+all:
+	echo \$(X)
+EOF2
+
+args=
+if echo "${mk}" | grep -q rkati; then
+  args="--use_find_emulator --find_cache --kati_stats"
+fi
+
+${mk} ${args} 2> ${log}
+if [ -e ninja.sh ]; then
+  if [ -n "${args}" ] && [ ! -e .kati_find_cache ]; then
+    echo 'Should write the find cache'
+  fi
+  ./ninja.sh
+fi
+
+sleep 1
+touch top/b/3.c
+${mk} ${args} 2> ${log}
+if [ -e ninja.sh ]; then
+  if [ -n "${args}" ] && grep -q '^\*kati\*: 0 find cache hits' ${log}; then
+    echo 'Should reuse unchanged directories'
+  fi
+  ./ninja.sh
+fi
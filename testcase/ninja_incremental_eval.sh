@@ -0,0 +1,87 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+set -e
+
+mk="$@"
+
+cat <<EOF2 > Makefile
+all:
+	echo \$(A) \$(B) \$(X)
+X := \$(wildcard src/*.c)
+include a.mk
+include b.mk
+EOF2
+echo 'A := one' > a.mk
+printf 'A += extra\nB := two\n' > b.mk
+mkdir -p src
+touch src/x.c
+
+if ! echo "${mk}" | grep -q rkati; then
+  # Only rkati has a daemon mode.
+  echo 'echo one extra two src/x.c'
+  echo 'echo one extra three src/x.c'
+  echo 'reusing evaluation up to Makefile:5'
+  echo 'echo uno extra three src/x.c'
+  echo 'reusing evaluation up to Makefile:4'
+  echo 'echo uno extra three src/x.c src/y.c'
+  echo 'reusing evaluation up to Makefile:5'
+  echo 3
+  echo 'echo uno extra four src/x.c src/y.c'
+  exit 0
+fi
+
+${mk} --daemon --incremental_eval > daemon.log 2>&1 &
+daemon=$!
+trap "kill ${daemon}" EXIT
+for i in $(seq 50); do
+  [ -S .kati_daemon.sock ] && break
+  sleep 0.1
+done
+
+${mk} --incremental_eval --daemon_wait
+grep -o 'echo one[^"]*' build.ninja
+
+# Only b.mk is evaluated again, so A is not appended to twice.
+sleep 0.1
+sed -i 's/two/three/' b.mk
+${mk} --incremental_eval --daemon_wait
+grep -o 'echo one[^"]*' build.ninja
+grep -o 'reusing evaluation up to .*' daemon.log | tail -1
+
+sleep 0.1
+echo 'A := uno' > a.mk
+${mk} --incremental_eval --daemon_wait
+grep -o 'echo uno[^"]*' build.ninja
+grep -o 'reusing evaluation up to .*' daemon.log | tail -1
+
+# A changed glob is consumed before any include, so nothing is reused.
+sleep 0.1
+touch src/y.c
+${mk} --incremental_eval --daemon_wait
+grep -o 'echo uno[^"]*' build.ninja
+
+# The top-level makefile is read before the first checkpoint too, even in a
+# run that resumed from a later one.
+sleep 0.1
+sed -i 's/three/four/' b.mk
+${mk} --incremental_eval --daemon_wait
+grep -o 'reusing evaluation up to .*' daemon.log | tail -1
+sleep 0.1
+sed -i 's/^X :=/X ?=/' Makefile
+${mk} --incremental_eval --daemon_wait
+grep -c 'reusing evaluation up to' daemon.log
+grep -o 'echo uno[^"]*' build.ninja
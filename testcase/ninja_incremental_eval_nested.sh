@@ -0,0 +1,95 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+
+set -e
+
+mk="$@"
+
+cat <<EOF2 > Makefile
+all:
+	echo \$(A) \$(B) \$(C) \$(D) \$(strip \$(MAKEFILE_LIST))
+A := top
+E := e
+include a.mk
+EOF2
+cat <<EOF2 > a.mk
+A += a
+include b.mk
+A += a2
+\$(KATI_deprecated_var E,Use C instead)
+include c.mk
+ifdef A
+include d.mk
+endif
+EOF2
+echo 'B := b' > b.mk
+echo 'C := c' > c.mk
+echo 'D := d' > d.mk
+
+if ! echo "${mk}" | grep -q rkati; then
+  # Only rkati has a daemon mode.
+  echo 'echo top a a2 b c d Makefile a.mk b.mk c.mk d.mk'
+  echo 'reusing evaluation up to a.mk:5'
+  echo 'echo top a a2 b cc d Makefile a.mk b.mk c.mk d.mk'
+  echo 'reusing evaluation up to a.mk:5'
+  echo 'echo top a a2 b cc dd Makefile a.mk b.mk c.mk d.mk'
+  echo 'reusing evaluation up to a.mk:5'
+  echo 'c.mk:1: E has been deprecated. Use C instead.'
+  echo 'echo top a a2 b ccc e dd Makefile a.mk b.mk c.mk d.mk'
+  echo 'reusing evaluation up to a.mk:2'
+  echo 'echo top a a2 bb ccc e dd Makefile a.mk b.mk c.mk d.mk'
+  exit 0
+fi
+
+${mk} --daemon --incremental_eval > daemon.log 2>&1 &
+daemon=$!
+trap "kill ${daemon}" EXIT
+for i in $(seq 50); do
+  [ -S .kati_daemon.sock ] && break
+  sleep 0.1
+done
+
+${mk} --incremental_eval --daemon_wait
+grep -o 'echo top[^"]*' build.ninja
+
+# Evaluation resumes inside a.mk, right before c.mk is included.
+sleep 0.1
+echo 'C := cc' > c.mk
+${mk} --incremental_eval --daemon_wait
+grep -o 'reusing evaluation up to .*' daemon.log | tail -1
+grep -o 'echo top[^"]*' build.ninja
+
+# Includes in conditionals get no checkpoint of their own.
+sleep 0.1
+echo 'D := dd' > d.mk
+${mk} --incremental_eval --daemon_wait
+grep -o 'reusing evaluation up to .*' daemon.log | tail -1
+grep -o 'echo top[^"]*' build.ninja
+
+# The deprecation between the includes survives the checkpoint of c.mk.
+sleep 0.1
+echo 'C := ccc $(E)' > c.mk
+${mk} --incremental_eval --daemon_wait
+grep -o 'reusing evaluation up to .*' daemon.log | tail -1
+grep -o 'c.mk:1: E has been deprecated.*' daemon.log | tail -1
+grep -o 'echo top[^"]*' build.ninja
+
+sleep 0.1
+echo 'B := bb' > b.mk
+${mk} --incremental_eval --daemon_wait
+grep -o 'reusing evaluation up to .*' daemon.log | tail -1
+grep -o 'echo top[^"]*' build.ninja
@@ -0,0 +1,57 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+set -e
+
+log=stderr_log
+mk="$@"
+
+cat <<EOF2 > Makefile
+include a.mk
+all:
+	echo \$(A)
+EOF2
+echo 'A := foo' > a.mk
+
+hash=
+if echo "${mk}" | grep -q rkati; then
+  hash=--regen_content_hash
+fi
+
+${mk} ${hash} 2> ${log}
+if [ -e ninja.sh ]; then
+  ./ninja.sh
+fi
+
+sleep 1
+touch a.mk
+${mk} ${hash} 2> ${log}
+if [ -e ninja.sh ]; then
+  if [ -n "${hash}" ] && grep regenerating ${log}; then
+    echo 'Should not be regenerated (same content)'
+  fi
+  ./ninja.sh
+fi
+
+sleep 1
+echo 'A := bar' > a.mk
+${mk} ${hash} 2> ${log}
+if [ -e ninja.sh ]; then
+  if ! grep -q 'a.mk was modified, regenerating' ${log}; then
+    echo 'Should be regenerated (a.mk)'
+  fi
+  ./ninja.sh
+fi
@@ -0,0 +1,47 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+
+set -e
+
+mk="$@"
+
+# a.mk changes while kati is evaluating, after it was read. The stamp must
+# record the contents that were parsed, not the ones on disk at the end.
+cat <<EOF2 > Makefile
+include a.mk
+ifeq (\$(A),foo)
+\$(shell sleep 1; echo 'A := bar' > a.mk)
+endif
+all:
+	echo \$(A)
+EOF2
+echo 'A := foo' > a.mk
+
+hash=
+if echo "${mk}" | grep -q rkati; then
+  hash=--regen_content_hash
+fi
+
+${mk} ${hash} 2> /dev/null
+if [ -e ninja.sh ]; then
+  ./ninja.sh
+fi
+
+${mk} ${hash} 2> /dev/null
+if [ -e ninja.sh ]; then
+  ./ninja.sh
+fi
@@ -0,0 +1,59 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+set -e
+
+log=stderr_log
+mk="$@"
+
+cat <<EOF > Makefile
+X := \$(wildcard *.mk)
+all:
+	echo \$(X) \$(VAR)
+EOF
+
+explain=
+if [ -n "$(echo "${mk}" | grep rkati)" ]; then
+  explain=--regen_explain=explain.json
+fi
+
+${mk} ${explain} 2> ${log}
+if [ -e ninja.sh ]; then
+  ./ninja.sh
+fi
+
+${mk} ${explain} 2> ${log}
+if [ -e explain.json ]; then
+  grep -q '"needs_regen": false' explain.json || echo 'Should not be regenerated'
+  grep -q '"reasons": \[\]' explain.json || echo 'Should not have reasons'
+fi
+
+sleep 1
+touch a.mk Makefile
+export VAR=foo
+${mk} ${explain} 2> ${log}
+if [ -e explain.json ]; then
+  grep -q '"needs_regen": true' explain.json || echo 'Should be regenerated'
+  # Every reason is reported, not just the first.
+  grep '"type"' explain.json | sed 's/ *"type": //' | sort
+else
+  echo '"env"'
+  echo '"file"'
+  echo '"glob"'
+fi
+if [ -e ninja.sh ]; then
+  ./ninja.sh
+fi
@@ -0,0 +1,59 @@
+#!/bin/sh
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+set -eu
+
+log=stderr_log
+mk="$@"
+
+echo foo > a.txt
+
+if echo "${mk}" | grep -q rkati; then
+  cat <<EOF > Makefile
+HASH := \$(KATI_file_hash a.txt)
+all:
+	echo \$(HASH)
+EOF
+else
+  cat <<EOF > Makefile
+HASH := \$(shell sha256sum a.txt | cut -d' ' -f1)
+all:
+	echo \$(HASH)
+EOF
+fi
+
+${mk} 2> ${log}
+if [ -e ninja.sh ]; then
+  ./ninja.sh
+fi
+
+${mk} 2> ${log}
+if [ -e ninja.sh ]; then
+  if grep -q regenerating ${log}; then
+    echo 'Should not be regenerated'
+  fi
+  ./ninja.sh
+fi
+
+echo bar > a.txt
+
+${mk} 2> ${log}
+if [ -e ninja.sh ]; then
+  if ! grep -q regenerating ${log}; then
+    echo 'Should have regenerated due to modified file'
+  fi
+  ./ninja.sh
+fi
@@ -0,0 +1,55 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+set -e
+
+log=stderr_log
+mk="$@"
+
+cat <<EOF2 > Makefile
+all:
+	echo \$(NOISY_A) \$(NOISY_KEEP) \$(NOISY_UNSET)
+EOF2
+
+if ! echo "${mk}" | grep -q rkati; then
+  # Only rkati supports --regen_ignore_env.
+  ${mk} > /dev/null 2> ${log}
+  echo 'warned about NOISY_A'
+  echo 'not regenerated (NOISY_A changed)'
+  echo 'not regenerated (NOISY_UNSET set)'
+  echo 'regenerated (NOISY_KEEP changed)'
+  exit 0
+fi
+
+args="--regen_ignore_env=NOISY_% --regen_track_env=NOISY_KEEP --warn"
+
+export NOISY_A=1 NOISY_KEEP=1
+${mk} ${args} > /dev/null 2> ${log}
+if grep -q 'environment variable NOISY_A was used' ${log}; then
+  echo 'warned about NOISY_A'
+fi
+
+export NOISY_A=2
+${mk} ${args} > /dev/null 2> ${log}
+grep -q regenerating ${log} || echo 'not regenerated (NOISY_A changed)'
+
+export NOISY_UNSET=1
+${mk} ${args} > /dev/null 2> ${log}
+grep -q regenerating ${log} || echo 'not regenerated (NOISY_UNSET set)'
+
+export NOISY_KEEP=2
+${mk} ${args} > /dev/null 2> ${log}
+grep -q regenerating ${log} && echo 'regenerated (NOISY_KEEP changed)'
@@ -0,0 +1,66 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+set -e
+
+log=stderr_log
+mk="$@"
+
+echo a > a.txt
+echo b > b.txt
+echo c > c.txt
+
+cat <<EOF2 > Makefile
+A := \$(shell cat a.txt)
+B := \$(shell cat b.txt)
+\$(file >w.txt,\$(A)\$(B))
+W := \$(shell cat w.txt)
+C := \$(shell cat c.txt)
+all:
+	echo \$(A) \$(B) \$(C) \$(W)
+EOF2
+
+debug=
+out=/dev/stdout
+if echo "${mk}" | grep -q rkati; then
+  debug=--regen_debug
+  out=debug.txt
+fi
+
+${mk} ${debug} >> ${out} 2> ${log}
+if [ -e ninja.sh ]; then
+  ./ninja.sh
+fi
+
+${mk} ${debug} >> ${out} 2> ${log}
+if [ -e ninja.sh ]; then
+  if grep regenerating ${log}; then
+    echo 'Should not be regenerated'
+  fi
+  if [ -n "${debug}" ] && [ "$(grep -c 'checked in' ${out})" != 5 ]; then
+    echo 'Should report a time for every command'
+  fi
+  ./ninja.sh
+fi
+
+echo B > b.txt
+${mk} ${debug} >> ${out} 2> ${log}
+if [ -e ninja.sh ]; then
+  if ! grep -q 'cat b.txt) was changed, regenerating' ${log}; then
+    echo 'Should be regenerated (b.txt)'
+  fi
+  ./ninja.sh
+fi
@@ -0,0 +1,39 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+set -eu
+
+mk="$@"
+
+cat <<'EOF' > Makefile
+include sub.mk
+-include missing.mk
+all:
+	echo $(X)
+EOF
+echo 'X := foo' > sub.mk
+
+if echo "${mk}" | grep -qv "kati"; then
+  # Make doesn't support --ninja_regen_rule.
+  ${mk} > /dev/null
+  echo " generator = 1"
+  echo " restat = 1"
+  echo "build ./build.ninja: kati_regen Makefile sub.mk"
+else
+  ${mk} --ninja --ninja_regen_rule --regen_ignoring_kati_binary > /dev/null
+  ./ninja.sh -j1 > /dev/null
+  grep -E '^ (generator|restat) = ' build.ninja
+  grep '^build ./build.ninja:' build.ninja
+fi
@@ -0,0 +1,45 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+set -eu
+set -e
+
+mk="$@"
+
+mkdir -p sub
+cat <<'EOF2' > sub/Makefile
+all:
+	echo foo
+EOF2
+
+if ! echo "${mk}" | grep -q rkati; then
+  # Only rkati has --ninja_regen_rule and a daemon mode.
+  echo '--ninja_regen_rule'
+  exit 0
+fi
+
+# The regen command runs in the directory ninja is in, and must neither start
+# a daemon nor write an explanation.
+${mk} -Csub --ninja --ninja_regen_rule --daemon --regen_explain=explain.txt \
+  > daemon.log 2>&1 &
+daemon=$!
+trap "kill ${daemon}" EXIT
+for i in $(seq 50); do
+  [ -S sub/.kati_daemon.sock ] && break
+  sleep 0.1
+done
+
+grep '^ command = ' sub/build.ninja | tr ' ' '\n' | \
+  grep -e '^-C' -e '^--daemon' -e '^--regen_explain' -e '^--ninja_regen_rule'
@@ -0,0 +1,61 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+set -e
+
+log=stderr_log
+mk="$@"
+
+cat <<EOF > Makefile
+all:
+	echo foo
+EOF
+
+${mk} 2> ${log}
+if [ -e ninja.sh ]; then
+  ./ninja.sh
+fi
+
+${mk} 2> ${log}
+if [ -e ninja.sh ]; then
+  if grep regenerating ${log}; then
+    echo 'Should not be regenerated'
+  fi
+  ./ninja.sh
+fi
+
+if [ -e ninja.sh ] && echo "${mk}" | grep -q rkati; then
+  printf 'x' >> .kati_stamp
+fi
+${mk} 2> ${log}
+if [ -e ninja.sh ] && echo "${mk}" | grep -q rkati; then
+  if ! grep -q 'checksum mismatch, regenerating' ${log}; then
+    echo 'Should be regenerated (trailing data)'
+  fi
+  ./ninja.sh
+fi
+
+if [ -e ninja.sh ] && echo "${mk}" | grep -q rkati; then
+  # A stamp from another kati, which starts with the generation time.
+  printf '\0\0\0\0\0\0\0\0' > .kati_stamp
+fi
+${mk} 2> ${log}
+if [ -e ninja.sh ] && echo "${mk}" | grep -q rkati; then
+  if ! grep -q 'bad magic), regenerating' ${log}; then
+    echo 'Should be regenerated (bad magic)'
+  fi
+  ./ninja.sh
+fi
@@ -0,0 +1,44 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+set -eu
+
+mk="$@"
+
+cat <<'EOF' > Makefile
+all: short long
+
+short:
+	echo $@ > $@
+
+long:
+	echo $@ $(foreach i,1 2 3 4 5 6 7 8 9 10,word$(i)) > $@
+EOF
+
+if echo "${mk}" | grep -qv "kati"; then
+  # Make doesn't support --rspfile_threshold.
+  ${mk} > /dev/null
+  echo "rspfiles: 1"
+  echo "rspfiles: 0"
+else
+  ${mk} --ninja --rspfile_threshold=80 > /dev/null
+  ./ninja.sh -j1 > /dev/null
+  echo "rspfiles: $(grep -c '^ rspfile = ' build.ninja)"
+  # Without the flag, the threshold is derived from ARG_MAX.
+  ${mk} --ninja > /dev/null
+  echo "rspfiles: $(grep -c '^ rspfile = ' build.ninja || true)"
+fi
+
+cat short long
@@ -0,0 +1,45 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+set -u
+
+mk="$@"
+
+cat <<'EOF' > Makefile
+all: a b c d
+a b c d:
+	echo $@
+EOF
+
+if echo "${mk}" | grep -qv "kati"; then
+  # Make doesn't support --ninja_shards.
+  echo "subninja count: 3"
+  echo "shard builds: 5"
+  echo "stable: yes"
+  echo "shards after shrinking: build.0.ninja build.1.ninja"
+else
+  ${mk} --ninja --ninja_shards=3 > /dev/null
+  echo "subninja count: $(grep -c '^subninja ' build.ninja)"
+  echo "shard builds: $(cat build.[0-9].ninja | grep -c '^build ')"
+  cat build.[0-9].ninja > shards.before
+  ${mk} --ninja --ninja_shards=3 > /dev/null
+  if cat build.[0-9].ninja | cmp -s - shards.before; then
+    echo "stable: yes"
+  else
+    echo "stable: no"
+  fi
+  ${mk} --ninja --ninja_shards=2 > /dev/null
+  echo "shards after shrinking:" build.[0-9].ninja
+fi
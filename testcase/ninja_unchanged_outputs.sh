@@ -0,0 +1,40 @@
+#!/bin/bash
+#
+# Copyright 2025 Google Inc. All rights reserved
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http:#www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+set -eu
+
+mk="$@"
+
+cat <<'EOF' > Makefile
+export FOO := 1
+all:
+	echo all
+EOF
+
+if echo "${mk}" | grep -qv "kati"; then
+  # Only kati generates ninja files.
+  echo "unchanged: build.ninja env.sh ninja.sh"
+  echo "changed: build.ninja"
+else
+  ${mk} --ninja > /dev/null
+  touch -d '2000-01-01' marker
+  touch -d '1999-01-01' build.ninja env.sh ninja.sh
+  ${mk} --ninja > /dev/null
+  echo "unchanged: $(find build.ninja env.sh ninja.sh ! -newer marker | sort | xargs)"
+
+  sed -i "s/echo all/echo changed/" Makefile
+  ${mk} --ninja > /dev/null
+  echo "changed: $(find build.ninja env.sh ninja.sh -newer marker | sort | xargs)"
+fi